@@ -0,0 +1,164 @@
+//! Passes averaging the sub-frames of a frame, for motion blur
+
+use wgpu::*;
+
+/// Format of the sum of the sub-frames, precise enough for an 8 bit canvas
+const ACCUMULATION_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+pub struct AccumulatePass {
+    /// Reads the canvas to add it to the sum
+    accumulate_bind_group: BindGroup,
+    accumulate_pipeline: RenderPipeline,
+    /// Reads the sum to write it back to the canvas
+    resolve_bind_group: BindGroup,
+    resolve_pipeline: RenderPipeline,
+    sum: Texture,
+}
+
+impl AccumulatePass {
+    /// The pass is tied to the canvas, it is created again when the canvas changes
+    pub fn new(device: &Device, canvas: &Texture, size: Extent3d, format: TextureFormat) -> Self {
+        let module = device.create_shader_module(&include_wgsl!("accumulate.wgsl"));
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("accumulate bind group layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("accumulate pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let create_pipeline = |label: &str, format: TextureFormat, blend: Option<BlendState>| {
+            device.create_render_pipeline(&RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: VertexState {
+                    module: &module,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+                fragment: Some(FragmentState {
+                    module: &module,
+                    entry_point: "fs_main",
+                    targets: &[ColorTargetState {
+                        format,
+                        write_mask: ColorWrites::ALL,
+                        blend,
+                    }],
+                }),
+            })
+        };
+
+        // sum += weight * canvas
+        let add = BlendComponent {
+            src_factor: BlendFactor::Constant,
+            dst_factor: BlendFactor::One,
+            operation: BlendOperation::Add,
+        };
+        let accumulate_pipeline = create_pipeline(
+            "accumulate pipeline",
+            ACCUMULATION_FORMAT,
+            Some(BlendState {
+                color: add,
+                alpha: add,
+            }),
+        );
+        let resolve_pipeline = create_pipeline("accumulate resolve pipeline", format, None);
+
+        let sum = device.create_texture(&TextureDescriptor {
+            label: Some("accumulation tex"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: ACCUMULATION_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+        });
+
+        Self {
+            accumulate_bind_group: Self::create_bind_group(device, &bind_group_layout, canvas),
+            accumulate_pipeline,
+            resolve_bind_group: Self::create_bind_group(device, &bind_group_layout, &sum),
+            resolve_pipeline,
+            sum,
+        }
+    }
+
+    /// Add the canvas to the sum with this weight, the first sub-frame starts a new sum
+    pub fn accumulate(&self, encoder: &mut CommandEncoder, weight: f64, first: bool) {
+        puffin::profile_scope!("accumulate pass execute");
+
+        let load = if first {
+            LoadOp::Clear(Color::TRANSPARENT)
+        } else {
+            LoadOp::Load
+        };
+        let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("accumulate render pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: &self.sum.create_view(&TextureViewDescriptor::default()),
+                resolve_target: None,
+                ops: Operations { load, store: true },
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(&self.accumulate_pipeline);
+        rpass.set_bind_group(0, &self.accumulate_bind_group, &[]);
+        rpass.set_blend_constant(Color {
+            r: weight,
+            g: weight,
+            b: weight,
+            a: weight,
+        });
+        rpass.draw(0..3, 0..1);
+    }
+
+    /// Write the sum back to the canvas
+    pub fn resolve(&self, encoder: &mut CommandEncoder, canvas: &TextureView) {
+        puffin::profile_scope!("accumulate resolve pass execute");
+
+        let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("accumulate resolve render pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: canvas,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(&self.resolve_pipeline);
+        rpass.set_bind_group(0, &self.resolve_bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, input: &Texture) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("accumulate bind group"),
+            layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(
+                    &input.create_view(&TextureViewDescriptor::default()),
+                ),
+            }],
+        })
+    }
+}
@@ -0,0 +1,14 @@
+[[group(0), binding(0)]]
+var input: texture_2d<f32>;
+
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] in_vertex_index: u32) -> [[builtin(position)]] vec4<f32> {
+    let pos = vec2<f32>(f32((in_vertex_index << 1u) & 2u), f32(in_vertex_index & 2u));
+    return vec4<f32>(pos * 2.0 - 1.0, 0.0, 1.0);
+}
+
+// The weight of the sub-frame is applied by the blend constant
+[[stage(fragment)]]
+fn fs_main([[builtin(position)]] pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    return textureLoad(input, vec2<i32>(pos.xy), 0);
+}
@@ -0,0 +1,394 @@
+//! Built-in editor for the main file of the loaded shader
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use egui::epaint::text::cursor::CCursor;
+use egui::epaint::Galley;
+use egui::{
+    Align2, Button, Color32, Key, Painter, Pos2, Rect, ScrollArea, TextEdit, TextStyle, Ui,
+};
+
+/// Words colored as keywords
+const KEYWORDS: &[&str] = &[
+    "if",
+    "else",
+    "for",
+    "while",
+    "do",
+    "break",
+    "continue",
+    "return",
+    "discard",
+    "switch",
+    "case",
+    "default",
+    "const",
+    "in",
+    "out",
+    "inout",
+    "uniform",
+    "buffer",
+    "layout",
+    "struct",
+    "precision",
+    "highp",
+    "mediump",
+    "lowp",
+    "flat",
+    "smooth",
+    "noperspective",
+    "centroid",
+    "invariant",
+    "shared",
+    "coherent",
+    "volatile",
+    "restrict",
+    "readonly",
+    "writeonly",
+    "true",
+    "false",
+];
+
+/// Words colored as types
+const TYPES: &[&str] = &[
+    "void",
+    "bool",
+    "int",
+    "uint",
+    "float",
+    "double",
+    "vec2",
+    "vec3",
+    "vec4",
+    "ivec2",
+    "ivec3",
+    "ivec4",
+    "uvec2",
+    "uvec3",
+    "uvec4",
+    "bvec2",
+    "bvec3",
+    "bvec4",
+    "dvec2",
+    "dvec3",
+    "dvec4",
+    "mat2",
+    "mat3",
+    "mat4",
+    "mat2x2",
+    "mat2x3",
+    "mat2x4",
+    "mat3x2",
+    "mat3x3",
+    "mat3x4",
+    "mat4x2",
+    "mat4x3",
+    "mat4x4",
+    "sampler",
+    "sampler2D",
+    "sampler3D",
+    "samplerCube",
+    "texture2D",
+    "texture3D",
+    "textureCube",
+    "image2D",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum TokenKind {
+    Comment,
+    Preprocessor,
+    Keyword,
+    Type,
+    Number,
+}
+
+impl TokenKind {
+    fn color(self, dark_mode: bool) -> Color32 {
+        match (self, dark_mode) {
+            (TokenKind::Comment, _) => Color32::from_rgb(120, 130, 120),
+            (TokenKind::Preprocessor, true) => Color32::from_rgb(200, 150, 230),
+            (TokenKind::Preprocessor, false) => Color32::from_rgb(130, 60, 160),
+            (TokenKind::Keyword, true) => Color32::from_rgb(240, 120, 110),
+            (TokenKind::Keyword, false) => Color32::from_rgb(180, 40, 40),
+            (TokenKind::Type, true) => Color32::from_rgb(110, 190, 240),
+            (TokenKind::Type, false) => Color32::from_rgb(20, 100, 170),
+            (TokenKind::Number, true) => Color32::from_rgb(220, 200, 120),
+            (TokenKind::Number, false) => Color32::from_rgb(150, 110, 0),
+        }
+    }
+}
+
+/// A colored part of a line, in chars since that's what the text edit cursors count
+struct Token {
+    chars: Range<usize>,
+    kind: TokenKind,
+}
+
+pub struct Editor {
+    path: PathBuf,
+    text: String,
+    /// Edited since the last save
+    dirty: bool,
+    /// Colored parts of the text, updated when it changes
+    tokens: Vec<Token>,
+}
+
+impl Editor {
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = read(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tokens: tokenize(&text),
+            text,
+            dirty: false,
+        })
+    }
+
+    /// Edit some text not coming from the file, it will replace the file on save
+    pub fn with_text(path: &Path, text: String) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            tokens: tokenize(&text),
+            text,
+            dirty: true,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replace the text, it will replace the file on save
+    pub fn set_text(&mut self, text: String) {
+        self.tokens = tokenize(&text);
+        self.text = text;
+        self.dirty = true;
+    }
+
+    /// Read the file again, unsaved edits are kept
+    pub fn refresh(&mut self) -> Result<()> {
+        if !self.dirty {
+            self.text = read(&self.path)?;
+            self.tokens = tokenize(&self.text);
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        fs::write(&self.path, &self.text)
+            .with_context(|| format!("Can't write {}", self.path.display()))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Draw the editor, returns true when the user asked to save with the button or Ctrl+S
+    pub fn show(&mut self, ui: &mut Ui) -> bool {
+        let mut save = false;
+        ui.horizontal(|ui| {
+            save = ui
+                .add(Button::new("Save").enabled(self.dirty))
+                .on_hover_text("Save the file and reload the shader (Ctrl+S)")
+                .clicked();
+            if self.dirty {
+                ui.label("modified");
+            }
+        });
+        ScrollArea::auto_sized().show(ui, |ui| {
+            // The text edit can only paint in a single color, the colored text is painted over
+            // its transparent text
+            let response = ui.add(
+                TextEdit::multiline(&mut self.text)
+                    .code_editor()
+                    .text_color(Color32::TRANSPARENT)
+                    .desired_width(f32::INFINITY)
+                    .desired_rows(30),
+            );
+            if response.changed() {
+                self.dirty = true;
+                self.tokens = tokenize(&self.text);
+            }
+            self.paint_text(ui, response.rect);
+        });
+        let input = ui.input();
+        save || (self.dirty && input.modifiers.command && input.key_pressed(Key::S))
+    }
+}
+
+impl Editor {
+    /// Paint the visible lines in the place of the text edit text, the tokens in their color
+    fn paint_text(&self, ui: &Ui, rect: Rect) {
+        // Laid out like the text edit does, to find where each char is
+        let galley =
+            ui.fonts()
+                .layout_multiline(TextStyle::Monospace, self.text.clone(), rect.width());
+        // Clipped to the visible part of the scroll area
+        let painter = ui.painter_at(rect);
+        let dark_mode = ui.visuals().dark_mode;
+        let plain = ui.visuals().widgets.inactive.text_color();
+        // Byte offset of each char
+        let offsets: Vec<usize> = self
+            .text
+            .char_indices()
+            .map(|(offset, _)| offset)
+            .chain(Some(self.text.len()))
+            .collect();
+        let run = |chars: Range<usize>, color| {
+            self.paint_run(&painter, &galley, rect.min, &offsets, chars, color)
+        };
+
+        let mut tokens = self.tokens.iter().peekable();
+        let mut line_start = 0;
+        for line in self.text.split('\n') {
+            let line_end = line_start + line.chars().count();
+            let mut start = line_start;
+            while let Some(token) = tokens.next_if(|it| it.chars.start < line_end) {
+                run(start..token.chars.start, plain);
+                run(token.chars.clone(), token.kind.color(dark_mode));
+                start = token.chars.end;
+            }
+            run(start..line_end, plain);
+            line_start = line_end + 1;
+        }
+    }
+
+    /// Paint chars of a single line, split where the text edit wraps them
+    fn paint_run(
+        &self,
+        painter: &Painter,
+        galley: &Galley,
+        origin: Pos2,
+        offsets: &[usize],
+        chars: Range<usize>,
+        color: Color32,
+    ) {
+        let pos = |index| {
+            galley
+                .pos_from_cursor(&galley.from_ccursor(CCursor {
+                    index,
+                    prefer_next_row: true,
+                }))
+                .translate(origin.to_vec2())
+        };
+        let clip = painter.clip_rect();
+        let mut start = chars.start;
+        while start < chars.end {
+            let first = pos(start);
+            if first.min.y > clip.max.y {
+                return;
+            }
+            // The end of the run or of the row, runs are rarely wrapped
+            let end = if pos(chars.end - 1).min.y == first.min.y {
+                chars.end
+            } else {
+                (start + 1..chars.end)
+                    .find(|index| pos(*index).min.y != first.min.y)
+                    .unwrap_or(chars.end)
+            };
+            let text = &self.text[offsets[start]..offsets[end]];
+            if first.intersects(clip) && !text.trim().is_empty() {
+                painter.text(
+                    first.min,
+                    Align2::LEFT_TOP,
+                    text,
+                    TextStyle::Monospace,
+                    color,
+                );
+            }
+            start = end;
+        }
+    }
+}
+
+/// Find the comments, preprocessor directives, keywords, types and numbers of GLSL code.
+/// Tokens don't span multiple lines.
+fn tokenize(text: &str) -> Vec<Token> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let push = |tokens: &mut Vec<Token>, chars_range: Range<usize>, kind| {
+        // Split at the line breaks
+        let mut start = chars_range.start;
+        for i in chars_range.clone() {
+            if chars[i] == '\n' {
+                if i > start {
+                    tokens.push(Token {
+                        chars: start..i,
+                        kind,
+                    });
+                }
+                start = i + 1;
+            }
+        }
+        if chars_range.end > start {
+            tokens.push(Token {
+                chars: start..chars_range.end,
+                kind,
+            });
+        }
+    };
+    let line_end = |from: usize| {
+        (from..chars.len())
+            .find(|i| chars[*i] == '\n')
+            .unwrap_or_else(|| chars.len())
+    };
+
+    let mut line_blank = true;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let start = i;
+        if c == '/' && next == Some('/') {
+            i = line_end(i);
+            push(&mut tokens, start..i, TokenKind::Comment);
+        } else if c == '/' && next == Some('*') {
+            i = (start + 3..chars.len())
+                .find(|j| chars[*j - 1] == '*' && chars[*j] == '/')
+                .map_or(chars.len(), |j| j + 1);
+            push(&mut tokens, start..i, TokenKind::Comment);
+        } else if c == '#' && line_blank {
+            i = line_end(i);
+            push(&mut tokens, start..i, TokenKind::Preprocessor);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if KEYWORDS.contains(&word.as_str()) {
+                push(&mut tokens, start..i, TokenKind::Keyword);
+            } else if TYPES.contains(&word.as_str()) {
+                push(&mut tokens, start..i, TokenKind::Type);
+            }
+        } else if c.is_ascii_digit() || (c == '.' && next.map_or(false, |it| it.is_ascii_digit())) {
+            i += 1;
+            while i < chars.len() {
+                let c = chars[i];
+                let exponent_sign = (c == '+' || c == '-')
+                    && matches!(chars[i - 1], 'e' | 'E')
+                    && !matches!(chars.get(start + 1), Some('x') | Some('X'));
+                if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            push(&mut tokens, start..i, TokenKind::Number);
+        } else {
+            i += 1;
+        }
+        // Only whitespace since the start of the line, for the preprocessor directives
+        line_blank = match chars[i - 1] {
+            '\n' => true,
+            c if c.is_whitespace() => line_blank,
+            _ => false,
+        };
+    }
+    tokens
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))
+}
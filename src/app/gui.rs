@@ -1,26 +1,54 @@
+use std::collections::HashSet;
 use std::mem;
 use std::sync::Arc;
-use std::time::Duration;
 
 use egui::special_emojis::GITHUB;
-use egui::{ClippedMesh, Color32, CtxRef, DragValue, Frame, Id, Texture, TextureId, Ui};
+use egui::{
+    Align2, ClippedMesh, Color32, CtxRef, CursorIcon, DragValue, Frame, Id, Key, Rect, Response,
+    Sense, Stroke, TextEdit, TextStyle, Texture, Ui,
+};
 use egui_wgpu_backend::ScreenDescriptor;
 use egui_winit_platform::Platform;
 use image::ImageFormat;
-use winit::event::Event;
+use log::error;
+use wgpu::{FilterMode, TextureFormat};
+use winit::event::{Event, WindowEvent};
+use winit::window::Window;
 
-use nuance::Slider;
+use nuance::renderer::BlendMode;
+use nuance::{ChannelSource, ParamFormat, Slider};
 
-use crate::app::Nuance;
+use crate::app::editor::Editor;
+use crate::app::watermark::Corner;
+use crate::app::{FrameCap, Nuance, WheelCurve};
+
+/// Formats selectable for the canvas, the first one is the swapchain format
+const CANVAS_FORMATS: &[TextureFormat] = &[
+    TextureFormat::Bgra8UnormSrgb,
+    TextureFormat::Rgba16Float,
+    TextureFormat::Rgba32Float,
+];
 
 pub struct Gui {
     /// Egui subsystem
     pub egui_platform: Platform,
     /// Logical size
     pub ui_width: u32,
+    /// Multiplies the window scale factor to make the ui bigger or smaller
+    pub ui_scale: f32,
     /// true if the profiling window should be open
     pub profiling_window: bool,
     export_window: bool,
+    pub editor_window: bool,
+    /// Editor for the loaded shader, kept when its window is closed to not lose edits
+    pub editor: Option<Editor>,
+    /// Content of the url text field
+    url: String,
+    /// Names of the params left alone when randomizing
+    pub param_locks: HashSet<String>,
+    /// Names of the params in the order they are shown, the params buffer keeps the declaration
+    /// order
+    pub param_order: Vec<String>,
 }
 
 impl Gui {
@@ -28,13 +56,65 @@ impl Gui {
         Self {
             egui_platform,
             ui_width,
+            ui_scale: 1.0,
             profiling_window: false,
             export_window: false,
+            editor_window: false,
+            editor: None,
+            url: String::new(),
+            param_locks: HashSet::new(),
+            param_order: Vec::new(),
         }
     }
 
+    /// Indices of the sliders in the order they are shown, the params missing from the order
+    /// come last in declaration order
+    pub fn param_display_order(&self, sliders: &[Slider]) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..sliders.len()).collect();
+        indices.sort_by_key(|&index| {
+            self.param_order
+                .iter()
+                .position(|name| name == sliders[index].name())
+                .unwrap_or(usize::MAX)
+        });
+        indices
+    }
+
     pub fn handle_event(&mut self, event: &Event<()>) {
-        self.egui_platform.handle_event(event);
+        match event {
+            // Keep our ui scale on top of the os one
+            Event::WindowEvent {
+                window_id,
+                event:
+                    WindowEvent::ScaleFactorChanged {
+                        scale_factor,
+                        new_inner_size,
+                    },
+            } => {
+                let mut size = **new_inner_size;
+                self.egui_platform.handle_event(&Event::WindowEvent {
+                    window_id: *window_id,
+                    event: WindowEvent::ScaleFactorChanged {
+                        scale_factor: scale_factor * self.ui_scale as f64,
+                        new_inner_size: &mut size,
+                    },
+                });
+            }
+            _ => self.egui_platform.handle_event(event),
+        }
+    }
+
+    /// Change the ui scale, egui only knows about it through scale factor changes so we fake one
+    pub fn set_ui_scale(&mut self, ui_scale: f32, window: &Window) {
+        self.ui_scale = ui_scale;
+        let mut size = window.inner_size();
+        self.handle_event(&Event::WindowEvent {
+            window_id: window.id(),
+            event: WindowEvent::ScaleFactorChanged {
+                scale_factor: window.scale_factor(),
+                new_inner_size: &mut size,
+            },
+        });
     }
 
     pub fn update_time(&mut self, time: f64) {
@@ -50,8 +130,10 @@ impl Gui {
         app.gui.egui_platform.begin_frame();
 
         let mut framerate = (1.0 / app.settings.target_framerate.as_secs_f32()).round() as u32;
+        let mut ui_scale = app.gui.ui_scale;
         //app.gui.ui_width as f32
-        let side_panel = egui::SidePanel::left("params").show(&app.gui.context(), |ui| {
+        // Kiosk mode only shows the canvas
+        let side_panel = (!app.kiosk).then(|| egui::SidePanel::left("params").show(&app.gui.context(), |ui| {
             ui.label(format!(
                 "resolution : {:.0}x{:.0} px",
                 app.globals.resolution.x, app.globals.resolution.y
@@ -62,12 +144,59 @@ impl Gui {
                 app.globals.mouse.x, app.globals.mouse.y
             ))
             .on_hover_text("The position of the mouse pointer as sent to the shader");
-            ui.label(format!("mouse wheel : {:.1}", app.globals.mouse_wheel))
-                .on_hover_text("The current value of the mouse wheel global");
+            ui.label(format!(
+                "mouse wheel : {:.1} ({} steps)",
+                app.globals.mouse_wheel, app.globals.wheel_steps
+            ))
+            .on_hover_text("The current value of the mouse wheel globals");
             ui.label(format!("time : {:.3} s", app.globals.time))
                 .on_hover_text("Time elapsed since the start of the shader execution");
             ui.label(format!("frame : {}", app.globals.frame))
                 .on_hover_text("Number of frames rendered since the start of the shader execution");
+            ui.label(format!("fps : {:.1}", app.frame_rate()))
+                .on_hover_text("Measured framerate, averaged over the last frames");
+            if let Some([r, g, b, a]) = app.renderer.probed() {
+                ui.label(format!("pixel : ({:.3}, {:.3}, {:.3}, {:.3})", r, g, b, a))
+                    .on_hover_text("Linear rgba value of the pixel under the mouse");
+            }
+            let stats = &app.load_stats;
+            if stats.loads + stats.failures > 0 {
+                ui.label(format!(
+                    "loads : {} ({} failed)",
+                    stats.loads + stats.failures,
+                    stats.failures
+                ))
+                .on_hover_text("Shader loads and reloads since the app started");
+                ui.label(format!(
+                    "load time : {} ms (avg {} ms)",
+                    stats.last.as_millis(),
+                    stats.average().as_millis()
+                ))
+                .on_hover_text("Time taken by the last successful load, slow loads may come from large includes");
+                ui.label(stats.breakdown()).on_hover_text("Time spent reading the shader and its includes, extracting the params, compiling to spirv, loading the textures and creating the pipeline");
+            }
+            egui::CollapsingHeader::new("Frame pacing").show(ui, |ui| {
+                let pacing = &app.frame_pacing;
+                ui.label(format!("frame time : {:.2} ms", pacing.mean() * 1000.0))
+                    .on_hover_text("Mean time between two frames over the last frames");
+                ui.label(format!("jitter : {:.2} ms", pacing.jitter() * 1000.0))
+                    .on_hover_text("Standard deviation of the frame times, high values mean an uneven pacing even if the framerate looks fine");
+                ui.label(format!("longest : {:.2} ms", pacing.max() * 1000.0))
+                    .on_hover_text("Longest frame time over the last frames");
+                let dropped = pacing.dropped;
+                ui.horizontal(|ui| {
+                    ui.label(format!("dropped : {}", dropped))
+                        .on_hover_text("Frames that took more than 1.5 times the frame budget. Compare with the gpu time of the watchdog to tell a slow shader from scheduling hiccups");
+                    if ui.small_button("Reset").clicked() {
+                        app.frame_pacing.reset();
+                    }
+                });
+            });
+            ui.label(format!(
+                "gpu memory : {:.1} MiB",
+                app.renderer.memory_estimate() as f64 / (1024.0 * 1024.0)
+            ))
+            .on_hover_text("Estimate of the memory taken by the canvas, previous frames and texture channels");
 
             if ui
                 .small_button("Reset")
@@ -81,14 +210,66 @@ impl Gui {
 
             ui.label("Settings");
 
-            ui.add(
-                DragValue::new(&mut framerate)
-                    .prefix("framerate : ")
-                    .clamp_range(4.0..=120.0)
-                    .max_decimals(0)
-                    .speed(0.1),
-            )
-            .on_hover_text("This is the framerate limit of the whole application.");
+            ui.horizontal(|ui| {
+                let sleep_cap = app.settings.frame_cap == FrameCap::Sleep;
+                ui.add(
+                    DragValue::new(&mut framerate)
+                        .prefix("framerate : ")
+                        .clamp_range(app.settings.framerate_range.0 as f32..=app.settings.framerate_range.1 as f32)
+                        .max_decimals(0)
+                        .speed(0.1),
+                )
+                .on_hover_text(if sleep_cap {
+                    "This is the framerate limit of the whole application."
+                } else {
+                    "The framerate follows the display refresh rate with the vsync cap, this is only passed to the shader."
+                });
+                egui::ComboBox::from_id_source("frame cap")
+                    .selected_text(if sleep_cap { "sleep cap" } else { "vsync cap" })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut app.settings.frame_cap, FrameCap::Sleep, "sleep cap");
+                        ui.selectable_value(&mut app.settings.frame_cap, FrameCap::Vsync, "vsync cap");
+                    })
+                    .response
+                    .on_hover_text("Sleep between frames to reach the framerate, or wait for the display for a smoother pacing");
+            });
+            ui.horizontal(|ui| {
+                let (min, max) = &mut app.settings.framerate_range;
+                ui.add(
+                    DragValue::new(min)
+                        .prefix("range : ")
+                        .clamp_range(1.0..=1000.0)
+                        .speed(0.1),
+                )
+                .on_hover_text("Lowest framerate that can be set");
+                ui.add(
+                    DragValue::new(max)
+                        .prefix("to ")
+                        .clamp_range(1.0..=1000.0)
+                        .speed(0.1),
+                )
+                .on_hover_text("Highest framerate that can be set, raise it for high refresh rate displays");
+                *max = (*max).max(*min);
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut app.settings.idle_framerate)
+                        .prefix("idle framerate : ")
+                        .clamp_range(0.0..=app.settings.framerate_range.1 as f32)
+                        .max_decimals(0)
+                        .speed(0.1),
+                )
+                .on_hover_text("Framerate used when there is no input to save power, 0 to disable");
+                ui.add(
+                    DragValue::new(&mut app.settings.idle_delay)
+                        .prefix("after : ")
+                        .suffix(" s")
+                        .clamp_range(0.0..=600.0)
+                        .max_decimals(1)
+                        .speed(0.1),
+                )
+                .on_hover_text("Time without mouse or keyboard input before using the idle framerate");
+            });
             ui.add(
                 DragValue::new(&mut app.settings.mouse_wheel_step)
                     .prefix("mouse wheel inc : ")
@@ -96,7 +277,229 @@ impl Gui {
                     .max_decimals(3)
                     .speed(0.01),
             )
-            .on_hover_text("The rate of change of the mouse wheel global");
+            .on_hover_text("The rate of change of the mouse wheel global, also the fraction of its range a param moves with ctrl+scroll");
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_source("wheel curve")
+                    .selected_text(format!("{:?}", app.settings.wheel_curve))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut app.settings.wheel_curve, WheelCurve::Linear, "Linear");
+                        ui.selectable_value(&mut app.settings.wheel_curve, WheelCurve::Accelerated, "Accelerated");
+                    })
+                    .response
+                    .on_hover_text("Accelerated makes fast scrolls move the mouse wheel global more");
+                if app.settings.wheel_curve == WheelCurve::Accelerated {
+                    ui.add(
+                        DragValue::new(&mut app.settings.wheel_acceleration)
+                            .prefix("x")
+                            .clamp_range(1.0..=100.0)
+                            .max_decimals(1)
+                            .speed(0.1),
+                    )
+                    .on_hover_text("Increment multiplier when scrolling at 10 notches per second");
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut app.settings.wheel_decay)
+                        .prefix("wheel decay : ")
+                        .clamp_range(0.0..=100.0)
+                        .max_decimals(2)
+                        .speed(0.05),
+                )
+                .on_hover_text("Rate at which the mouse wheel global eases back to its rest value, 0 keeps it where it is");
+                if app.settings.wheel_decay > 0.0 {
+                    ui.add(
+                        DragValue::new(&mut app.settings.wheel_rest)
+                            .prefix("rest : ")
+                            .max_decimals(3)
+                            .speed(0.01),
+                    )
+                    .on_hover_text("Value the mouse wheel global eases back to");
+                }
+            });
+            ui.add(
+                DragValue::new(&mut ui_scale)
+                    .prefix("ui scale : ")
+                    .clamp_range(0.5..=3.0)
+                    .max_decimals(2)
+                    .speed(0.01),
+            )
+            .on_hover_text("Scale of the ui on top of the system scaling");
+            let supports_gpu_timing = app.renderer.supports_gpu_timing();
+            ui.scope(|ui| {
+                ui.set_enabled(supports_gpu_timing);
+                ui.add(
+                    DragValue::new(&mut app.settings.watchdog_ms)
+                        .prefix("watchdog : ")
+                        .suffix(" ms")
+                        .clamp_range(0.0..=10000.0)
+                        .max_decimals(1)
+                        .speed(0.1),
+                )
+                .on_hover_text("Pause the shader when a frame takes longer than this on the gpu, 0 to disable")
+                .on_disabled_hover_text("This device can't measure the gpu time");
+            });
+            ui.add(
+                DragValue::new(&mut app.settings.max_delta)
+                    .prefix("max delta : ")
+                    .suffix(" s")
+                    .clamp_range(0.0..=10.0)
+                    .max_decimals(3)
+                    .speed(0.001),
+            )
+            .on_hover_text("Longest time step given to the shader in fTimeDelta, longer frames are clamped so simulations don't jump, the time keeps running. 0 to disable");
+            ui.add(
+                DragValue::new(&mut app.settings.max_feedback_resolution)
+                    .prefix("feedback cap : ")
+                    .suffix(" px")
+                    .clamp_range(0..=8192)
+                    .speed(8.0),
+            )
+            .on_hover_text("Longest side of the render of shaders sampling previous frames, the canvas is upscaled. Keeps heavy simulations manageable in large windows, 0 to disable");
+            ui.add(
+                DragValue::new(&mut app.settings.utc_offset)
+                    .prefix("utc offset : ")
+                    .suffix(" h")
+                    .clamp_range(-12.0..=14.0)
+                    .max_decimals(2)
+                    .speed(0.25),
+            )
+            .on_hover_text("Offset of the local time from utc, for the fDayColor global");
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut app.settings.loop_duration)
+                        .prefix("loop : ")
+                        .suffix(" s")
+                        .clamp_range(0.0..=3600.0)
+                        .max_decimals(2)
+                        .speed(0.01),
+                )
+                .on_hover_text("Wrap the time around after this duration, 0 to disable");
+                ui.checkbox(&mut app.settings.loop_frame, "frame")
+                    .on_hover_text("Also reset the frame count when the time wraps around");
+            });
+            let mut debug_view = app.settings.debug_view;
+            if ui
+                .checkbox(&mut debug_view, "debug view")
+                .on_hover_text("Draw the shader triangle in wireframe and set the uDebug global")
+                .changed()
+            {
+                app.set_debug_view(debug_view);
+            }
+            let mut uniform_globals = app.settings.uniform_globals;
+            if ui
+                .checkbox(&mut uniform_globals, "globals in a uniform buffer")
+                .on_hover_text("Pass the globals in a uniform buffer instead of push constants, to find out if a rendering bug comes from push constants on this backend. The shader is reloaded.")
+                .changed()
+            {
+                app.set_uniform_globals(uniform_globals);
+            }
+            let mut flip_y = app.globals.flip_y != 0;
+            if ui
+                .checkbox(&mut flip_y, "flip y")
+                .on_hover_text("Put the origin at the bottom left, defaults to on when the shader defines NUANCE_FLIP_Y")
+                .changed()
+            {
+                app.globals.flip_y = flip_y as u32;
+            }
+            ui.checkbox(&mut app.settings.probe, "probe")
+                .on_hover_text("Show the value of the pixel under the mouse, waits for the gpu every frame");
+            ui.checkbox(&mut app.settings.params_overlay, "params overlay")
+                .on_hover_text("List the params values on top of the canvas, for presentations");
+            ui.checkbox(&mut app.settings.watch_on_load, "watch on load")
+                .on_hover_text("Watch the shaders opened with Load, uncheck to open them without reloading on changes");
+            ui.checkbox(&mut app.settings.warm_up, "warm up")
+                .on_hover_text("Draw new shaders once off-screen while loading, so the first frame doesn't stutter while the driver compiles them");
+            ui.checkbox(&mut app.settings.checkerboard, "checkerboard")
+                .on_hover_text("Draw a checkerboard behind the canvas to see its alpha channel");
+            if !app.settings.checkerboard {
+                ui.horizontal(|ui| {
+                    ui.label("background")
+                        .on_hover_text("Color behind the canvas, to preview how the shader looks framed");
+                    for (name, gray) in [("black", 0), ("gray", 128)] {
+                        if ui.selectable_label(app.settings.background == [gray; 3], name).clicked() {
+                            app.settings.background = [gray; 3];
+                        }
+                    }
+                    ui.color_edit_button_srgb(&mut app.settings.background);
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.label("aspect")
+                    .on_hover_text("Fit the canvas to this width/height ratio, the rest of the panel shows the background");
+                for (name, aspect) in [("free", 0.0), ("16:9", 16.0 / 9.0), ("4:3", 4.0 / 3.0), ("1:1", 1.0), ("9:16", 9.0 / 16.0)] {
+                    if ui.selectable_label(app.settings.canvas_aspect == aspect, name).clicked() {
+                        app.settings.canvas_aspect = aspect;
+                    }
+                }
+            });
+            ui.add(
+                egui::Slider::new(&mut app.settings.canvas_aspect, 0.0..=4.0)
+                    .text("custom aspect"),
+            )
+            .on_hover_text("Width/height ratio of the canvas, 0 fills the panel");
+            let mut always_on_top = app.settings.always_on_top;
+            if ui
+                .checkbox(&mut always_on_top, "always on top")
+                .on_hover_text("Keep the window above the others, to watch the shader while editing it elsewhere")
+                .changed()
+            {
+                app.set_always_on_top(always_on_top);
+            }
+            ui.checkbox(&mut app.settings.nearest_filter, "nearest filtering")
+                .on_hover_text("Display the canvas without smoothing, keeps pixel art sharp when the canvas is scaled");
+            let mut canvas_format = app.renderer.canvas_format();
+            egui::ComboBox::from_label("canvas format")
+                .selected_text(format!("{:?}", canvas_format))
+                .show_ui(ui, |ui| {
+                    for format in CANVAS_FORMATS {
+                        ui.selectable_value(&mut canvas_format, *format, format!("{:?}", format));
+                    }
+                })
+                .response
+                .on_hover_text("Float formats are tonemapped before being displayed");
+            if canvas_format != app.renderer.canvas_format() {
+                app.set_canvas_format(canvas_format);
+            }
+            let current_adapter = app.renderer.adapter_info();
+            let mut adapter = app.renderer.adapters().iter().position(|it| *it == current_adapter);
+            egui::ComboBox::from_label("adapter")
+                .selected_text(current_adapter.name.clone())
+                .show_ui(ui, |ui| {
+                    for (i, info) in app.renderer.adapters().iter().enumerate() {
+                        ui.selectable_value(&mut adapter, Some(i), format!("{} ({:?})", info.name, info.backend));
+                    }
+                })
+                .response
+                .on_hover_text("The gpu used to render, switching reopens the window");
+            if let Some(index) = adapter.filter(|it| app.renderer.adapters()[*it] != current_adapter) {
+                app.ask_to_switch_adapter(index);
+            }
+            let mut blend_mode = app.renderer.blend_mode();
+            egui::ComboBox::from_label("blend mode")
+                .selected_text(format!("{:?}", blend_mode))
+                .show_ui(ui, |ui| {
+                    for mode in BlendMode::ALL.iter() {
+                        ui.selectable_value(&mut blend_mode, *mode, format!("{:?}", mode));
+                    }
+                })
+                .response
+                .on_hover_text("How the shader output is combined with the background of the canvas");
+            if blend_mode != app.renderer.blend_mode() {
+                app.set_blend_mode(blend_mode);
+            }
+            if blend_mode != BlendMode::Replace && !app.renderer.can_blend(app.renderer.canvas_format()) {
+                ui.colored_label(Color32::YELLOW, format!("{:?} can't be blended, the output replaces the canvas", app.renderer.canvas_format()));
+            }
+            ui.checkbox(&mut app.settings.dither, "dither")
+                .on_hover_text("Add noise to hide the banding of smooth gradients, 8 bit canvases are rendered in a float texture meanwhile");
+            let can_sanitize = app.renderer.can_sanitize();
+            ui.scope(|ui| {
+                ui.set_enabled(can_sanitize);
+                ui.checkbox(&mut app.settings.sanitize, "show NaN")
+                    .on_hover_text("Replace NaN and infinite pixels with magenta, so they are visible and don't spread through the previous frames")
+                    .on_disabled_hover_text("Only float canvas formats can hold NaN, 8 bit canvases store them as 0");
+            });
 
             ui.separator();
 
@@ -104,30 +507,123 @@ impl Gui {
                 if ui.button("Load").on_hover_text("Load a new shader").clicked() {
                     app.ask_to_load();
                 }
+                if ui.button("New window").on_hover_text("Open another independent window").clicked() {
+                    app.ask_to_open_window();
+                }
                 if app.shader_loaded() {
                     if ui.button("Reload").on_hover_text("Reload this shader").clicked() {
                         app.reload_shader();
                     }
-                    if ui.checkbox(&mut app.watching, "watch").on_hover_text("Watch for changes (on the filesystem) and reload the shader when necessary").changed() {
-                        if app.watching {
-                            app.watch();
-                        } else {
-                            app.unwatch();
-                        }
+                    let mut watching = app.watching;
+                    if !app.shader_is_url() && ui.checkbox(&mut watching, "watch").on_hover_text("Watch for changes (on the filesystem) and reload the shader when necessary").changed() {
+                        app.set_watching(watching);
                     }
                     if ui.button("Export").on_hover_text("Opens a window to export an image").clicked() {
                         app.gui.export_window = true;
                     }
+                    if !app.shader_is_url() && ui.button("Edit").on_hover_text("Edit the shader source in the app").clicked() {
+                        app.open_editor();
+                    }
+                }
+            });
+
+            if app.shader_loaded() {
+                ui.horizontal(|ui| {
+                    if ui.button("Load B").on_hover_text("Load a variant of this shader to compare side by side, it must declare the same params and textures").clicked() {
+                        app.ask_to_load_compare();
+                    }
+                    if app.compare_shader.is_some() {
+                        ui.checkbox(&mut app.settings.compare, "A/B").on_hover_text("Show this shader left of the divider and the variant right of it");
+                        if ui.button("Close B").on_hover_text("Stop comparing with the variant").clicked() {
+                            app.close_compare_shader();
+                        }
+                    }
+                });
+                if let Some(compare) = app.compare_shader.as_ref() {
+                    ui.colored_label(Color32::LIGHT_BLUE, format!("B : {}", compare.main.display()));
+                }
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Load session").on_hover_text("Load a shader with its params and time from a session file").clicked() {
+                    app.ask_to_load_session();
+                }
+                if app.shader_loaded() && ui.button("Save session").on_hover_text("Save the shader, its params and time to a session file").clicked() {
+                    app.ask_to_save_session();
+                }
+                if app.shader_loaded() && ui.button("Copy share string").on_hover_text("Copy the shader source, its params and time as text to paste in the url field of another nuance").clicked() {
+                    match app.share_string() {
+                        Ok(text) => ui.output().copied_text = text,
+                        Err(e) => error!("{:#}", e),
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if app.input_recording.is_some() {
+                    if ui.button("Stop recording").on_hover_text("Stop recording the inputs and save them to a file").clicked() {
+                        app.stop_input_recording();
+                    }
+                } else if app.shader_loaded() && ui.button("Record inputs").on_hover_text("Restart the shader and record the mouse and keyboard inputs, to attach to a bug report").clicked() {
+                    app.start_input_recording();
+                }
+                if app.input_recording.is_none() && app.shader_loaded() && ui.button("Replay inputs").on_hover_text("Restart the shader and replay recorded inputs").clicked() {
+                    app.ask_replay_inputs = true;
+                }
+                if app.input_replay.is_some() {
+                    ui.colored_label(Color32::LIGHT_BLUE, "replaying");
+                }
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    TextEdit::singleline(&mut app.gui.url)
+                        .hint_text("https:// or nuance:")
+                        .desired_width(160.0),
+                );
+                if ui
+                    .button("Load url")
+                    .on_hover_text("Download a shader and load it, or load a share string")
+                    .clicked()
+                {
+                    app.load_url();
                 }
             });
 
             // Shader name
             if let Some(shader) = app.shader.as_ref() {
                 ui.colored_label(Color32::GREEN, shader.main.to_str().unwrap());
+                if !shader.warnings.is_empty() {
+                    egui::CollapsingHeader::new(format!("{} warnings", shader.warnings.len()))
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            for warning in shader.warnings.iter() {
+                                ui.colored_label(Color32::YELLOW, warning);
+                            }
+                        });
+                }
             } else {
                 ui.colored_label(Color32::RED, "No shader");
             }
 
+            if let Some(error) = app.load_error.as_ref() {
+                ui.colored_label(Color32::RED, error.to_string());
+                if ui
+                    .button("Copy error")
+                    .on_hover_text("Copy the error with the offending source lines")
+                    .clicked()
+                {
+                    ui.output().copied_text = app.load_error_report().unwrap_or_default();
+                }
+                if ui
+                    .add(egui::Button::new("Revert").enabled(app.last_good_source.is_some()))
+                    .on_hover_text("Keep the last shader that compiled and put its source back in the editor")
+                    .clicked()
+                {
+                    app.revert_to_last_good();
+                }
+            }
+
             if app.shader_loaded() && ui.selectable_label(app.is_paused(), "Pause").on_hover_text("Pause the current shader execution").clicked() {
                 if app.is_paused() {
                     app.resume();
@@ -136,30 +632,124 @@ impl Gui {
                 }
             }
 
+            if let Some(gpu_time) = app.watchdog_tripped {
+                ui.colored_label(
+                    Color32::RED,
+                    format!(
+                        "Paused by the watchdog, a frame took {:.1} ms",
+                        gpu_time.as_secs_f32() * 1000.0
+                    ),
+                );
+            }
+
             let mut should_reset_params = false;
-            if let Some(metadata) = app.shader_metadata_mut() {
+            let mut should_randomize_params = false;
+            let mut params_changed = false;
+            let scroll = ParamScroll {
+                notches: mem::take(&mut app.param_scroll),
+                step: app.settings.mouse_wheel_step,
+            };
+            if let Some(metadata) = app.shader.as_mut().and_then(|it| it.metadata.as_mut()) {
+                let order = app.gui.param_display_order(&metadata.sliders);
                 ui.separator();
                 ui.horizontal(|ui| {
                     ui.label("Params").on_hover_text("Params are special values you can declare in your shader and tweak in this panel");
                     if ui.button("Reset").on_hover_text("Reset all params to their default values").clicked() {
                         should_reset_params = true;
                     }
+                    if ui.button("🎲 Randomize").on_hover_text("Set the unlocked params to random values within their range").clicked() {
+                        should_randomize_params = true;
+                    }
+                    if ui.button("Copy GLSL").on_hover_text("Copy the params block with the current values as initial values, to paste in the shader").clicked() {
+                        ui.output().copied_text = metadata.params_glsl();
+                    }
                 });
                 let sliders = &mut metadata.sliders;
+                let param_locks = &mut app.gui.param_locks;
+                // Position of the moved param in the display order and where it goes
+                let mut moved = None;
+                let mut dropped = None;
+                let mut rows = Vec::with_capacity(order.len());
                 egui::Grid::new("params grid")
                     .striped(true)
                     //.max_col_width(self.ui_width as f32 - 20.0)
                     .show(ui, |ui| {
-                        for slider in sliders {
-                            draw_slider(slider, ui);
+                        for (position, &index) in order.iter().enumerate() {
+                            let slider = &mut sliders[index];
+                            // The id follows the param so it keeps the focus when it moves
+                            let label = ui.label("☰");
+                            let handle = ui
+                                .interact(label.rect, Id::new(("param handle", slider.name())), Sense::click_and_drag())
+                                .on_hover_cursor(CursorIcon::Grab)
+                                .on_hover_text("Drag to reorder, or click and use the up and down arrows");
+                            if handle.clicked() {
+                                handle.request_focus();
+                            }
+                            if handle.has_focus() {
+                                if ui.input().key_pressed(Key::ArrowUp) && position > 0 {
+                                    moved = Some((position, position - 1));
+                                } else if ui.input().key_pressed(Key::ArrowDown) && position + 1 < order.len() {
+                                    moved = Some((position, position + 1));
+                                }
+                            }
+                            if handle.drag_released() {
+                                dropped = Some(position);
+                            }
+                            rows.push(label.rect.center().y);
+                            params_changed |= draw_slider(slider, ui, &scroll);
+                            draw_lock(ui, param_locks, slider.name());
                             ui.end_row();
                         }
                     });
+                // Dropped on the row closest to the cursor
+                if let (Some(from), Some(pointer)) = (dropped, ui.input().pointer.hover_pos()) {
+                    let to = rows
+                        .iter()
+                        .enumerate()
+                        .min_by(|(_, a), (_, b)| (*a - pointer.y).abs().partial_cmp(&(*b - pointer.y).abs()).unwrap())
+                        .map_or(from, |(to, _)| to);
+                    moved = Some((from, to));
+                }
+                if let Some((from, to)) = moved {
+                    let mut names: Vec<String> = order.iter().map(|&index| sliders[index].name().to_string()).collect();
+                    let name = names.remove(from);
+                    names.insert(to, name);
+                    app.gui.param_order = names;
+                }
+            }
+
+            let mut texture_filter = None;
+            if let Some(metadata) = app.shader.as_ref().and_then(|it| it.metadata.as_ref()).filter(|it| !it.textures.is_empty()) {
+                ui.separator();
+                ui.label("Textures").on_hover_text("Texture channels declared by the shader");
+                for channel in metadata.textures.iter() {
+                    ui.horizontal(|ui| {
+                        let name = match &channel.source {
+                            ChannelSource::Image(path) => path.file_name().map(|it| it.to_string_lossy().into_owned()),
+                            ChannelSource::Cubemap(_) => Some("cubemap".to_string()),
+                            ChannelSource::Keyboard => Some("keyboard".to_string()),
+                        };
+                        ui.label(format!("{} {}", channel.index, name.unwrap_or_default()));
+                        let mut nearest = channel.filter == FilterMode::Nearest;
+                        if ui.checkbox(&mut nearest, "nearest").on_hover_text("Sample without interpolation, the pragma setting is restored on reload").changed() {
+                            texture_filter = Some((channel.index, if nearest { FilterMode::Nearest } else { FilterMode::Linear }));
+                        }
+                    });
+                }
+            }
+            if let Some((index, filter)) = texture_filter {
+                app.set_texture_filter(index, filter);
             }
 
             if should_reset_params {
                 app.reset_params();
             }
+            if should_randomize_params {
+                app.randomize_params();
+            }
+            if params_changed {
+                app.mark_params_dirty();
+            }
 
             ui.add_space(ui.available_size().y - 2.0 * ui.spacing().item_spacing.y - 30.0);
             ui.vertical_centered(|ui| {
@@ -172,32 +762,64 @@ impl Gui {
                     "https://github.com/Gui-Yom/nuance",
                 );
             });
-        }).response;
+        }).response);
+        let side_panel_width = side_panel.map_or(0.0, |it| it.rect.max.x);
 
         // Update the size of the side panel
         // We want to resize the canvas it changes
-        app.gui.ui_width = side_panel.rect.max.x.round() as u32;
+        app.gui.ui_width = side_panel_width.round() as u32;
 
         //log::info!("{:?}", app.gui.ui_width);
         //log::info!("{:?}", app.gui.context().used_size());
 
-        egui::CentralPanel::default()
+        let canvas_rect = egui::CentralPanel::default()
             .frame(Frame::none())
             .show(&app.gui.context(), |ui| {
-                ui.image(
-                    TextureId::User(0),
-                    egui::Vec2::new(
-                        window.physical_width as f32 / window.scale_factor - side_panel.rect.max.x,
-                        window.physical_height as f32 / window.scale_factor,
-                    ),
+                let size = egui::Vec2::new(
+                    window.physical_width as f32 / window.scale_factor - side_panel_width,
+                    window.physical_height as f32 / window.scale_factor,
                 );
-            });
+                let (panel, _) = ui.allocate_exact_size(size, Sense::hover());
+                // The background fills the letterbox bars around the fitted canvas
+                let [r, g, b] = app.settings.background;
+                ui.painter()
+                    .rect_filled(panel, 0.0, Color32::from_rgb(r, g, b));
+                let (width, height) = super::fit_aspect(size.x, size.y, app.settings.canvas_aspect);
+                let rect = Rect::from_center_size(panel.center(), egui::vec2(width, height));
+                if app.settings.checkerboard {
+                    paint_checkerboard(ui, rect);
+                }
+                egui::Image::new(app.renderer.canvas_texture_id(), rect.size()).paint_at(ui, rect);
+                if app.settings.compare && app.compare_shader.is_some() {
+                    draw_divider(ui, rect, &mut app.settings.compare_split);
+                }
+                rect
+            })
+            .inner;
+
+        if app.settings.params_overlay {
+            if let Some(metadata) = app.shader_metadata() {
+                egui::Area::new("params overlay")
+                    .fixed_pos(canvas_rect.min + egui::Vec2::splat(8.0))
+                    .interactable(false)
+                    .show(&app.gui.context(), |ui| {
+                        Frame::popup(ui.style()).show(ui, |ui| {
+                            for index in app.gui.param_display_order(&metadata.sliders) {
+                                let slider = &metadata.sliders[index];
+                                ui.label(format!("{} : {}", slider.name(), format_param(slider)));
+                            }
+                        });
+                    });
+            }
+        }
 
         let mut should_ask_export = false;
 
         let format_ref = &mut app.export_data.format;
         let size_x_ref = &mut app.export_data.size.x;
         let size_y_ref = &mut app.export_data.size.y;
+        let high_bit_depth_ref = &mut app.export_data.high_bit_depth;
+        let watermark = &mut app.export_data.watermark;
         egui::Window::new("Export image")
             .id(Id::new("export image window"))
             .open(&mut app.gui.export_window)
@@ -213,6 +835,11 @@ impl Gui {
                         ui.selectable_value(format_ref, ImageFormat::Gif, "GIF");
                         ui.selectable_value(format_ref, ImageFormat::Jpeg, "JPEG");
                     });
+                if *format_ref == ImageFormat::Png {
+                    ui.checkbox(high_bit_depth_ref, "16 bit").on_hover_text(
+                        "Render in a float format and save a 16 bit png, highlights above 1 are still clipped",
+                    );
+                }
 
                 ui.horizontal(|ui| {
                     ui.label("Size :");
@@ -224,6 +851,21 @@ impl Gui {
                     ui.colored_label(Color32::RED, "× Image width must be a multiple of 64");
                 }
 
+                ui.checkbox(&mut watermark.enabled, "watermark")
+                    .on_hover_text("Burn a line of text into the exported image");
+                if watermark.enabled {
+                    ui.add(TextEdit::singleline(&mut watermark.text))
+                        .on_hover_text("{shader} is replaced by the shader file name and {time} by the shader time");
+                    egui::ComboBox::from_label("corner")
+                        .selected_text(format!("{:?}", watermark.corner))
+                        .show_ui(ui, |ui| {
+                            for corner in Corner::ALL.iter() {
+                                ui.selectable_value(&mut watermark.corner, *corner, format!("{:?}", corner));
+                            }
+                        });
+                    ui.add(egui::Slider::new(&mut watermark.opacity, 0.0..=1.0).text("opacity"));
+                }
+
                 if ui.button("export").clicked() {
                     should_ask_export = true;
                 }
@@ -233,6 +875,21 @@ impl Gui {
             app.ask_to_export();
         }
 
+        let mut should_save_editor = false;
+        if let Some(editor) = app.gui.editor.as_mut() {
+            egui::Window::new(format!("Editor - {}", editor.path().display()))
+                .id(Id::new("editor window"))
+                .open(&mut app.gui.editor_window)
+                .scroll(false)
+                .show(&app.gui.egui_platform.context(), |ui| {
+                    should_save_editor = editor.show(ui);
+                });
+        }
+
+        if should_save_editor {
+            app.save_editor();
+        }
+
         if app.gui.profiling_window {
             app.gui.profiling_window = puffin_egui::profiler_window(&app.gui.context());
         }
@@ -240,7 +897,10 @@ impl Gui {
         // End the UI frame. We could now handle the output and draw the UI with the backend.
         let (_, paint_commands) = app.gui.egui_platform.end_frame(Some(&app.window));
 
-        app.settings.target_framerate = Duration::from_secs_f32(1.0 / framerate as f32);
+        app.set_target_framerate(framerate);
+        if ui_scale != app.gui.ui_scale {
+            app.gui.set_ui_scale(ui_scale, &app.window);
+        }
 
         app.gui.context().tessellate(paint_commands)
     }
@@ -254,68 +914,261 @@ impl Gui {
     }
 }
 
-fn draw_slider(slider: &mut Slider, ui: &mut Ui) {
+/// Ctrl+scroll over a param since the last frame
+struct ParamScroll {
+    notches: f32,
+    /// Fraction of the range of a float param covered by a notch
+    step: f32,
+}
+
+impl ParamScroll {
+    /// Move the value of the hovered param, returns true if it changed
+    fn apply_float(&self, response: &Response, value: &mut f32, min: f32, max: f32) -> bool {
+        if self.notches == 0.0 || !response.hovered() {
+            return false;
+        }
+        let range = max - min;
+        let step = if range > 0.0 && range.is_finite() {
+            self.step * range
+        } else {
+            self.step
+        };
+        let old = *value;
+        *value = (*value + self.notches * step).clamp(min.min(max), max.max(min));
+        *value != old
+    }
+
+    /// Uint params move by 1 per notch
+    fn apply_uint(&self, response: &Response, value: &mut u32, min: u32, max: u32) -> bool {
+        if self.notches == 0.0 || !response.hovered() {
+            return false;
+        }
+        let old = *value;
+        let new = *value as i64 + self.notches.round() as i64;
+        *value = new.clamp(min.min(max) as i64, max.max(min) as i64) as u32;
+        *value != old
+    }
+}
+
+/// Returns true if the value changed
+fn draw_slider(slider: &mut Slider, ui: &mut Ui, scroll: &ParamScroll) -> bool {
     match slider {
         Slider::Float {
             name,
+            description,
             min,
             max,
+            logarithmic,
+            expr,
+            scripted,
+            format,
             value,
             ..
         } => {
-            ui.label(name.as_str());
-            ui.add(
-                DragValue::new(value)
-                    .clamp_range(*min..=*max)
-                    .speed((*max - *min) / ui.available_width())
-                    .max_decimals(3),
-            );
+            param_label(ui, name, description);
+            let response = if *logarithmic {
+                ui.add(
+                    egui::Slider::new(value, *min..=*max)
+                        .logarithmic(true)
+                        .suffix(&format.suffix)
+                        .max_decimals(format.decimals),
+                )
+            } else {
+                ui.add(
+                    DragValue::new(value)
+                        .clamp_range(*min..=*max)
+                        .speed(drag_speed(*min as f64, *max as f64, ui))
+                        .suffix(&format.suffix)
+                        .max_decimals(format.decimals),
+                )
+            };
+            let changed = response.changed() | scroll.apply_float(&response, value, *min, *max);
+            if let Some(expr) = expr {
+                ui.checkbox(scripted, "expr")
+                    .on_hover_text(format!("Follow the expression {} instead of the ui", expr));
+            }
+            changed
         }
         Slider::Uint {
             name,
+            description,
             min,
             max,
+            format,
             value,
             ..
         } => {
-            ui.label(name.as_str());
-            ui.add(
+            param_label(ui, name, description);
+            let response = ui.add(
                 DragValue::new(value)
                     .clamp_range(*min..=*max)
-                    .speed((*max - *min) as f32 / ui.available_width())
-                    .max_decimals(3),
+                    .speed(drag_speed(*min as f64, *max as f64, ui))
+                    .suffix(&format.suffix),
             );
+            response.changed() | scroll.apply_uint(&response, value, *min, *max)
         }
-        Slider::Vec2 { name, value, .. } => {
-            ui.label(name.as_str());
+        Slider::Vec2 {
+            name,
+            description,
+            format,
+            value,
+            ..
+        } => {
+            param_label(ui, name, description);
             ui.spacing_mut().item_spacing.x = 2.0;
             ui.columns(2, |columns| {
-                columns[0].add(DragValue::new(&mut value.x).speed(0.01).max_decimals(3));
-                columns[1].add(DragValue::new(&mut value.y).speed(0.01).max_decimals(3));
-            });
+                let x = columns[0].add(component_drag(&mut value.x, format));
+                let y = columns[1].add(component_drag(&mut value.y, format));
+                x.changed() || y.changed()
+            })
         }
-        Slider::Vec3 { name, value, .. } => {
-            ui.label(name.as_str());
+        Slider::Vec3 {
+            name,
+            description,
+            format,
+            value,
+            ..
+        } => {
+            param_label(ui, name, description);
             ui.spacing_mut().item_spacing.x = 2.0;
             ui.columns(3, |columns| {
-                columns[0].add(DragValue::new(&mut value.x).speed(0.01).max_decimals(3));
-                columns[1].add(DragValue::new(&mut value.y).speed(0.01).max_decimals(3));
-                columns[2].add(DragValue::new(&mut value.z).speed(0.01).max_decimals(3));
-            });
+                let x = columns[0].add(component_drag(&mut value.x, format));
+                let y = columns[1].add(component_drag(&mut value.y, format));
+                let z = columns[2].add(component_drag(&mut value.z, format));
+                x.changed() || y.changed() || z.changed()
+            })
         }
-        Slider::Color { name, value, .. } => {
-            ui.label(name.as_str());
+        Slider::Color {
+            name,
+            description,
+            value,
+            ..
+        } => {
+            param_label(ui, name, description);
             // I feel bad for doing this BUT mint only implements AsRef but not AsMut,
             // so this right here is the same implementation as AsRef but mutable
             let ref_mut = unsafe { mem::transmute(value) };
-            ui.color_edit_button_rgb(ref_mut);
+            ui.color_edit_button_rgb(ref_mut).changed()
         }
-        Slider::Bool { name, value, .. } => {
-            ui.label(name.as_str());
+        Slider::Bool {
+            name,
+            description,
+            value,
+            ..
+        } => {
+            param_label(ui, name, description);
             let mut val = *value != 0;
-            if ui.checkbox(&mut val, "").changed() {
+            let changed = ui.checkbox(&mut val, "").changed();
+            if changed {
                 *value = if val { 1 } else { 0 };
             }
+            changed
+        }
+    }
+}
+
+/// Drag value of a vector component
+fn component_drag<'a>(value: &'a mut f32, format: &ParamFormat) -> DragValue<'a> {
+    DragValue::new(value)
+        .speed(0.01)
+        .suffix(&format.suffix)
+        .max_decimals(format.decimals)
+}
+
+/// Speed at which dragging across the panel covers the whole range of a param
+fn drag_speed(min: f64, max: f64, ui: &Ui) -> f64 {
+    let range = max - min;
+    if range > 0.0 && range.is_finite() {
+        range / ui.available_width().max(1.0) as f64
+    } else {
+        // Empty or inverted range
+        0.01
+    }
+}
+
+/// Toggle keeping a param out of the randomization
+fn draw_lock(ui: &mut Ui, locks: &mut HashSet<String>, name: &str) {
+    let locked = locks.contains(name);
+    if ui
+        .selectable_label(locked, "🔒")
+        .on_hover_text("Keep this param when randomizing")
+        .clicked()
+    {
+        if locked {
+            locks.remove(name);
+        } else {
+            locks.insert(name.to_string());
+        }
+    }
+}
+
+/// Short text of a param value
+fn format_param(slider: &Slider) -> String {
+    match slider {
+        Slider::Float { value, format, .. } => {
+            format!("{:.*}{}", format.decimals, value, format.suffix)
+        }
+        Slider::Uint { value, format, .. } => format!("{}{}", value, format.suffix),
+        Slider::Bool { value, .. } => (if *value != 0 { "on" } else { "off" }).to_string(),
+        Slider::Vec2 { value, .. } => format!("({:.3}, {:.3})", value.x, value.y),
+        Slider::Vec3 { value, .. } | Slider::Color { value, .. } => {
+            format!("({:.3}, {:.3}, {:.3})", value.x, value.y, value.z)
+        }
+    }
+}
+
+/// The param name, with its description as a tooltip if there is one
+fn param_label(ui: &mut Ui, name: &str, description: &Option<String>) {
+    let response = ui.label(name);
+    if let Some(description) = description {
+        response.on_hover_text(description);
+    }
+}
+
+/// Line between the main shader and the compared one, dragged to move the split
+fn draw_divider(ui: &mut Ui, canvas: Rect, split: &mut f32) {
+    const HANDLE_WIDTH: f32 = 8.0;
+    let x = canvas.left() + canvas.width() * *split;
+    let handle = Rect::from_min_max(
+        egui::pos2(x - HANDLE_WIDTH / 2.0, canvas.top()),
+        egui::pos2(x + HANDLE_WIDTH / 2.0, canvas.bottom()),
+    );
+    let response = ui
+        .interact(handle, Id::new("compare divider"), Sense::drag())
+        .on_hover_cursor(CursorIcon::ResizeHorizontal);
+    if let Some(pos) = response.interact_pointer_pos() {
+        *split = ((pos.x - canvas.left()) / canvas.width()).clamp(0.0, 1.0);
+    }
+
+    let painter = ui.painter();
+    let x = canvas.left() + canvas.width() * *split;
+    painter.line_segment(
+        [egui::pos2(x, canvas.top()), egui::pos2(x, canvas.bottom())],
+        Stroke::new(2.0, Color32::WHITE),
+    );
+    for (label, anchor, offset) in [("A", Align2::RIGHT_TOP, -8.0), ("B", Align2::LEFT_TOP, 8.0)] {
+        painter.text(
+            egui::pos2(x + offset, canvas.top() + 8.0),
+            anchor,
+            label,
+            TextStyle::Heading,
+            Color32::WHITE,
+        );
+    }
+}
+
+/// Checkerboard in the canvas background, visible through transparent pixels
+fn paint_checkerboard(ui: &Ui, rect: Rect) {
+    const CELL: f32 = 16.0;
+    let painter = ui.painter();
+    painter.rect_filled(rect, 0.0, Color32::from_gray(102));
+    let cols = (rect.width() / CELL).ceil() as u32;
+    let rows = (rect.height() / CELL).ceil() as u32;
+    for row in 0..rows {
+        for col in (row % 2..cols).step_by(2) {
+            let min = rect.min + egui::vec2(col as f32, row as f32) * CELL;
+            let cell = Rect::from_min_size(min, egui::Vec2::splat(CELL)).intersect(rect);
+            painter.rect_filled(cell, 0.0, Color32::from_gray(153));
         }
     }
 }
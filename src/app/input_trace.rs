@@ -0,0 +1,117 @@
+//! Input traces, the window inputs recorded with the frame they happened at. Replaying a trace
+//! feeds the inputs back through the same handlers, to reproduce interaction dependent bugs.
+//! The window must have the same size for the cursor positions to match.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use winit::dpi::PhysicalPosition;
+use winit::event::{
+    DeviceId, ElementState, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta,
+    TouchPhase, WindowEvent,
+};
+
+pub const INPUT_TRACE_EXTENSION: &str = "inputs";
+
+/// The part of a window event needed to replay it
+#[derive(Serialize, Deserialize)]
+pub enum Input {
+    CursorMoved(PhysicalPosition<f64>),
+    MouseInput {
+        state: ElementState,
+        button: MouseButton,
+    },
+    MouseWheel(MouseScrollDelta),
+    Keyboard(KeyboardInput),
+    Modifiers(ModifiersState),
+}
+
+impl Input {
+    /// None for the events that aren't inputs
+    pub fn from_event(event: &WindowEvent) -> Option<Self> {
+        Some(match event {
+            WindowEvent::CursorMoved { position, .. } => Input::CursorMoved(*position),
+            WindowEvent::MouseInput { state, button, .. } => Input::MouseInput {
+                state: *state,
+                button: *button,
+            },
+            WindowEvent::MouseWheel { delta, .. } => Input::MouseWheel(*delta),
+            WindowEvent::KeyboardInput { input, .. } => Input::Keyboard(*input),
+            WindowEvent::ModifiersChanged(modifiers) => Input::Modifiers(*modifiers),
+            _ => return None,
+        })
+    }
+
+    /// The window event this input came from
+    #[allow(deprecated)]
+    pub fn to_event(&self) -> WindowEvent<'static> {
+        // Handlers don't look at the device
+        let device_id = unsafe { DeviceId::dummy() };
+        match *self {
+            Input::CursorMoved(position) => WindowEvent::CursorMoved {
+                device_id,
+                position,
+                modifiers: ModifiersState::empty(),
+            },
+            Input::MouseInput { state, button } => WindowEvent::MouseInput {
+                device_id,
+                state,
+                button,
+                modifiers: ModifiersState::empty(),
+            },
+            Input::MouseWheel(delta) => WindowEvent::MouseWheel {
+                device_id,
+                delta,
+                phase: TouchPhase::Moved,
+                modifiers: ModifiersState::empty(),
+            },
+            Input::Keyboard(input) => WindowEvent::KeyboardInput {
+                device_id,
+                input,
+                is_synthetic: false,
+            },
+            Input::Modifiers(modifiers) => WindowEvent::ModifiersChanged(modifiers),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct InputTrace {
+    /// Inputs in order, with the number of frames drawn since the recording started
+    inputs: VecDeque<(u32, Input)>,
+}
+
+impl InputTrace {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Can't read input trace {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid input trace {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string(self)?;
+        fs::write(path, contents)
+            .with_context(|| format!("Can't write input trace {}", path.display()))
+    }
+
+    pub fn push(&mut self, frame: u32, input: Input) {
+        self.inputs.push_back((frame, input));
+    }
+
+    /// Take the next input if its frame has come
+    pub fn next_due(&mut self, frame: u32) -> Option<Input> {
+        match self.inputs.front() {
+            Some((at, _)) if *at <= frame => self.inputs.pop_front().map(|it| it.1),
+            _ => None,
+        }
+    }
+
+    /// All the inputs got replayed
+    pub fn is_done(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
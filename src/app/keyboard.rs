@@ -0,0 +1,100 @@
+//! Keyboard state exposed to shaders as a texture, like the keyboard channel of Shadertoy
+
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+/// One texel per key code
+pub const KEYBOARD_TEX_WIDTH: u32 = 256;
+/// Rows are : key down, key pressed this frame, key toggled
+pub const KEYBOARD_TEX_HEIGHT: u32 = 3;
+
+const DOWN_ROW: usize = 0;
+const PRESSED_ROW: usize = 1;
+const TOGGLED_ROW: usize = 2;
+
+pub struct Keyboard {
+    data: Vec<u8>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            data: vec![0; (KEYBOARD_TEX_WIDTH * KEYBOARD_TEX_HEIGHT) as usize],
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn handle_input(&mut self, input: &KeyboardInput) {
+        let code = match input.virtual_keycode.and_then(js_keycode) {
+            Some(code) => code as usize,
+            None => return,
+        };
+        match input.state {
+            ElementState::Pressed => {
+                // Ignore key repeats
+                if self.texel(DOWN_ROW, code) == 0 {
+                    *self.texel_mut(DOWN_ROW, code) = 255;
+                    *self.texel_mut(PRESSED_ROW, code) = 255;
+                    *self.texel_mut(TOGGLED_ROW, code) ^= 255;
+                }
+            }
+            ElementState::Released => {
+                *self.texel_mut(DOWN_ROW, code) = 0;
+            }
+        }
+    }
+
+    /// The pressed state only lasts one frame
+    pub fn end_frame(&mut self) {
+        let width = KEYBOARD_TEX_WIDTH as usize;
+        self.data[PRESSED_ROW * width..(PRESSED_ROW + 1) * width].fill(0);
+    }
+
+    /// Texture data in the R8Unorm format
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn texel(&self, row: usize, code: usize) -> u8 {
+        self.data[row * KEYBOARD_TEX_WIDTH as usize + code]
+    }
+
+    fn texel_mut(&mut self, row: usize, code: usize) -> &mut u8 {
+        &mut self.data[row * KEYBOARD_TEX_WIDTH as usize + code]
+    }
+}
+
+/// Javascript key codes, used by shaders written for Shadertoy
+fn js_keycode(key: VirtualKeyCode) -> Option<u8> {
+    use VirtualKeyCode::*;
+
+    let offset = |first: VirtualKeyCode, base: u8| base + (key as u32 - first as u32) as u8;
+    Some(match key {
+        Key1 | Key2 | Key3 | Key4 | Key5 | Key6 | Key7 | Key8 | Key9 => offset(Key1, 49),
+        Key0 => 48,
+        A | B | C | D | E | F | G | H | I | J | K | L | M | N | O | P | Q | R | S | T | U | V
+        | W | X | Y | Z => offset(A, 65),
+        F1 | F2 | F3 | F4 | F5 | F6 | F7 | F8 | F9 | F10 | F11 | F12 => offset(F1, 112),
+        Numpad0 | Numpad1 | Numpad2 | Numpad3 | Numpad4 | Numpad5 | Numpad6 | Numpad7 | Numpad8
+        | Numpad9 => offset(Numpad0, 96),
+        Back => 8,
+        Tab => 9,
+        Return => 13,
+        LShift | RShift => 16,
+        LControl | RControl => 17,
+        LAlt | RAlt => 18,
+        Escape => 27,
+        Space => 32,
+        PageUp => 33,
+        PageDown => 34,
+        End => 35,
+        Home => 36,
+        Left => 37,
+        Up => 38,
+        Right => 39,
+        Down => 40,
+        Insert => 45,
+        Delete => 46,
+        _ => return None,
+    })
+}
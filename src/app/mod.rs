@@ -1,42 +1,252 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::mpsc::Receiver;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
-use crevice::std430::AsStd430;
-use crevice::std430::Std430;
+use anyhow::{bail, Context, Result};
 use egui::{FontDefinitions, Style};
 use egui_wgpu_backend::ScreenDescriptor;
 use egui_winit_platform::{Platform, PlatformDescriptor};
 use image::{ImageBuffer, ImageFormat, Rgba};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use mint::Vector2;
 use notify::{watcher, DebouncedEvent, Error, RecommendedWatcher, RecursiveMode, Watcher};
-use rfd::FileDialog;
-use winit::event::{Event, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+use rfd::{FileDialog, MessageButtons, MessageDialog, MessageLevel};
+use wgpu::{FilterMode, TextureFormat};
+use winit::dpi::PhysicalPosition;
+use winit::event::{
+    ElementState, Event, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
 use winit::event_loop::ControlFlow;
-use winit::window::Window;
+use winit::window::{Fullscreen, Window, WindowId};
 
-use nuance::loader::ShaderLoader;
+use nuance::builtins::{day_color, BuiltinValue, Builtins, PUSH_CONSTANTS_SIZE};
+use nuance::loader::{LoadTimes, ShaderLoadError, ShaderLoader};
+use nuance::renderer::{BlendMode, GlobalsMode};
 use nuance::Globals;
-use nuance::{Shader, ShaderMetadata};
+use nuance::{MouseAxis, Shader, ShaderMetadata, Slider};
 
+use crate::app::editor::Editor;
 use crate::app::gui::Gui;
+use crate::app::input_trace::{Input, InputTrace, INPUT_TRACE_EXTENSION};
+use crate::app::keyboard::Keyboard;
+#[cfg(feature = "osc")]
+use crate::app::osc::OscInput;
 use crate::app::renderer::Renderer;
+use crate::app::script::{Script, ScriptCommand};
+use crate::app::session::{
+    set_slider_values, slider_values, Session, SESSION_EXTENSION, SESSION_VERSION,
+};
+use crate::app::share::{Shared, SHARE_PREFIX};
+use crate::app::watermark::Watermark;
+use crate::cli::ParamOverride;
 
+mod accumulate;
+mod editor;
 mod gui;
+mod input_trace;
+mod keyboard;
+#[cfg(feature = "osc")]
+mod osc;
+mod post;
+mod probe;
+mod recovery;
 mod renderer;
+mod sanitize;
+mod script;
+mod session;
+mod share;
+mod timer;
+mod watermark;
+
+pub use recovery::install_panic_hook;
+
+/// Number of frame times used to compute the measured framerate
+const FRAME_TIMES_WINDOW: usize = 16;
+/// Number of frame times used for the pacing statistics
+const PACING_WINDOW: usize = 240;
+/// A frame taking this many times the frame budget counts as dropped
+const DROPPED_FRAME_FACTOR: f32 = 1.5;
+/// Interval between two snapshots of the session saved on panic
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response of the mouse wheel global to scrolling
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WheelCurve {
+    /// Every notch moves by the same increment
+    Linear,
+    /// Fast scrolls move more per notch, for both fine and coarse control
+    Accelerated,
+}
+
+/// How the framerate is limited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameCap {
+    /// Sleep between frames to reach the target framerate
+    Sleep,
+    /// Wait for the display when presenting, smoother on displays matching the target but the
+    /// framerate follows their refresh rate
+    Vsync,
+}
 
 pub struct Settings {
     pub target_framerate: Duration,
+    /// Lowest and highest target framerates that can be set
+    pub framerate_range: (u32, u32),
+    pub frame_cap: FrameCap,
     pub mouse_wheel_step: f32,
+    pub wheel_curve: WheelCurve,
+    /// Increment multiplier at 10 notches per sec with the accelerated curve
+    pub wheel_acceleration: f32,
+    /// Rate per sec at which the mouse wheel global eases back to `wheel_rest`, 0 to disable
+    pub wheel_decay: f32,
+    /// Value the mouse wheel global eases back to
+    pub wheel_rest: f32,
+    /// Draw the shader triangle in wireframe and tell the shader to show debug info
+    pub debug_view: bool,
+    /// Pass the globals in a uniform buffer instead of push constants, to debug drivers
+    pub uniform_globals: bool,
+    /// Draw the shader once off-screen right after loading it, so the first frame doesn't stutter
+    pub warm_up: bool,
+    /// Longest `fTimeDelta` in sec, longer frames are clamped to it, 0 to disable
+    pub max_delta: f32,
+    /// Longest side in pixels of the render of shaders sampling previous frames, 0 to disable
+    pub max_feedback_resolution: u32,
+    /// Hours added to the utc time for the local time of day, std knows nothing of time zones
+    pub utc_offset: f32,
+    /// Period in sec at which the time wraps around, 0 to disable
+    pub loop_duration: f32,
+    /// Reset the frame count when the time wraps around
+    pub loop_frame: bool,
+    /// Gpu time in ms above which the shader gets paused, 0 to disable
+    pub watchdog_ms: f32,
+    /// Framerate used after some time without input to save power, 0 to disable
+    pub idle_framerate: u32,
+    /// Time in sec without input before switching to the idle framerate
+    pub idle_delay: f32,
+    /// Draw a checkerboard behind the canvas to show its alpha channel
+    pub checkerboard: bool,
+    /// Watch the shaders loaded from the file dialog
+    pub watch_on_load: bool,
+    /// Color behind the canvas, shows through its transparent pixels and fills the letterbox bars
+    pub background: [u8; 3],
+    /// Width/height ratio the canvas is fitted to with letterbox bars, 0 to fill the panel
+    pub canvas_aspect: f32,
+    /// Show the value of the pixel under the mouse
+    pub probe: bool,
+    /// List the params values on top of the canvas
+    pub params_overlay: bool,
+    /// Add noise to float canvases to hide banding
+    pub dither: bool,
+    /// Show NaN and infinite pixels in magenta instead of feeding them back to the shader
+    pub sanitize: bool,
+    /// Display the canvas with nearest filtering, for pixel art
+    pub nearest_filter: bool,
+    /// Keep the window above the others
+    pub always_on_top: bool,
+    /// Show the compared shader right of the divider
+    pub compare: bool,
+    /// Position of the divider as a fraction of the canvas width
+    pub compare_split: f32,
 }
 
 pub struct ExportData {
     pub size: Vector2<u32>,
     pub format: ImageFormat,
     pub path: PathBuf,
+    /// Export a 16 bit png from a float render, without tonemapping
+    pub high_bit_depth: bool,
+    /// Text burned into the exported image
+    pub watermark: Watermark,
+}
+
+/// Shader load counters for this session
+#[derive(Default)]
+pub struct LoadStats {
+    /// Successful loads and reloads
+    pub loads: u32,
+    pub failures: u32,
+    /// Duration of the last successful load
+    pub last: Duration,
+    pub total: Duration,
+    /// Steps of the last successful load in the shader loader
+    pub last_steps: LoadTimes,
+    /// Loading the textures of the last successful load
+    pub last_textures: Duration,
+    /// Creating the pipeline of the last successful load, with the warm up
+    pub last_pipeline: Duration,
+}
+
+impl LoadStats {
+    pub fn average(&self) -> Duration {
+        self.total / self.loads.max(1)
+    }
+
+    /// Time spent in each step of the last successful load
+    pub fn breakdown(&self) -> String {
+        let millis = |duration: Duration| duration.as_secs_f32() * 1000.0;
+        format!(
+            "read {:.1} ms, preprocess {:.1} ms, compile {:.1} ms, textures {:.1} ms, pipeline {:.1} ms",
+            millis(self.last_steps.read),
+            millis(self.last_steps.preprocess),
+            millis(self.last_steps.compile),
+            millis(self.last_textures),
+            millis(self.last_pipeline)
+        )
+    }
+}
+
+/// Consistency of the frame times, to tell stutter from a slow shader
+#[derive(Default)]
+pub struct FramePacing {
+    /// Last frame times in sec
+    frame_times: VecDeque<f32>,
+    /// Frames that took much longer than the frame budget since the last reset
+    pub dropped: u32,
+}
+
+impl FramePacing {
+    fn record(&mut self, frame_time: Duration, budget: Duration) {
+        if self.frame_times.len() == PACING_WINDOW {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(frame_time.as_secs_f32());
+        if frame_time.as_secs_f32() > budget.as_secs_f32() * DROPPED_FRAME_FACTOR {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.frame_times.clear();
+        self.dropped = 0;
+    }
+
+    /// Mean frame time in sec
+    pub fn mean(&self) -> f32 {
+        self.frame_times.iter().sum::<f32>() / self.frame_times.len().max(1) as f32
+    }
+
+    /// Standard deviation of the frame times in sec, 0 for a perfect pacing
+    pub fn jitter(&self) -> f32 {
+        let mean = self.mean();
+        let variance = self
+            .frame_times
+            .iter()
+            .map(|it| (it - mean) * (it - mean))
+            .sum::<f32>()
+            / self.frame_times.len().max(1) as f32;
+        variance.sqrt()
+    }
+
+    /// Longest frame time in sec
+    pub fn max(&self) -> f32 {
+        self.frame_times.iter().copied().fold(0.0, f32::max)
+    }
 }
 
 impl Default for ExportData {
@@ -45,6 +255,8 @@ impl Default for ExportData {
             size: Vector2::from([2048, 2048]),
             format: ImageFormat::Png,
             path: PathBuf::from_str("render.png").unwrap(),
+            high_bit_depth: false,
+            watermark: Watermark::default(),
         }
     }
 }
@@ -52,27 +264,45 @@ impl Default for ExportData {
 pub struct Nuance {
     /// The main window
     window: Window,
+    /// Fixed window title given on the command line
+    title: Option<String>,
     gui: Gui,
     /// App settings
     settings: Settings,
 
     /// The current loaded shader
     shader: Option<Shader>,
+    /// Variant of the shader compared side by side with it, reloaded along with it
+    compare_shader: Option<Shader>,
+    /// Why the last shader load failed
+    load_error: Option<anyhow::Error>,
     /// Shader compiler and transpiler
     shader_loader: ShaderLoader,
     watcher: RecommendedWatcher,
     /// Receiver for watcher events
     watcher_rx: Receiver<DebouncedEvent>,
     watching: bool,
+    /// Watched files and the shader reloaded when they change, includes map to the shader
+    /// including them
+    watched: HashMap<PathBuf, PathBuf>,
 
     renderer: Renderer,
     /// Parameters passed to shaders
     globals: Globals,
+    /// Uniforms computed on the cpu, appended to the globals
+    builtins: Builtins,
+    /// Keyboard state for the keyboard channels
+    keyboard: Keyboard,
+    modifiers: ModifiersState,
 
     // Time since start
     start_time: Instant,
     // Time since last draw
     last_draw: Instant,
+    /// Last mouse or keyboard input, for the idle framerate
+    last_input: Instant,
+    /// Duration of the last frames, used to compute the measured framerate
+    frame_times: VecDeque<Duration>,
 
     /// The instant at which the simulation started
     /// Reset on simulation restart and on unpause
@@ -81,16 +311,73 @@ pub struct Nuance {
     /// Reset on simulation restart
     sim_duration: Duration,
     paused: bool,
+    /// The params changed since the last upload to the gpu
+    params_dirty: bool,
+    #[cfg(feature = "osc")]
+    osc: Option<OscInput>,
+    load_stats: LoadStats,
+    frame_pacing: FramePacing,
+    /// Source of the main file of the last shader loaded without errors
+    last_good_source: Option<String>,
+    /// Mouse position at the last draw
+    last_mouse: Vector2<u32>,
+    /// The cursor is over the canvas rather than the ui panel
+    cursor_over_canvas: bool,
+    /// Time of the last mouse wheel event, for the accelerated wheel curve
+    last_wheel: Instant,
+    /// Last cursor position received since the last update, committed once per frame
+    pending_cursor: Option<PhysicalPosition<f64>>,
+    /// The left button is held after a click on the canvas
+    mouse_down: bool,
+    /// The left button was pressed on the canvas since the last update
+    mouse_clicked: bool,
+    /// Cursor during the last drag then its start, negated once released, like the Shadertoy mouse
+    mouse_drag: [f32; 4],
+    /// Wheel notches scrolled with ctrl over the ui, applied to the hovered param by the ui
+    pub param_scroll: f32,
+    /// Compiled param expressions of the time, with the index of their param
+    param_exprs: Vec<(usize, Box<dyn Fn(f64) -> f64>)>,
+    /// Gpu time of the frame that made the watchdog pause the shader
+    watchdog_tripped: Option<Duration>,
+    /// Last time the session was snapshotted for the panic hook
+    last_snapshot: Instant,
+    /// Commands left to run as the shader time passes
+    script: Option<Script>,
+    /// Fullscreen without ui, Esc quits
+    kiosk: bool,
 
     /// Export configuration
     export_data: ExportData,
 
     ask_load: bool,
+    ask_load_compare: bool,
     ask_export: bool,
+    ask_new_window: bool,
+    /// Reopen the window on the adapter with this index
+    ask_adapter: Option<usize>,
+    ask_save_session: bool,
+    ask_load_session: bool,
+    ask_save_inputs: bool,
+    ask_replay_inputs: bool,
+    /// Inputs recorded since the recording started
+    input_recording: Option<InputTrace>,
+    /// Inputs left to replay
+    input_replay: Option<InputTrace>,
+    /// Frames drawn since the input recording or replay started
+    input_frame: u32,
+    /// Close the app
+    ask_quit: bool,
 }
 
 impl Nuance {
-    pub async fn init(window: Window, pref_hp: bool) -> Result<Self> {
+    /// `adapter` picks an adapter by index instead of using `pref_hp`.
+    /// `title` replaces the default window title showing the loaded shader.
+    pub async fn init(
+        window: Window,
+        pref_hp: bool,
+        adapter: Option<usize>,
+        title: Option<String>,
+    ) -> Result<Self> {
         let window_size = window.inner_size();
         let scale_factor = window.scale_factor();
 
@@ -107,15 +394,36 @@ impl Nuance {
         let renderer = Renderer::new(
             &window,
             pref_hp,
+            adapter,
             canvas_size.into(),
-            Globals::std430_size_static() as u32,
+            PUSH_CONSTANTS_SIZE,
         )
         .await?;
 
         let (tx, rx) = std::sync::mpsc::channel();
 
-        Ok(Self {
+        let mut globals = Globals {
+            resolution: Vector2::from([0, 0]),
+            mouse: Vector2::from([0, 0]),
+            mouse_wheel: 0.0,
+            ratio: 1.0,
+            time: 0.0,
+            frame: 0,
+            target_fps: 60.0,
+            aspect: Vector2::from([1.0, 1.0]),
+            flip_y: 0,
+            wheel_steps: 0,
+            real_time: 0.0,
+            subframe: 0,
+            subframes: 1,
+            subframe_offset: 0.0,
+            time_delta: 0.0,
+        };
+        globals.set_resolution(canvas_size.into());
+
+        let mut app = Self {
             window,
+            title,
             gui: Gui::new(
                 Platform::new(PlatformDescriptor {
                     physical_width: window_size.width,
@@ -128,37 +436,120 @@ impl Nuance {
             ),
             settings: Settings {
                 target_framerate: Duration::from_secs_f32(1.0 / 60.0),
+                framerate_range: (4, 240),
+                frame_cap: FrameCap::Sleep,
                 mouse_wheel_step: 0.1,
+                wheel_curve: WheelCurve::Linear,
+                wheel_acceleration: 2.0,
+                wheel_decay: 0.0,
+                wheel_rest: 0.0,
+                debug_view: false,
+                uniform_globals: false,
+                warm_up: true,
+                max_delta: 0.1,
+                max_feedback_resolution: 0,
+                utc_offset: 0.0,
+                loop_duration: 0.0,
+                loop_frame: false,
+                watchdog_ms: 0.0,
+                idle_framerate: 0,
+                idle_delay: 10.0,
+                checkerboard: false,
+                watch_on_load: true,
+                background: [0, 0, 0],
+                canvas_aspect: 0.0,
+                probe: false,
+                params_overlay: false,
+                dither: false,
+                sanitize: false,
+                nearest_filter: false,
+                always_on_top: false,
+                compare: false,
+                compare_split: 0.5,
             },
             shader: None,
+            compare_shader: None,
+            load_error: None,
+            builtins: Builtins::default(),
             shader_loader: ShaderLoader::new(),
             watcher: watcher(tx, Duration::from_millis(200))?,
             watcher_rx: rx,
             renderer,
             watching: false,
-            globals: Globals {
-                resolution: Vector2::from([canvas_size.width, canvas_size.height]),
-                mouse: Vector2::from([0, 0]),
-                mouse_wheel: 0.0,
-                ratio: (canvas_size.width) as f32 / canvas_size.height as f32,
-                time: 0.0,
-                frame: 0,
-            },
+            watched: HashMap::new(),
+            globals,
+            keyboard: Keyboard::default(),
+            modifiers: ModifiersState::empty(),
             start_time: Instant::now(),
             last_draw: Instant::now(),
+            last_input: Instant::now(),
+            frame_times: VecDeque::with_capacity(FRAME_TIMES_WINDOW),
             sim_start: Instant::now(),
             sim_duration: Duration::from_nanos(0),
             paused: false,
+            params_dirty: false,
+            #[cfg(feature = "osc")]
+            osc: None,
+            load_stats: LoadStats::default(),
+            frame_pacing: FramePacing::default(),
+            last_good_source: None,
+            last_mouse: Vector2::from([0, 0]),
+            cursor_over_canvas: false,
+            last_wheel: Instant::now(),
+            pending_cursor: None,
+            mouse_down: false,
+            mouse_clicked: false,
+            mouse_drag: [0.0; 4],
+            param_scroll: 0.0,
+            param_exprs: Vec::new(),
+            watchdog_tripped: None,
+            last_snapshot: Instant::now(),
+            script: None,
+            kiosk: false,
             export_data: Default::default(),
             ask_load: false,
+            ask_load_compare: false,
             ask_export: false,
-        })
+            ask_new_window: false,
+            ask_adapter: None,
+            ask_save_session: false,
+            ask_load_session: false,
+            ask_save_inputs: false,
+            ask_replay_inputs: false,
+            input_recording: None,
+            input_replay: None,
+            input_frame: 0,
+            ask_quit: false,
+        };
+        app.set_builtin("fScaleFactor", BuiltinValue::Float(scale_factor as f32));
+        if !app.builtins.fits_push_constants() {
+            app.set_uniform_globals(true);
+        }
+        Ok(app)
     }
 
     /// Handle an event
-    pub fn handle_event(&mut self, event: Event<'_, ()>, control_flow: &mut ControlFlow) {
+    pub fn handle_event(&mut self, event: Event<'_, ()>) {
         // Let egui update with the window events
         self.gui.handle_event(&event);
+        if let (Some(trace), Event::WindowEvent { event, .. }) =
+            (self.input_recording.as_mut(), &event)
+        {
+            if let Some(input) = Input::from_event(event) {
+                trace.push(self.input_frame, input);
+            }
+        }
+        if let Event::WindowEvent { event, .. } = &event {
+            if matches!(
+                event,
+                WindowEvent::CursorMoved { .. }
+                    | WindowEvent::MouseInput { .. }
+                    | WindowEvent::MouseWheel { .. }
+                    | WindowEvent::KeyboardInput { .. }
+            ) {
+                self.last_input = Instant::now();
+            }
+        }
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CursorMoved {
@@ -166,40 +557,73 @@ impl Nuance {
                     position,
                     ..
                 } => {
-                    let scale_factor = self.window.scale_factor();
-                    let ui_width = self.gui.ui_width as f64 * scale_factor;
-                    if position.x > ui_width {
-                        self.globals.mouse =
-                            Vector2::from([(position.x - ui_width) as u32, position.y as u32]);
-                    }
+                    self.cursor_over_canvas = self.canvas_position(position).is_some();
+                    // High polling rate mice send many events per frame, only the last one counts
+                    self.pending_cursor = Some(position);
+                }
+                WindowEvent::ModifiersChanged(modifiers) => {
+                    self.modifiers = modifiers;
                 }
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                } => match state {
+                    ElementState::Pressed if self.cursor_over_canvas => {
+                        self.mouse_down = true;
+                        self.mouse_clicked = true;
+                    }
+                    ElementState::Pressed => {}
+                    ElementState::Released => self.mouse_down = false,
+                },
                 WindowEvent::MouseWheel {
                     device_id: _device_id,
                     delta,
                     ..
                 } => match delta {
+                    // Scrolling the panel is left to the ui
+                    MouseScrollDelta::LineDelta(_, value) if !self.cursor_over_canvas => {
+                        if self.modifiers.ctrl() {
+                            self.param_scroll += value;
+                        }
+                    }
                     MouseScrollDelta::LineDelta(_, value) => {
-                        self.globals.mouse_wheel += value * self.settings.mouse_wheel_step;
+                        self.globals.mouse_wheel +=
+                            value * self.settings.mouse_wheel_step * self.wheel_multiplier(value);
+                        // At least one step per event, whatever the line count reported
+                        if value != 0.0 {
+                            self.globals.wheel_steps +=
+                                (value.signum() * value.abs().round().max(1.0)) as i32;
+                        }
                     }
                     _ => {
                         log::warn!("Unsupported MouseScrollDelta::PixelDelta");
                     }
                 },
-                WindowEvent::KeyboardInput { input, .. } => match input.virtual_keycode {
-                    Some(VirtualKeyCode::F1) => {
-                        self.gui.profiling_window = true;
+                WindowEvent::KeyboardInput { input, .. } => {
+                    // Typing in the ui shouldn't reach the shader
+                    if !self.gui.egui_platform.context().wants_keyboard_input() {
+                        self.keyboard.handle_input(&input);
                     }
-                    _ => {}
-                },
+                    match input.virtual_keycode {
+                        Some(VirtualKeyCode::Escape) if self.kiosk => {
+                            self.ask_quit = true;
+                        }
+                        // No other shortcut in kiosk mode
+                        _ if self.kiosk => {}
+                        Some(VirtualKeyCode::F1) => {
+                            self.gui.profiling_window = true;
+                        }
+                        _ => {}
+                    }
+                }
                 WindowEvent::Resized(size) => {
-                    let mut size = size.into();
+                    let size = size.into();
                     self.renderer.resize(size);
-                    size.x -= self.gui.ui_width;
-                    self.renderer.resize_inner_canvas(size);
-                    self.globals.resolution = size;
+                    self.resize_canvas(size);
                 }
-                WindowEvent::CloseRequested => {
-                    *control_flow = ControlFlow::Exit;
+                WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                    self.set_builtin("fScaleFactor", BuiltinValue::Float(scale_factor as f32));
                 }
                 _ => {}
             },
@@ -208,28 +632,85 @@ impl Nuance {
     }
 
     /// Final update
-    /// Called before draw and after handling all events
+    /// Called before draw and after handling all events.
+    /// The control flow is shared with the other windows, it is only set to wait less.
     pub fn update(&mut self, control_flow: &mut ControlFlow) {
         // Poll the file watcher
-        if let Ok(DebouncedEvent::Write(_)) = self.watcher_rx.try_recv() {
-            self.reload_shader();
+        if let Ok(DebouncedEvent::Write(path)) = self.watcher_rx.try_recv() {
+            self.reload_watched(&path);
         }
 
         // Do not poll events, wait until next frame based on target fps
+        let frame_interval = self.frame_interval();
         let since_last_draw = self.last_draw.elapsed();
-        if since_last_draw >= self.settings.target_framerate {
+        let next_frame = if since_last_draw >= frame_interval {
             self.window.request_redraw();
+            Instant::now() + frame_interval
         } else {
-            // Sleep til next frame
-            *control_flow = ControlFlow::WaitUntil(
-                Instant::now() + self.settings.target_framerate - since_last_draw,
-            );
+            Instant::now() + frame_interval - since_last_draw
+        };
+        // Sleep til next frame
+        match *control_flow {
+            ControlFlow::WaitUntil(other) if other <= next_frame => {}
+            ControlFlow::Exit => {}
+            _ => *control_flow = ControlFlow::WaitUntil(next_frame),
+        }
+
+        self.replay_due_inputs();
+        self.commit_cursor();
+        self.update_mouse_drag();
+        if self.last_snapshot.elapsed() >= RECOVERY_INTERVAL {
+            recovery::update(self.session().ok());
+            self.last_snapshot = Instant::now();
         }
+        let real_time = self.start_time.elapsed().as_secs_f32();
+        let delta = real_time - mem::replace(&mut self.globals.real_time, real_time);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |it| it.as_secs_f64());
+        let day_fraction =
+            ((now / 3600.0 + self.settings.utc_offset as f64) / 24.0).rem_euclid(1.0);
+        self.set_builtin(
+            "fDayColor",
+            BuiltinValue::Vec3(day_color(day_fraction as f32)),
+        );
 
         // Update shader time
         if !self.is_paused() {
-            self.globals.time = (self.sim_start.elapsed() + self.sim_duration).as_secs_f32();
+            // A hitch doesn't make the simulation jump, the shader clock isn't affected.
+            // Clamps the total, several updates can run before the next draw resets it.
+            let max_delta = self.settings.max_delta;
+            self.globals.time_delta += delta;
+            if max_delta > 0.0 {
+                self.globals.time_delta = self.globals.time_delta.min(max_delta);
+            }
+            if self.settings.wheel_decay > 0.0 {
+                // Exponential so the easing doesn't depend on the framerate
+                let rest = self.settings.wheel_rest;
+                self.globals.mouse_wheel = rest
+                    + (self.globals.mouse_wheel - rest)
+                        * (-self.settings.wheel_decay * delta).exp();
+            }
+            let elapsed = (self.sim_start.elapsed() + self.sim_duration).as_secs_f32();
+            if self.settings.loop_duration > 0.0 {
+                let time = elapsed % self.settings.loop_duration;
+                if time < self.globals.time && self.settings.loop_frame {
+                    self.globals.frame = 0;
+                }
+                self.globals.time = time;
+            } else {
+                self.globals.time = elapsed;
+            }
+            self.evaluate_param_exprs();
         }
+        self.run_script(control_flow);
+
+        if self.ask_quit {
+            *control_flow = ControlFlow::Exit;
+        }
+
+        #[cfg(feature = "osc")]
+        self.apply_osc();
 
         if self.ask_load {
             if let Some(path) = FileDialog::new()
@@ -237,12 +718,29 @@ impl Nuance {
                 .add_filter("Shaders", ShaderLoader::supported_extensions())
                 .pick_file()
             {
-                self.unwatch();
+                self.set_watching(false);
                 self.load_shader(&path);
+                if self.settings.watch_on_load && self.load_error.is_none() {
+                    self.set_watching(true);
+                }
             }
             self.ask_load = false;
         }
 
+        if self.ask_load_compare {
+            if let Some(path) = FileDialog::new()
+                .set_parent(&self.window)
+                .add_filter("Shaders", ShaderLoader::supported_extensions())
+                .pick_file()
+            {
+                match self.load_compare_shader(&path) {
+                    Ok(()) => self.settings.compare = true,
+                    Err(e) => error!("Can't compare with {} : {:#}", path.display(), e),
+                }
+            }
+            self.ask_load_compare = false;
+        }
+
         if self.ask_export {
             if let Some(path) = FileDialog::new()
                 .set_parent(&self.window)
@@ -255,15 +753,182 @@ impl Nuance {
             self.ask_export = false;
         }
 
+        if self.ask_save_session {
+            if let Some(path) = FileDialog::new()
+                .set_parent(&self.window)
+                .add_filter("Session", &[SESSION_EXTENSION])
+                .save_file()
+            {
+                if let Err(e) = self.save_session(&path) {
+                    error!("{:#}", e);
+                }
+            }
+            self.ask_save_session = false;
+        }
+
+        if self.ask_save_inputs {
+            if let Some(trace) = self.input_recording.take() {
+                if let Some(path) = FileDialog::new()
+                    .set_parent(&self.window)
+                    .add_filter("Input trace", &[INPUT_TRACE_EXTENSION])
+                    .save_file()
+                {
+                    match trace.save(&path) {
+                        Ok(()) => info!("Saved input trace to {}", path.display()),
+                        Err(e) => error!("{:#}", e),
+                    }
+                }
+            }
+            self.ask_save_inputs = false;
+        }
+
+        if self.ask_replay_inputs {
+            if let Some(path) = FileDialog::new()
+                .set_parent(&self.window)
+                .add_filter("Input trace", &[INPUT_TRACE_EXTENSION])
+                .pick_file()
+            {
+                if let Err(e) = self.replay_inputs(&path) {
+                    error!("{:#}", e);
+                }
+            }
+            self.ask_replay_inputs = false;
+        }
+
+        if self.ask_load_session {
+            if let Some(path) = FileDialog::new()
+                .set_parent(&self.window)
+                .add_filter("Session", &[SESSION_EXTENSION])
+                .pick_file()
+            {
+                if let Err(e) = self.load_session(&path) {
+                    error!("{:#}", e);
+                }
+            }
+            self.ask_load_session = false;
+        }
+
         // Resize canvas if the UI got resized
-        let mut size: Vector2<u32> = self.window.inner_size().into();
-        size.x -= self.gui.ui_width;
+        self.resize_canvas(self.window.inner_size().into());
+    }
+
+    /// Resize the canvas to the window minus the ui, when its render size changed
+    fn resize_canvas(&mut self, window_size: Vector2<u32>) {
+        let (_, size) = self.canvas_viewport(window_size);
+        let size = self.render_size(size);
         if size != self.globals.resolution {
             self.renderer.resize_inner_canvas(size);
-            self.globals.resolution = size;
+            self.globals.set_resolution(size);
         }
     }
 
+    /// Position and size in physical pixels of the canvas in the window, centered in the space
+    /// left by the ui and fitted to the canvas aspect setting
+    fn canvas_viewport(&self, window_size: Vector2<u32>) -> (Vector2<u32>, Vector2<u32>) {
+        let ui_width = self.ui_physical_width();
+        let panel = Vector2::from([window_size.x.saturating_sub(ui_width), window_size.y]);
+        let (width, height) =
+            fit_aspect(panel.x as f32, panel.y as f32, self.settings.canvas_aspect);
+        let size = Vector2::from([
+            (width.round() as u32).min(panel.x),
+            (height.round() as u32).min(panel.y),
+        ]);
+        let offset = Vector2::from([ui_width + (panel.x - size.x) / 2, (panel.y - size.y) / 2]);
+        (offset, size)
+    }
+
+    /// Cursor position in physical pixels from the top left of the canvas, none over the ui and
+    /// the letterbox bars
+    fn canvas_position(&self, position: PhysicalPosition<f64>) -> Option<(f64, f64)> {
+        let (offset, size) = self.canvas_viewport(self.window.inner_size().into());
+        let x = position.x - offset.x as f64;
+        let y = position.y - offset.y as f64;
+        Some((x, y)).filter(|_| x >= 0.0 && y >= 0.0 && x < size.x as f64 && y < size.y as f64)
+    }
+
+    /// Size the shader renders at for this canvas size. Shaders sampling previous frames are
+    /// capped to the max feedback resolution setting, the ui upscales them to fill the canvas.
+    fn render_size(&self, canvas: Vector2<u32>) -> Vector2<u32> {
+        let max = self.settings.max_feedback_resolution;
+        let uses_feedback = self.shader.as_ref().map_or(false, |it| it.uses_feedback);
+        let longest = canvas.x.max(canvas.y);
+        if max == 0 || !uses_feedback || longest <= max {
+            return canvas;
+        }
+        let scale = max as f64 / longest as f64;
+        Vector2::from([
+            ((canvas.x as f64 * scale).round() as u32).max(1),
+            ((canvas.y as f64 * scale).round() as u32).max(1),
+        ])
+    }
+
+    /// Time between frames, longer when idle
+    fn frame_interval(&self) -> Duration {
+        let idle = self.settings.idle_framerate > 0
+            && self.last_input.elapsed().as_secs_f32() > self.settings.idle_delay;
+        if idle {
+            Duration::from_secs_f32(1.0 / self.settings.idle_framerate as f32)
+                .max(self.settings.target_framerate)
+        } else if self.settings.frame_cap == FrameCap::Vsync {
+            // Presenting waits for the display
+            Duration::ZERO
+        } else {
+            self.settings.target_framerate
+        }
+    }
+
+    /// Set the target framerate, clamped to the framerate range setting
+    fn set_target_framerate(&mut self, framerate: u32) {
+        let (min, max) = self.settings.framerate_range;
+        let framerate = framerate.clamp(min.max(1), max.max(min).max(1));
+        self.settings.target_framerate = Duration::from_secs_f32(1.0 / framerate as f32);
+        self.globals.target_fps = framerate as f32;
+    }
+
+    /// Scale factor of the ui, the os one multiplied by the ui scale setting
+    fn ui_scale_factor(&self) -> f64 {
+        self.window.scale_factor() * self.gui.ui_scale as f64
+    }
+
+    /// Mouse position in the canvas texture, from the top left, when probing is enabled
+    fn probe_position(&self) -> Option<Vector2<u32>> {
+        if !self.settings.probe {
+            return None;
+        }
+        let mouse = self.globals.mouse;
+        // The mouse global follows fragCoord
+        let y = if self.globals.flip_y != 0 {
+            self.globals.resolution.y.saturating_sub(mouse.y + 1)
+        } else {
+            mouse.y
+        };
+        Some(Vector2::from([mouse.x, y]))
+    }
+
+    /// Width of the side panel in physical pixels
+    fn ui_physical_width(&self) -> u32 {
+        (self.gui.ui_width as f64 * self.ui_scale_factor()).round() as u32
+    }
+
+    /// The globals of each sub-frame, spread back in time over the duration of a frame
+    fn subframe_globals(&self) -> Vec<Vec<u8>> {
+        let subframes = self
+            .shader_metadata()
+            .map_or(1, ShaderMetadata::subframe_count);
+        let frame_duration = 1.0 / self.globals.target_fps.max(1.0);
+        (0..subframes)
+            .map(|subframe| {
+                let mut globals = self.globals.clone();
+                globals.subframe = subframe;
+                globals.subframes = subframes;
+                globals.subframe_offset =
+                    -((subframes - 1 - subframe) as f32) / subframes as f32 * frame_duration;
+                globals.time += globals.subframe_offset;
+                self.builtins.write(&globals)
+            })
+            .collect()
+    }
+
     /// Draw the app
     pub fn draw(&mut self) {
         // Tell the profiler we're running a new frame
@@ -278,32 +943,114 @@ impl Nuance {
         let screen_desc = ScreenDescriptor {
             physical_width: window_size.width,
             physical_height: window_size.height,
-            scale_factor: self.window.scale_factor() as f32,
+            scale_factor: self.ui_scale_factor() as f32,
         };
 
         // Generate the GUI
         let paint_jobs = Gui::render(self, &screen_desc);
 
+        self.renderer.update_keyboard(self.keyboard.data());
+        // Dithering an 8 bit canvas switches it to a float texture with new shader passes
+        if self.renderer.set_dither(self.settings.dither) {
+            self.params_dirty = true;
+        }
+
+        // Accumulating shaders start over when their inputs change
+        let inputs_changed = self.params_dirty || self.globals.mouse != self.last_mouse;
+        if inputs_changed
+            && self
+                .shader_metadata()
+                .map_or(false, |it| it.reset_on_change)
+        {
+            self.globals.frame = 0;
+        }
+        self.last_mouse = self.globals.mouse;
+
+        // Only upload the params when they changed
+        let params_buffer = if mem::take(&mut self.params_dirty) {
+            self.shader_metadata().map(|it| it.params_buffer())
+        } else {
+            None
+        };
+
+        self.renderer
+            .set_gpu_timing(self.settings.watchdog_ms > 0.0);
+        self.renderer.set_probe(self.probe_position());
+        self.renderer
+            .set_vsync(self.settings.frame_cap == FrameCap::Vsync);
+        self.renderer
+            .set_sanitize(self.settings.sanitize && self.renderer.can_sanitize());
+        self.renderer
+            .set_canvas_filter(if self.settings.nearest_filter {
+                FilterMode::Nearest
+            } else {
+                FilterMode::Linear
+            });
+        self.renderer.set_compare_split(
+            Some(self.settings.compare_split)
+                .filter(|_| self.settings.compare && self.compare_shader.is_some()),
+        );
+
+        let push_constants = self.subframe_globals();
+
         // Render the UI
         self.renderer
             .render(
                 &screen_desc,
                 (&self.gui.texture(), &paint_jobs),
-                &self
-                    .shader_metadata()
-                    .map(|it| it.params_buffer())
-                    .unwrap_or_default(),
-                self.globals.as_std430().as_bytes(),
-                !self.is_paused(),
+                params_buffer.as_deref(),
+                &push_constants,
+                !self.is_paused() || self.draws_while_paused(),
             )
             .unwrap();
 
+        self.check_watchdog();
+
         if !self.is_paused() {
+            self.keyboard.end_frame();
             self.globals.frame += 1;
+            self.globals.time_delta = 0.0;
+            self.input_frame += 1;
+            self.record_frame_time(self.last_draw.elapsed());
             self.last_draw = Instant::now();
         }
     }
 
+    /// Run the shader fullscreen without ui nor cursor, for installations and screensavers.
+    /// Esc quits.
+    pub fn enter_kiosk(&mut self) {
+        self.kiosk = true;
+        self.window
+            .set_fullscreen(Some(Fullscreen::Borderless(None)));
+        self.window.set_cursor_visible(false);
+    }
+
+    /// Keep the window above the others, to watch the shader while editing it elsewhere
+    pub fn set_always_on_top(&mut self, always_on_top: bool) {
+        self.settings.always_on_top = always_on_top;
+        self.window.set_always_on_top(always_on_top);
+    }
+
+    pub fn window_id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    /// true if the user asked for a new window since the last call
+    pub fn take_new_window_request(&mut self) -> bool {
+        mem::take(&mut self.ask_new_window)
+    }
+
+    /// Index of the adapter this window should be reopened on
+    pub fn take_adapter_request(&mut self) -> Option<usize> {
+        self.ask_adapter.take()
+    }
+
+    /// The window is reopened by the event loop owner, a new surface can't be created for it
+    /// while the current one is alive
+    fn ask_to_switch_adapter(&mut self, index: usize) {
+        self.ask_adapter = Some(index);
+    }
+
     /// This shows a file dialog to load a shader
     /// This only happens next frame
     fn ask_to_load(&mut self) {
@@ -315,7 +1062,10 @@ impl Nuance {
         info!("Loading {}", path.as_ref().to_str().unwrap());
         let reload_start = Instant::now();
 
-        match self.shader_loader.load_shader(&path) {
+        let loaded = self.shader_loader.load_shader(&path);
+        // Loading the compared shader resets the times
+        let load_steps = self.shader_loader.last_times();
+        match loaded {
             Ok((shader, source)) => {
                 let buffer_size = if let Some(metadata) = shader.metadata.as_ref() {
                     metadata.params_buffer_size()
@@ -323,106 +1073,900 @@ impl Nuance {
                     0
                 };
 
-                self.renderer
-                    .set_shader(source, Globals::std430_size_static() as u32, buffer_size);
+                let required_features = shader
+                    .metadata
+                    .as_ref()
+                    .map(|it| it.required_features.as_slice())
+                    .unwrap_or_default();
+                let textures = shader
+                    .metadata
+                    .as_ref()
+                    .map(|it| it.textures.as_slice())
+                    .unwrap_or_default();
+                let textures_start = Instant::now();
+                if let Err(e) = self
+                    .renderer
+                    .check_features(required_features)
+                    .and_then(|_| self.renderer.set_textures(textures))
+                {
+                    error!("{:#}", e);
+                    error!("Can't load {}", path.as_ref().to_str().unwrap());
+                    self.load_error = Some(e);
+                    self.load_stats.failures += 1;
+                    return;
+                }
+                let textures_time = textures_start.elapsed();
+                self.renderer.set_storage(
+                    shader
+                        .metadata
+                        .as_ref()
+                        .map_or(0, ShaderMetadata::storage_size),
+                );
 
+                // The compared shader might not match the new one, it is checked again once loaded
+                self.renderer.set_compare_shader(None);
+                let pipeline_start = Instant::now();
+                self.renderer.set_shader(
+                    source,
+                    self.builtins.size(),
+                    shader.globals_stages,
+                    buffer_size,
+                    shader.uses_feedback,
+                    shader
+                        .metadata
+                        .as_ref()
+                        .map_or(1, ShaderMetadata::history_frames),
+                    shader
+                        .metadata
+                        .as_ref()
+                        .map_or(1, ShaderMetadata::target_count),
+                    shader
+                        .metadata
+                        .as_ref()
+                        .map_or_else(Vec::new, |it| it.kept_targets.clone()),
+                );
+                if self.settings.warm_up {
+                    let warm_up_time = self.renderer.warm_up();
+                    info!("Warmed up the pipeline in {} ms", warm_up_time.as_millis());
+                }
+                let pipeline_time = pipeline_start.elapsed();
+
+                // Follow the shader orientation when it changes, keep the ui choice across reloads
+                let flip_y = shader.metadata.as_ref().map_or(false, |it| it.flip_y);
+                let previous_flip_y = self
+                    .shader
+                    .as_ref()
+                    .filter(|it| it.main == shader.main)
+                    .map(|it| it.metadata.as_ref().map_or(false, |it| it.flip_y));
+                if previous_flip_y != Some(flip_y) {
+                    self.globals.flip_y = flip_y as u32;
+                }
+                let param_count = shader
+                    .metadata
+                    .as_ref()
+                    .map_or(0, |it| it.sliders.len() as u32);
+                self.set_builtin("uParamCount", BuiltinValue::Uint(param_count));
+
+                // Show the changes made outside of the editor
+                if let Some(editor) = self
+                    .gui
+                    .editor
+                    .as_mut()
+                    .filter(|it| it.path() == shader.main)
+                {
+                    if let Err(e) = editor.refresh() {
+                        warn!("{:#}", e);
+                    }
+                }
+
+                self.last_good_source = if ShaderLoader::is_url(&shader.main) {
+                    None
+                } else {
+                    fs::read_to_string(&shader.main).ok()
+                };
                 self.shader = Some(shader);
+                self.load_error = None;
+                self.reload_compare_shader();
+                // The shader may include other files now
+                if self.watching {
+                    self.watch();
+                }
+                self.update_title();
+                self.compile_param_exprs();
+                // The params buffer is new, always upload it
+                self.params_dirty = true;
                 // Reset the running globals
                 self.globals.reset();
+                self.frame_times.clear();
                 self.sim_start = Instant::now();
                 self.sim_duration = Duration::from_nanos(0);
 
+                let load_time = reload_start.elapsed();
+                self.load_stats.loads += 1;
+                self.load_stats.last = load_time;
+                self.load_stats.total += load_time;
+                self.load_stats.last_steps = load_steps;
+                self.load_stats.last_textures = textures_time;
+                self.load_stats.last_pipeline = pipeline_time;
                 info!(
-                    "Loaded and ready ! (took {} ms)",
-                    reload_start.elapsed().as_millis()
+                    "Loaded and ready ! (took {} ms : {})",
+                    load_time.as_millis(),
+                    self.load_stats.breakdown()
                 );
             }
             Err(e) => {
                 error!("{}", e);
                 error!("Can't load {}", path.as_ref().to_str().unwrap());
+                self.load_error = Some(e.into());
+                self.load_stats.failures += 1;
+            }
+        }
+    }
+
+    /// This shows a file dialog to load a shader to compare with the current one
+    /// This only happens next frame
+    fn ask_to_load_compare(&mut self) {
+        self.ask_load_compare = true;
+    }
+
+    /// Load a variant of the current shader to draw side by side with it
+    fn load_compare_shader(&mut self, path: &Path) -> Result<()> {
+        let main = self.shader.as_ref().context("No shader to compare with")?;
+        let (shader, source) = self.shader_loader.load_shader(path)?;
+        check_comparable(main, &shader)?;
+
+        let buffer_size = shader
+            .metadata
+            .as_ref()
+            .map_or(0, ShaderMetadata::params_buffer_size);
+        self.renderer
+            .set_compare_shader(Some((source, buffer_size, shader.globals_stages)));
+        self.compare_shader = Some(shader);
+        // The params buffer is new, always upload it
+        self.params_dirty = true;
+        if self.watching {
+            self.watch();
+        }
+        info!("Comparing with {}", path.display());
+        Ok(())
+    }
+
+    /// The main shader changed, the compared one must still match it
+    fn reload_compare_shader(&mut self) {
+        let path = match self.compare_shader.as_ref() {
+            Some(shader) => shader.main.clone(),
+            None => return,
+        };
+        if let Err(e) = self.load_compare_shader(&path) {
+            error!("Can't compare with {} : {:#}", path.display(), e);
+            self.close_compare_shader();
+        }
+    }
+
+    fn close_compare_shader(&mut self) {
+        self.compare_shader = None;
+        self.settings.compare = false;
+        self.renderer.set_compare_shader(None);
+        if self.watching {
+            self.watch();
+        }
+    }
+
+    /// Show the name of the loaded shader in the window title, unless a title was given
+    fn update_title(&self) {
+        if self.title.is_some() {
+            return;
+        }
+        let name = self
+            .shader
+            .as_ref()
+            .and_then(|it| it.main.file_name())
+            .map(|it| it.to_string_lossy());
+        match name {
+            Some(name) => self.window.set_title(&format!("Nuance — {}", name)),
+            None => self.window.set_title("Nuance"),
+        }
+    }
+
+    /// Describe the last load error, including the offending source lines for compile errors
+    fn load_error_report(&self) -> Option<String> {
+        self.load_error
+            .as_ref()
+            .map(|e| match e.downcast_ref::<ShaderLoadError>() {
+                Some(ShaderLoadError::Compile(compile_error)) => compile_error.report(),
+                _ => format!("{:?}", e),
+            })
+    }
+
+    /// Immediate load from the url or the share string typed in the UI
+    fn load_url(&mut self) {
+        let url = self.gui.url.trim().to_string();
+        if url.starts_with(SHARE_PREFIX) {
+            if let Err(e) = self.load_share_string(&url) {
+                error!("{:#}", e);
+            }
+            return;
+        }
+        if !ShaderLoader::is_url(&url) {
+            error!("'{}' is not an http(s) url", url);
+            return;
+        }
+        // We can't watch a remote file
+        self.unwatch();
+        self.watching = false;
+        self.load_shader(url);
+    }
+
+    /// Load a shader and override some of its params
+    pub fn open_shader(&mut self, path: &Path, params: &[ParamOverride]) -> Result<()> {
+        self.load_shader(path);
+        if let Some(report) = self.load_error_report() {
+            bail!("Can't load {} : {}", path.display(), report);
+        }
+        self.set_params(params)
+    }
+
+    /// Set params by name, all of them must exist
+    fn set_params(&mut self, params: &[ParamOverride]) -> Result<()> {
+        let sliders = self
+            .shader_metadata_mut()
+            .map(|it| it.sliders.as_mut_slice())
+            .unwrap_or_default();
+        for param in params {
+            let slider = sliders
+                .iter_mut()
+                .find(|slider| slider_values(slider).0 == param.name)
+                .with_context(|| format!("Unknown param {}", param.name))?;
+            let expected = slider_values(slider).1.len();
+            if param.values.len() != expected {
+                bail!(
+                    "Param {} expects {} values, got {}",
+                    param.name,
+                    expected,
+                    param.values.len()
+                );
+            }
+            set_slider_values(slider, &param.values);
+        }
+        self.params_dirty = true;
+        Ok(())
+    }
+
+    /// Search this directory for includes not found next to the including file
+    pub fn add_include_dir(&mut self, dir: &Path) {
+        self.shader_loader.add_include_dir(dir);
+    }
+
+    /// Run the commands of a script file as the shader time passes
+    pub fn load_script(&mut self, path: &Path) -> Result<()> {
+        self.script = Some(Script::load(path)?);
+        info!("Running script {}", path.display());
+        Ok(())
+    }
+
+    /// Run the script commands whose time has come
+    fn run_script(&mut self, control_flow: &mut ControlFlow) {
+        while let Some(command) = self
+            .script
+            .as_mut()
+            .and_then(|it| it.next_due(self.globals.time))
+        {
+            match command {
+                ScriptCommand::Load(path) => {
+                    self.unwatch();
+                    self.load_shader(&path);
+                    // The time started over, the next commands wait for the new shader
+                    break;
+                }
+                ScriptCommand::Set(param) => {
+                    if let Err(e) = self.set_params(std::slice::from_ref(&param)) {
+                        error!("{:#}", e);
+                    }
+                }
+                ScriptCommand::Screenshot(path) => {
+                    if !self.shader_loaded() {
+                        error!("No shader to take a screenshot of");
+                        continue;
+                    }
+                    match ImageFormat::from_path(&path) {
+                        Ok(format) => {
+                            self.export_data.format = format;
+                            self.export_data.path = path;
+                            self.export_image();
+                        }
+                        Err(e) => error!("Can't take a screenshot to {} : {}", path.display(), e),
+                    }
+                }
+                ScriptCommand::Quit => *control_flow = ControlFlow::Exit,
+            }
+        }
+        if self.script.as_ref().map_or(false, Script::is_done) {
+            info!("Script done");
+            self.script = None;
+        }
+    }
+
+    /// Drive params with the OSC messages received on this port
+    #[cfg(feature = "osc")]
+    pub fn listen_osc(&mut self, config: &crate::cli::OscConfig) -> Result<()> {
+        self.osc = Some(OscInput::listen(config)?);
+        Ok(())
+    }
+
+    /// Apply the OSC messages received since the last frame
+    #[cfg(feature = "osc")]
+    fn apply_osc(&mut self) {
+        let messages = match self.osc.as_ref() {
+            Some(osc) => osc.poll(),
+            None => return,
+        };
+        if messages.is_empty() {
+            return;
+        }
+        if let Some(metadata) = self.shader_metadata_mut() {
+            for (name, values) in messages {
+                match metadata
+                    .sliders
+                    .iter_mut()
+                    .find(|slider| slider_values(slider).0 == name)
+                {
+                    Some(slider) => osc::apply(slider, &values),
+                    None => debug!("No param named {} for an OSC message", name),
+                }
             }
         }
+        self.params_dirty = true;
     }
 
+    /// Save the shader, its params and the time to a session file
+    fn save_session(&self, path: &Path) -> Result<()> {
+        self.session()?.save(path)?;
+        info!("Saved session to {}", path.display());
+        Ok(())
+    }
+
+    /// The shader, its params and the time
+    pub fn session(&self) -> Result<Session> {
+        let shader = self.shader.as_ref().context("No shader loaded")?;
+        let params = self
+            .shader_metadata()
+            .map(|metadata| {
+                metadata
+                    .sliders
+                    .iter()
+                    .map(|slider| {
+                        let (name, values) = slider_values(slider);
+                        (name.to_string(), values)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Session {
+            version: SESSION_VERSION,
+            shader: shader.main.clone(),
+            params,
+            param_order: self.gui.param_order.clone(),
+            time: self.globals.time,
+            frame: self.globals.frame,
+            mouse_wheel: self.globals.mouse_wheel,
+            wheel_steps: self.globals.wheel_steps,
+            paused: self.is_paused(),
+            flip_y: Some(self.globals.flip_y != 0),
+            framerate: Some(self.globals.target_fps.round() as u32),
+        })
+    }
+
+    /// The session with the source of the main shader file, as a single line of text
+    pub fn share_string(&self) -> Result<String> {
+        let session = self.session()?;
+        let source =
+            if ShaderLoader::is_url(&session.shader) {
+                None
+            } else {
+                Some(fs::read_to_string(&session.shader).with_context(|| {
+                    format!("Can't read {} to share it", session.shader.display())
+                })?)
+            };
+        Shared { session, source }.encode()
+    }
+
+    /// Load the shader of a share string and restore its state. The source is written to the
+    /// temp directory, the shader is loaded from there.
+    pub fn load_share_string(&mut self, text: &str) -> Result<()> {
+        let Shared {
+            mut session,
+            source,
+        } = Shared::decode(text)?;
+        if let Some(source) = source {
+            let name = session
+                .shader
+                .file_name()
+                .context("The shared shader has no file name")?;
+            let dir = std::env::temp_dir().join("nuance-shared");
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(name);
+            fs::write(&path, source)
+                .with_context(|| format!("Can't write the shared shader to {}", path.display()))?;
+            session.shader = path;
+        }
+        self.restore_session(session)?;
+        info!(
+            "Loaded shared shader {}",
+            self.shader.as_ref().unwrap().main.display()
+        );
+        Ok(())
+    }
+
+    /// Offer to restore the session saved by a previous crash, the recovery file is removed
+    /// whatever the answer
+    pub fn offer_recovery(&mut self) -> Result<()> {
+        let path = recovery::recovery_file();
+        if !path.exists() {
+            return Ok(());
+        }
+        let session = Session::load(&path);
+        fs::remove_file(&path)?;
+        let session = session?;
+
+        let restore = MessageDialog::new()
+            .set_parent(&self.window)
+            .set_title("Restore session")
+            .set_description(&format!(
+                "Nuance crashed while running {}, restore this session ?",
+                session.shader.display()
+            ))
+            .set_buttons(MessageButtons::YesNo)
+            .set_level(MessageLevel::Info)
+            .show();
+        if restore {
+            self.restore_session(session)?;
+            info!("Restored session from {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Load the shader of a session file and restore its state
+    fn load_session(&mut self, path: &Path) -> Result<()> {
+        self.restore_session(Session::load(path)?)?;
+        info!("Loaded session from {}", path.display());
+        Ok(())
+    }
+
+    /// Load the shader of a session and restore its state
+    pub fn restore_session(&mut self, session: Session) -> Result<()> {
+        self.unwatch();
+        self.watching = false;
+        self.load_shader(&session.shader);
+        if self.load_error.is_some() {
+            bail!("Can't load the session shader");
+        }
+
+        if let Some(metadata) = self.shader_metadata_mut() {
+            for slider in metadata.sliders.iter_mut() {
+                let (name, _) = slider_values(slider);
+                if let Some(values) = session.params.get(name) {
+                    set_slider_values(slider, values);
+                }
+            }
+        }
+        self.gui.param_order = session.param_order;
+        self.params_dirty = true;
+
+        self.globals.time = session.time;
+        self.globals.frame = session.frame;
+        self.globals.mouse_wheel = session.mouse_wheel;
+        self.globals.wheel_steps = session.wheel_steps;
+        self.sim_start = Instant::now();
+        self.sim_duration = Duration::from_secs_f32(session.time.max(0.0));
+        self.paused = session.paused;
+        if let Some(flip_y) = session.flip_y {
+            self.globals.flip_y = flip_y as u32;
+        }
+        if let Some(framerate) = session.framerate.filter(|it| *it > 0) {
+            self.set_target_framerate(framerate);
+        }
+        Ok(())
+    }
+
+    /// Reload the current shader, shaders loaded from an url are downloaded again
     fn reload_shader(&mut self) {
         info!("Reloading !");
         let path = self.shader.as_ref().unwrap().main.clone();
         self.load_shader(&path);
     }
 
-    /// Watch the currently loaded file
+    /// Reload the shader when its files change
+    pub fn set_watching(&mut self, watching: bool) {
+        self.watching = watching;
+        if watching {
+            self.watch();
+        } else {
+            self.unwatch();
+        }
+    }
+
+    /// Watch the files of the loaded shaders and their includes
     fn watch(&mut self) {
-        // TODO should watch for every file that is part of compilation
-        if let Some(path) = self.shader.as_ref().map(|it| &it.main) {
-            self.watcher
-                .watch(path, RecursiveMode::NonRecursive)
-                .unwrap();
-            info!("Watching loaded shader for changes.");
+        self.unwatch();
+        for shader in self.shader.iter().chain(self.compare_shader.iter()) {
+            for source in shader.sources.iter() {
+                let path = fs::canonicalize(source).unwrap_or_else(|_| source.clone());
+                // Reloading the main shader also reloads the compared one
+                if self.watched.contains_key(&path) {
+                    continue;
+                }
+                match self.watcher.watch(&path, RecursiveMode::NonRecursive) {
+                    Ok(()) => {
+                        self.watched.insert(path, shader.main.clone());
+                    }
+                    Err(e) => error!("Can't watch {}, cause : {:?}", path.display(), e),
+                }
+            }
         }
+        info!("Watching {} files for changes.", self.watched.len());
     }
 
     /// Immediate unwatch
     fn unwatch(&mut self) {
-        // TODO should unwatch for every file that is part of compilation
-        if let Some(shader) = self.shader.as_ref() {
-            match self.watcher.unwatch(&shader.main) {
-                Ok(_) => {
-                    info!("Not watching for changes anymore.");
-                }
-                Err(e) => match e {
-                    Error::WatchNotFound => {
-                        info!("Was not watching ?");
-                    }
-                    other => {
-                        error!("Can't unwatch, cause : {:?}", other);
-                    }
-                },
+        if self.watched.is_empty() {
+            return;
+        }
+        for (path, _) in self.watched.drain() {
+            match self.watcher.unwatch(&path) {
+                Ok(()) | Err(Error::WatchNotFound) => {}
+                Err(other) => error!("Can't unwatch {}, cause : {:?}", path.display(), other),
             }
         }
+        info!("Not watching for changes anymore.");
+    }
+
+    /// Reload the shader a changed file belongs to, an include reloads the shader including it
+    fn reload_watched(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let root = match self.watched.get(&path) {
+            Some(root) => root.clone(),
+            None => return,
+        };
+        if self.shader.as_ref().map_or(false, |it| it.main == root) {
+            self.reload_shader();
+        } else {
+            self.reload_compare_shader();
+        }
     }
 
     fn reset_globals(&mut self) {
         info!("Resetting globals !");
         // Reset the running globals
         self.globals.reset();
+        self.frame_times.clear();
         self.sim_start = Instant::now();
         self.sim_duration = Duration::from_nanos(0);
     }
 
+    /// Update the measured framerate with a new frame time
+    fn record_frame_time(&mut self, frame_time: Duration) {
+        if self.frame_times.len() == FRAME_TIMES_WINDOW {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(frame_time);
+        // Vsync has no interval, the target framerate is the closest to the display refresh rate
+        let budget = match self.frame_interval() {
+            interval if interval.is_zero() => self.settings.target_framerate,
+            interval => interval,
+        };
+        self.frame_pacing.record(frame_time, budget);
+        self.set_builtin("fFrameRate", BuiltinValue::Float(self.frame_rate()));
+    }
+
+    /// Measured framerate, averaged over the last frames
+    fn frame_rate(&self) -> f32 {
+        let total: Duration = self.frame_times.iter().sum();
+        if total.is_zero() {
+            return 0.0;
+        }
+        self.frame_times.len() as f32 / total.as_secs_f32()
+    }
+
+    /// Update a builtin global, errors are programming mistakes and only logged
+    fn set_builtin(&mut self, name: &str, value: BuiltinValue) {
+        if let Err(e) = self.builtins.set(name, value) {
+            error!("{}", e);
+        }
+    }
+
     fn reset_params(&mut self) {
         info!("Resetting params !");
         if let Some(metadata) = self.shader_metadata_mut() {
             metadata.reset_params();
         }
+        self.params_dirty = true;
+    }
+
+    /// Give the unlocked params random values within their range
+    fn randomize_params(&mut self) {
+        let metadata = match self.shader.as_mut().and_then(|it| it.metadata.as_mut()) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        // Xorshift seeded by the std hasher keys, good enough to explore params
+        let mut state = RandomState::new().build_hasher().finish() | 1;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32
+        };
+        for slider in metadata.sliders.iter_mut() {
+            if !self.gui.param_locks.contains(slider.name()) {
+                slider.randomize(&mut random);
+            }
+        }
+        self.params_dirty = true;
+    }
+
+    fn set_debug_view(&mut self, enabled: bool) {
+        self.settings.debug_view = enabled;
+        self.set_builtin("uDebug", BuiltinValue::Uint(enabled as u32));
+        if enabled && !self.renderer.supports_wireframe() {
+            warn!("Wireframe isn't supported by this device, only the uDebug flag will be set");
+        }
+        self.renderer.set_wireframe(enabled);
+        // The shader pass got recreated along with its params buffer
+        self.params_dirty = true;
+    }
+
+    /// Switch how the globals get to the shader, it is reloaded with the matching globals block
+    fn set_uniform_globals(&mut self, enabled: bool) {
+        if !enabled && !self.builtins.fits_push_constants() {
+            warn!(
+                "The globals take {} bytes, more than the {} bytes of push constants, they stay in a uniform buffer",
+                self.builtins.size(),
+                PUSH_CONSTANTS_SIZE
+            );
+        }
+        let enabled = enabled || !self.builtins.fits_push_constants();
+        self.settings.uniform_globals = enabled;
+        let mode = if enabled {
+            GlobalsMode::UniformBuffer
+        } else {
+            GlobalsMode::PushConstants
+        };
+        info!("Globals passed with {:?}", mode);
+        self.shader_loader.set_globals_mode(mode);
+        self.renderer.set_globals_mode(mode);
+        if self.shader.is_some() {
+            self.reload_shader();
+        }
+    }
+
+    fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.renderer.set_blend_mode(blend_mode);
+        // The shader pass got recreated along with its params buffer
+        self.params_dirty = true;
+    }
+
+    fn set_canvas_format(&mut self, format: TextureFormat) {
+        match self.renderer.set_canvas_format(format) {
+            Ok(()) => {
+                info!("Canvas format set to {:?}", format);
+                self.params_dirty = true;
+            }
+            Err(e) => error!("{}", e),
+        }
     }
 
     fn ask_to_export(&mut self) {
         self.ask_export = true;
     }
 
+    /// Schedule an upload of the params to the gpu
+    fn mark_params_dirty(&mut self) {
+        self.params_dirty = true;
+    }
+
+    /// Switch the sampling of a texture channel, until the shader is loaded again
+    fn set_texture_filter(&mut self, index: u32, filter: FilterMode) {
+        if let Some(channel) = self
+            .shader
+            .as_mut()
+            .and_then(|it| it.metadata.as_mut())
+            .and_then(|it| it.textures.iter_mut().find(|it| it.index == index))
+        {
+            channel.filter = filter;
+            self.renderer.set_texture_filter(index, filter);
+        }
+    }
+
+    /// Record the inputs from the first frame, the shader starts over
+    fn start_input_recording(&mut self) {
+        self.input_replay = None;
+        self.reset_globals();
+        self.input_frame = 0;
+        self.input_recording = Some(InputTrace::default());
+        info!("Recording inputs");
+    }
+
+    /// Stop recording and ask where to save the trace
+    fn stop_input_recording(&mut self) {
+        self.ask_save_inputs = true;
+    }
+
+    /// Replay an input trace from the first frame, the shader starts over
+    pub fn replay_inputs(&mut self, path: &Path) -> Result<()> {
+        let trace = InputTrace::load(path)?;
+        self.input_recording = None;
+        self.reset_globals();
+        self.input_frame = 0;
+        self.input_replay = Some(trace);
+        info!("Replaying inputs from {}", path.display());
+        Ok(())
+    }
+
+    /// Feed the inputs of the trace recorded up to this frame through the event handler
+    fn replay_due_inputs(&mut self) {
+        let trace = match self.input_replay.as_mut() {
+            Some(trace) => trace,
+            None => return,
+        };
+        let mut inputs = Vec::new();
+        while let Some(input) = trace.next_due(self.input_frame) {
+            inputs.push(input);
+        }
+        if trace.is_done() {
+            self.input_replay = None;
+            info!("Input replay done");
+        }
+        let window_id = self.window.id();
+        for input in inputs {
+            self.handle_event(Event::WindowEvent {
+                window_id,
+                event: input.to_event(),
+            });
+        }
+    }
+
+    fn ask_to_save_session(&mut self) {
+        self.ask_save_session = true;
+    }
+
+    fn ask_to_load_session(&mut self) {
+        self.ask_load_session = true;
+    }
+
+    /// Open the editor on the main file of the loaded shader
+    fn open_editor(&mut self) {
+        let path = match self.shader.as_ref() {
+            Some(shader) => shader.main.clone(),
+            None => return,
+        };
+        // Keep unsaved edits of the same file
+        if self.gui.editor.as_ref().map(|it| it.path()) != Some(path.as_path()) {
+            match Editor::open(&path) {
+                Ok(editor) => self.gui.editor = Some(editor),
+                Err(e) => {
+                    error!("{:#}", e);
+                    return;
+                }
+            }
+        }
+        self.gui.editor_window = true;
+    }
+
+    /// Go back to the last shader loaded without errors after a failed load.
+    /// Its pipeline is still running, its source is put back in the editor to be saved over the
+    /// broken file.
+    fn revert_to_last_good(&mut self) {
+        let (path, source) = match (self.shader.as_ref(), self.last_good_source.as_ref()) {
+            (Some(shader), Some(source)) => (shader.main.clone(), source.clone()),
+            _ => return,
+        };
+        match self.gui.editor.as_mut().filter(|it| it.path() == path) {
+            Some(editor) => editor.set_text(source),
+            None => self.gui.editor = Some(Editor::with_text(&path, source)),
+        }
+        self.gui.editor_window = true;
+        self.load_error = None;
+        info!("Reverted to the last good version of {}", path.display());
+    }
+
+    /// Save the editor content, the shader is reloaded by the watcher or right away
+    fn save_editor(&mut self) {
+        let editor = match self.gui.editor.as_mut() {
+            Some(editor) => editor,
+            None => return,
+        };
+        if let Err(e) = editor.save() {
+            error!("{:#}", e);
+            return;
+        }
+        // The editor may still be on a previously loaded shader
+        let is_loaded = self
+            .shader
+            .as_ref()
+            .map_or(false, |it| it.main == editor.path());
+        if is_loaded && !self.watching {
+            self.reload_shader();
+        }
+    }
+
+    /// The new window is created by the event loop owner
+    fn ask_to_open_window(&mut self) {
+        self.ask_new_window = true;
+    }
+
     fn export_image(&self) {
         let export_start = Instant::now();
 
         let ExportData {
-            size, path, format, ..
+            size,
+            path,
+            format,
+            high_bit_depth,
+            watermark,
         } = &self.export_data;
+        let high_bit_depth = *high_bit_depth && *format == ImageFormat::Png;
 
         let mut globals = self.globals.clone();
-        globals.resolution = *size;
-        globals.ratio = globals.resolution.x as f32 / globals.resolution.y as f32;
+        globals.set_resolution(*size);
+
+        let render_format = if high_bit_depth {
+            TextureFormat::Rgba32Float
+        } else {
+            self.renderer.surface_format()
+        };
+
+        let watermark_text = if watermark.enabled {
+            let shader_name = self
+                .shader
+                .as_ref()
+                .and_then(|it| it.main.file_name())
+                .map(|it| it.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Some(watermark.expand(&shader_name, self.globals.time))
+        } else {
+            None
+        };
 
         self.renderer
             .render_to_buffer(
                 *size,
+                render_format,
                 &self
                     .shader_metadata()
                     .map(|it| it.params_buffer())
                     .unwrap_or_default(),
-                globals.as_std430().as_bytes(),
+                &self.builtins.write(&globals),
                 |buf| {
-                    let image = ImageBuffer::<Rgba<_>, _>::from_raw(size.x, size.y, &buf[..])
+                    if high_bit_depth {
+                        let mut image = ImageBuffer::<Rgba<u16>, _>::from_raw(
+                            size.x,
+                            size.y,
+                            float_to_rgba16(&buf[..]),
+                        )
                         .context("Can't create image from buffer")?;
-                    image.save_with_format(path, *format)?;
+                        if let Some(text) = watermark_text.as_ref() {
+                            watermark.stamp(text, size.x, size.y, |x, y, alpha| {
+                                let pixel = image.get_pixel_mut(x, y);
+                                for c in pixel.0[..3].iter_mut() {
+                                    *c += ((u16::MAX - *c) as f32 * alpha) as u16;
+                                }
+                            });
+                        }
+                        image.save_with_format(path, *format)?;
+                    } else {
+                        let mut image =
+                            ImageBuffer::<Rgba<_>, _>::from_raw(size.x, size.y, buf.to_vec())
+                                .context("Can't create image from buffer")?;
+                        if let Some(text) = watermark_text.as_ref() {
+                            watermark.stamp(text, size.x, size.y, |x, y, alpha| {
+                                let pixel = image.get_pixel_mut(x, y);
+                                for c in pixel.0[..3].iter_mut() {
+                                    *c += ((u8::MAX - *c) as f32 * alpha) as u8;
+                                }
+                            });
+                        }
+                        image.save_with_format(path, *format)?;
+                    }
 
                     Ok(())
                 },
@@ -435,7 +1979,8 @@ impl Nuance {
         );
     }
 
-    fn pause(&mut self) {
+    /// Stop the time, the last frame stays displayed
+    pub fn pause(&mut self) {
         self.sim_duration += self.sim_start.elapsed();
         self.paused = true;
     }
@@ -444,15 +1989,61 @@ impl Nuance {
         self.paused
     }
 
+    /// The shader animates with the wall clock, the frozen simulation still needs redrawing
+    fn draws_while_paused(&self) -> bool {
+        self.shader_metadata()
+            .map_or(false, |metadata| metadata.uses_real_time)
+    }
+
     fn resume(&mut self) {
         self.sim_start = Instant::now();
         self.paused = false;
+        self.watchdog_tripped = None;
+    }
+
+    /// Pause the shader if the last frame took too long on the gpu
+    fn check_watchdog(&mut self) {
+        if self.settings.watchdog_ms <= 0.0 || self.is_paused() {
+            return;
+        }
+        if let Some(gpu_time) = self.renderer.gpu_time() {
+            if gpu_time.as_secs_f32() * 1000.0 > self.settings.watchdog_ms {
+                warn!(
+                    "Frame took {:.1} ms on the gpu, pausing the shader",
+                    gpu_time.as_secs_f32() * 1000.0
+                );
+                self.pause();
+                self.watchdog_tripped = Some(gpu_time);
+            }
+        }
+    }
+
+    /// Check the shader got its pipeline built and draw it once, after moving to another adapter
+    pub fn check_pipelines(&mut self) -> Result<()> {
+        if !self.shader_loaded() {
+            return Ok(());
+        }
+        if let Some(e) = self.load_error.as_ref() {
+            bail!("The shader didn't load : {:#}", e);
+        }
+        if !self.renderer.has_pipeline() {
+            bail!("No pipeline was built for the shader");
+        }
+        let warm_up_time = self.renderer.warm_up();
+        debug!("Drew the shader in {} ms", warm_up_time.as_millis());
+        Ok(())
     }
 
     fn shader_loaded(&self) -> bool {
         self.shader.is_some()
     }
 
+    fn shader_is_url(&self) -> bool {
+        self.shader
+            .as_ref()
+            .map_or(false, |it| ShaderLoader::is_url(&it.main))
+    }
+
     fn shader_metadata(&self) -> Option<&ShaderMetadata> {
         self.shader
             .as_ref()
@@ -460,6 +2051,150 @@ impl Nuance {
             .flatten()
     }
 
+    /// Compile the expressions of the loaded shader params, invalid ones are reported as warnings
+    fn compile_param_exprs(&mut self) {
+        self.param_exprs.clear();
+        let shader = match self.shader.as_mut() {
+            Some(shader) => shader,
+            None => return,
+        };
+        let sliders = match shader.metadata.as_mut() {
+            Some(metadata) => &mut metadata.sliders,
+            None => return,
+        };
+        for (index, slider) in sliders.iter_mut().enumerate() {
+            if let Slider::Float {
+                name,
+                expr: Some(expr),
+                scripted,
+                ..
+            } = slider
+            {
+                match expr.parse::<meval::Expr>().and_then(|it| it.bind("t")) {
+                    Ok(function) => self.param_exprs.push((index, Box::new(function))),
+                    Err(e) => {
+                        *scripted = false;
+                        shader
+                            .warnings
+                            .push(format!("Invalid expression for {} : {}", name, e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Scripted params follow their expression of the time
+    fn evaluate_param_exprs(&mut self) {
+        let t = self.globals.time as f64;
+        let mut changed = false;
+        if let Some(metadata) = self.shader.as_mut().and_then(|it| it.metadata.as_mut()) {
+            for (index, function) in self.param_exprs.iter() {
+                if let Some(Slider::Float {
+                    value,
+                    scripted: true,
+                    ..
+                }) = metadata.sliders.get_mut(*index)
+                {
+                    *value = function(t) as f32;
+                    changed = true;
+                }
+            }
+        }
+        self.params_dirty |= changed;
+    }
+
+    /// Scale of the wheel increment for a wheel event, depends on the scrolling speed for the
+    /// accelerated curve
+    fn wheel_multiplier(&mut self, lines: f32) -> f32 {
+        let since_last = mem::replace(&mut self.last_wheel, Instant::now()).elapsed();
+        match self.settings.wheel_curve {
+            WheelCurve::Linear => 1.0,
+            WheelCurve::Accelerated => {
+                // Notches per sec, a pause in scrolling starts over from the finest increment
+                let rate = if since_last > Duration::from_millis(250) {
+                    0.0
+                } else {
+                    (lines.abs() / since_last.as_secs_f32().max(0.001)).min(60.0)
+                };
+                self.settings.wheel_acceleration.max(1.0).powf(rate / 10.0)
+            }
+        }
+    }
+
+    /// Update the mouse globals from the last cursor position received
+    fn commit_cursor(&mut self) {
+        let position = match self.pending_cursor.take() {
+            Some(position) => position,
+            None => return,
+        };
+        if let Some((x, y)) = self.canvas_position(position) {
+            // In render pixels, the canvas is upscaled when the render size is capped
+            let (_, viewport) = self.canvas_viewport(self.window.inner_size().into());
+            let scale = self.globals.resolution.x as f64 / (viewport.x as f64).max(1.0);
+            let x = x * scale;
+            let y = y * scale;
+            // Same origin as fragCoord
+            let y = if self.globals.flip_y != 0 {
+                self.globals.resolution.y as f64 - y
+            } else {
+                y
+            };
+            self.globals.mouse = Vector2::from([x as u32, y.max(0.0) as u32]);
+            if self.modifiers.ctrl() {
+                self.drive_params_with_mouse();
+            }
+        }
+    }
+
+    /// Follow the cursor while the left button is held, keeping where the click happened. The
+    /// click position is negated once the button is released.
+    fn update_mouse_drag(&mut self) {
+        let (x, y) = (self.globals.mouse.x as f32, self.globals.mouse.y as f32);
+        let drag = &mut self.mouse_drag;
+        if mem::take(&mut self.mouse_clicked) {
+            *drag = [x, y, x, y];
+        } else if self.mouse_down {
+            drag[0] = x;
+            drag[1] = y;
+        }
+        if !self.mouse_down {
+            drag[2] = -drag[2].abs();
+            drag[3] = -drag[3].abs();
+        }
+        self.set_builtin("fMouseDrag", BuiltinValue::Vec4(self.mouse_drag));
+    }
+
+    /// Params bound to a mouse axis follow the mouse over the canvas
+    fn drive_params_with_mouse(&mut self) {
+        let x = self.globals.mouse.x as f32 / self.globals.resolution.x.max(1) as f32;
+        // Up is the max
+        let mut y = self.globals.mouse.y as f32 / self.globals.resolution.y.max(1) as f32;
+        if self.globals.flip_y == 0 {
+            y = 1.0 - y;
+        }
+        let mut changed = false;
+        if let Some(metadata) = self.shader_metadata_mut() {
+            for slider in metadata.sliders.iter_mut() {
+                if let Slider::Float {
+                    min,
+                    max,
+                    mouse_axis: Some(axis),
+                    value,
+                    ..
+                } = slider
+                {
+                    let t = match axis {
+                        MouseAxis::X => x,
+                        MouseAxis::Y => y,
+                    };
+                    *value = *min + (*max - *min) * t.clamp(0.0, 1.0);
+                    changed = true;
+                }
+            }
+        }
+        self.params_dirty |= changed;
+    }
+
     fn shader_metadata_mut(&mut self) -> Option<&mut ShaderMetadata> {
         self.shader
             .as_mut()
@@ -467,3 +2202,84 @@ impl Nuance {
             .flatten()
     }
 }
+
+/// The compared shader is drawn with the params, textures and feedback of the main shader,
+/// it must declare the same
+fn check_comparable(main: &Shader, other: &Shader) -> Result<()> {
+    let params = |shader: &Shader| {
+        shader.metadata.as_ref().map_or(Vec::new(), |metadata| {
+            metadata
+                .sliders
+                .iter()
+                .map(Slider::name)
+                .zip(metadata.params_offsets())
+                .map(|(name, offset)| (name.to_string(), offset))
+                .collect()
+        })
+    };
+    if params(main) != params(other) {
+        bail!("The params differ from the ones of the main shader");
+    }
+
+    let textures = |shader: &Shader| {
+        shader.metadata.as_ref().map_or(Vec::new(), |metadata| {
+            metadata
+                .textures
+                .iter()
+                .map(|it| (it.index, it.source.view_dimension()))
+                .collect()
+        })
+    };
+    if textures(main) != textures(other) {
+        bail!("The texture channels differ from the ones of the main shader");
+    }
+
+    let history = |shader: &Shader| {
+        shader
+            .metadata
+            .as_ref()
+            .map_or(1, ShaderMetadata::history_frames)
+    };
+    if other.uses_feedback && (!main.uses_feedback || history(other) > history(main)) {
+        bail!("The shader samples more previous frames than the main shader");
+    }
+    Ok(())
+}
+
+/// Convert a linear Rgba32Float buffer to 16 bit srgb values.
+/// Values above 1 are clipped, there is no tonemapping.
+fn float_to_rgba16(buf: &[u8]) -> Vec<u16> {
+    buf.chunks_exact(4)
+        .enumerate()
+        .map(|(i, bytes)| {
+            let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            // Alpha is linear
+            let value = if i % 4 == 3 {
+                value
+            } else {
+                linear_to_srgb(value)
+            };
+            (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+        })
+        .collect()
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Largest size with the `aspect` width/height ratio fitting in `width` x `height`, the whole
+/// size when `aspect` is 0
+fn fit_aspect(width: f32, height: f32, aspect: f32) -> (f32, f32) {
+    if aspect <= 0.0 {
+        (width, height)
+    } else if width > height * aspect {
+        (height * aspect, height)
+    } else {
+        (width, width / aspect)
+    }
+}
@@ -0,0 +1,143 @@
+//! OSC input driving params, for live performance. Enabled with the `osc` feature.
+
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use rosc::{OscPacket, OscType};
+
+use nuance::Slider;
+
+use crate::cli::OscConfig;
+
+/// Messages sent to `/param/<name>` drive the param with this name without any mapping
+const PARAM_PREFIX: &str = "/param/";
+
+pub struct OscInput {
+    rx: Receiver<(String, Vec<f32>)>,
+    /// Param names by OSC address
+    map: HashMap<String, String>,
+}
+
+impl OscInput {
+    /// Listen for messages on a background thread
+    pub fn listen(config: &OscConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", config.port))
+            .with_context(|| format!("Can't listen for OSC messages on port {}", config.port))?;
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            loop {
+                let size = match socket.recv_from(&mut buf) {
+                    Ok((size, _)) => size,
+                    Err(e) => {
+                        error!("Can't receive OSC messages : {}", e);
+                        return;
+                    }
+                };
+                match rosc::decoder::decode(&buf[..size]) {
+                    Ok(packet) => {
+                        // The app is gone
+                        if !send_packet(&tx, packet) {
+                            return;
+                        }
+                    }
+                    Err(e) => debug!("Invalid OSC packet : {:?}", e),
+                }
+            }
+        });
+        info!("Listening for OSC messages on port {}", config.port);
+        Ok(Self {
+            rx,
+            map: config.map.iter().cloned().collect(),
+        })
+    }
+
+    /// Param names and normalized values of the messages received since the last call
+    pub fn poll(&self) -> Vec<(String, Vec<f32>)> {
+        self.rx
+            .try_iter()
+            .filter_map(|(address, values)| {
+                let name = match self.map.get(&address) {
+                    Some(name) => name.clone(),
+                    None => address.strip_prefix(PARAM_PREFIX)?.to_string(),
+                };
+                Some((name, values))
+            })
+            .collect()
+    }
+}
+
+/// Forward the numeric arguments of every message in the packet, false when the receiver is gone
+fn send_packet(tx: &Sender<(String, Vec<f32>)>, packet: OscPacket) -> bool {
+    match packet {
+        OscPacket::Message(message) => {
+            let values = message
+                .args
+                .iter()
+                .filter_map(|arg| match arg {
+                    OscType::Float(value) => Some(*value),
+                    OscType::Double(value) => Some(*value as f32),
+                    OscType::Int(value) => Some(*value as f32),
+                    OscType::Bool(value) => Some(*value as u32 as f32),
+                    _ => None,
+                })
+                .collect();
+            tx.send((message.addr, values)).is_ok()
+        }
+        OscPacket::Bundle(bundle) => bundle
+            .content
+            .into_iter()
+            .all(|packet| send_packet(tx, packet)),
+    }
+}
+
+/// Set a param from normalized control values, mapped to its range
+pub fn apply(slider: &mut Slider, values: &[f32]) {
+    let norm = |i: usize| values.get(i).map(|it| it.clamp(0.0, 1.0));
+    match slider {
+        Slider::Float {
+            value, min, max, ..
+        } => {
+            if let Some(v) = norm(0) {
+                *value = *min + v * (*max - *min);
+            }
+        }
+        Slider::Uint {
+            value, min, max, ..
+        } => {
+            if let Some(v) = norm(0) {
+                *value = *min + (v * (*max - *min) as f32).round() as u32;
+            }
+        }
+        Slider::Bool { value, .. } => {
+            if let Some(v) = norm(0) {
+                *value = (v > 0.5) as u32;
+            }
+        }
+        // No range, the values are taken as is
+        Slider::Vec2 { value, .. } => {
+            if let [x, y, ..] = values {
+                value.x = *x;
+                value.y = *y;
+            }
+        }
+        Slider::Vec3 { value, .. } => {
+            if let [x, y, z, ..] = values {
+                value.x = *x;
+                value.y = *y;
+                value.z = *z;
+            }
+        }
+        Slider::Color { value, .. } => {
+            if let (Some(r), Some(g), Some(b)) = (norm(0), norm(1), norm(2)) {
+                value.x = r;
+                value.y = g;
+                value.z = b;
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Fullscreen pass transforming the canvas before it is displayed
+
+use crevice::std140::{AsStd140, Std140};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+/// Settings passed to the post processing shader
+#[derive(AsStd140, Default)]
+pub struct PostSettings {
+    /// 1 to map hdr colors to the displayable range
+    pub tonemap: u32,
+    /// 1 to add noise hiding the banding of the 8 bit output
+    pub dither: u32,
+}
+
+pub struct PostPass {
+    bind_group_layout: BindGroupLayout,
+    bind_group: BindGroup,
+    settings_buffer: Buffer,
+    pipeline: RenderPipeline,
+}
+
+impl PostPass {
+    pub fn new(device: &Device, input: &Texture, output_format: TextureFormat) -> Self {
+        let module = device.create_shader_module(&include_wgsl!("post.wgsl"));
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("post bind group layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let settings_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("post settings ubo"),
+            contents: PostSettings::default().as_std140().as_bytes(),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &settings_buffer, input);
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("post pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("post pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[ColorTargetState {
+                    format: output_format,
+                    write_mask: ColorWrites::ALL,
+                    blend: None,
+                }],
+            }),
+        });
+
+        Self {
+            bind_group_layout,
+            bind_group,
+            settings_buffer,
+            pipeline,
+        }
+    }
+
+    /// Change the input texture, it must be of the same size as the output
+    pub fn set_input(&mut self, device: &Device, input: &Texture) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.settings_buffer,
+            input,
+        );
+    }
+
+    pub fn update_settings(&self, queue: &Queue, settings: &PostSettings) {
+        queue.write_buffer(&self.settings_buffer, 0, settings.as_std140().as_bytes());
+    }
+
+    pub fn execute(&self, encoder: &mut CommandEncoder, output: &TextureView) {
+        puffin::profile_scope!("post pass execute");
+
+        let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("post render pass"),
+            color_attachments: &[RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(&self.pipeline);
+        rpass.set_bind_group(0, &self.bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    fn create_bind_group(
+        device: &Device,
+        layout: &BindGroupLayout,
+        settings_buffer: &Buffer,
+        input: &Texture,
+    ) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("post bind group"),
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(
+                        &input.create_view(&TextureViewDescriptor::default()),
+                    ),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+}
@@ -0,0 +1,34 @@
+[[block]]
+struct Settings {
+    tonemap: u32;
+    dither: u32;
+};
+
+[[group(0), binding(0)]]
+var input: texture_2d<f32>;
+[[group(0), binding(1)]]
+var<uniform> settings: Settings;
+
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] in_vertex_index: u32) -> [[builtin(position)]] vec4<f32> {
+    let pos = vec2<f32>(f32((in_vertex_index << 1u) & 2u), f32(in_vertex_index & 2u));
+    return vec4<f32>(pos * 2.0 - 1.0, 0.0, 1.0);
+}
+
+[[stage(fragment)]]
+fn fs_main([[builtin(position)]] pos: vec4<f32>) -> [[location(0)]] vec4<f32> {
+    var color: vec4<f32> = textureLoad(input, vec2<i32>(pos.xy), 0);
+    if (settings.tonemap == 1u) {
+        // Reinhard tonemapping
+        color = vec4<f32>(color.rgb / (color.rgb + vec3<f32>(1.0)), color.a);
+    }
+    if (settings.dither == 1u) {
+        // Interleaved gradient noise of one quantization step, added in the (approximate) srgb
+        // encoded space of the 8 bit output where the steps are even
+        let noise = fract(52.9829189 * fract(dot(pos.xy, vec2<f32>(0.06711056, 0.00583715))));
+        let encoded = pow(clamp(color.rgb, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(1.0 / 2.2));
+        let dithered = clamp(encoded + (noise - 0.5) / 255.0, vec3<f32>(0.0), vec3<f32>(1.0));
+        color = vec4<f32>(pow(dithered, vec3<f32>(2.2)), color.a);
+    }
+    return color;
+}
@@ -0,0 +1,119 @@
+//! Readback of a single canvas pixel
+
+use std::convert::TryInto;
+use std::num::NonZeroU32;
+
+use anyhow::{bail, Result};
+use mint::Vector2;
+use wgpu::*;
+
+/// Copies one pixel of a texture to a small buffer read back on the cpu
+pub struct PixelProbe {
+    buffer: Buffer,
+}
+
+impl PixelProbe {
+    pub fn new(device: &Device) -> Self {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("pixel probe buffer"),
+            // A single row still has to be aligned
+            size: COPY_BYTES_PER_ROW_ALIGNMENT as BufferAddress,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        Self { buffer }
+    }
+
+    pub fn copy(&self, encoder: &mut CommandEncoder, texture: &Texture, pos: Vector2<u32>) {
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: pos.x,
+                    y: pos.y,
+                    z: 0,
+                },
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &self.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(COPY_BYTES_PER_ROW_ALIGNMENT),
+                    rows_per_image: NonZeroU32::new(1),
+                },
+            },
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Wait for the gpu to finish the submitted work and read the linear rgba value of the pixel
+    pub fn read(&self, device: &Device, format: TextureFormat) -> Result<[f32; 4]> {
+        let slice = self.buffer.slice(..);
+        let mapping = slice.map_async(MapMode::Read);
+        device.poll(Maintain::Wait);
+        futures_executor::block_on(mapping)?;
+
+        let color = {
+            let view = slice.get_mapped_range();
+            decode_pixel(&view, format)
+        };
+        self.buffer.unmap();
+        color
+    }
+}
+
+fn decode_pixel(bytes: &[u8], format: TextureFormat) -> Result<[f32; 4]> {
+    let color = match format {
+        TextureFormat::Bgra8UnormSrgb => {
+            let channel = |i: usize| srgb_to_linear(bytes[i] as f32 / 255.0);
+            [channel(2), channel(1), channel(0), bytes[3] as f32 / 255.0]
+        }
+        TextureFormat::Rgba8UnormSrgb => {
+            let channel = |i: usize| srgb_to_linear(bytes[i] as f32 / 255.0);
+            [channel(0), channel(1), channel(2), bytes[3] as f32 / 255.0]
+        }
+        TextureFormat::Rgba16Float => {
+            let channel = |i: usize| {
+                f16_to_f32(u16::from_ne_bytes(
+                    bytes[2 * i..2 * i + 2].try_into().unwrap(),
+                ))
+            };
+            [channel(0), channel(1), channel(2), channel(3)]
+        }
+        TextureFormat::Rgba32Float => {
+            let channel =
+                |i: usize| f32::from_ne_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+            [channel(0), channel(1), channel(2), channel(3)]
+        }
+        other => bail!("Can't read pixels of a {:?} texture", other),
+    };
+    Ok(color)
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Decode an IEEE half precision float
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    match exponent {
+        // Subnormal
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
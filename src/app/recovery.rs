@@ -0,0 +1,44 @@
+//! Session saved when the app panics, offered back on the next launch
+
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::app::session::{Session, SESSION_EXTENSION};
+
+lazy_static! {
+    /// Last state of the window updated most recently
+    static ref SNAPSHOT: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+/// Where the session is saved on panic
+pub fn recovery_file() -> PathBuf {
+    std::env::temp_dir().join(format!("nuance-recovery.{}", SESSION_EXTENSION))
+}
+
+/// Keep this state around to be saved if the app panics
+pub fn update(session: Option<Session>) {
+    if let Ok(mut snapshot) = SNAPSHOT.lock() {
+        *snapshot = session;
+    }
+}
+
+/// Save the last snapshot to the recovery file before the default panic message
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // The panic may have happened while the snapshot was being updated
+        if let Ok(snapshot) = SNAPSHOT.try_lock() {
+            if let Some(session) = snapshot.as_ref() {
+                let path = recovery_file();
+                match session.save(&path) {
+                    Ok(()) => eprintln!("Session saved to {}", path.display()),
+                    Err(e) => eprintln!("Can't save the session : {:#}", e),
+                }
+            }
+        }
+        default_hook(info);
+    }));
+}
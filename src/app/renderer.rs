@@ -1,5 +1,9 @@
+use std::fmt;
 use std::mem;
 use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::slice;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use egui::{ClippedMesh, TextureId};
@@ -7,72 +11,253 @@ use egui_wgpu_backend::ScreenDescriptor;
 use log::{debug, error, info};
 use mint::Vector2;
 use puffin::ProfilerScope;
+use wgpu::util::DeviceExt;
 use wgpu::*;
 use winit::window::Window;
 
-use nuance::renderer::ShaderRenderPass;
+use nuance::renderer::{
+    channel_layout_entries, create_vertex_module, feedback_layout_entries, storage_layout_entries,
+    BlendMode, GlobalsMode, Scissor, ShaderRenderPass, DEFAULT_GLOBALS_STAGES,
+    PREVIOUS_TARGETS_BINDING, TARGETS_FORMAT,
+};
+use nuance::{requirable_features, ChannelSource, TextureChannel};
+
+use crate::app::accumulate::AccumulatePass;
+use crate::app::keyboard::{KEYBOARD_TEX_HEIGHT, KEYBOARD_TEX_WIDTH};
+use crate::app::post::{PostPass, PostSettings};
+use crate::app::probe::PixelProbe;
+use crate::app::sanitize::SanitizePass;
+use crate::app::timer::GpuTimer;
+
+/// Why the renderer couldn't be created
+#[derive(Debug)]
+pub enum RendererError {
+    /// No adapter can render to the window
+    NoAdapter,
+    /// The adapter chosen by index doesn't exist or can't render to the window
+    InvalidAdapter(usize),
+    /// The adapter can't give us a device with the features we need
+    RequestDevice(RequestDeviceError),
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RendererError::NoAdapter => write!(f, "Can't find a suitable adapter"),
+            RendererError::InvalidAdapter(index) => {
+                write!(
+                    f,
+                    "Adapter {} doesn't exist or can't render to the window",
+                    index
+                )
+            }
+            RendererError::RequestDevice(e) => write!(f, "Can't create device : {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::NoAdapter | RendererError::InvalidAdapter(_) => None,
+            RendererError::RequestDevice(e) => Some(e),
+        }
+    }
+}
+
+impl From<RequestDeviceError> for RendererError {
+    fn from(e: RequestDeviceError) -> Self {
+        RendererError::RequestDevice(e)
+    }
+}
 
 pub struct Renderer {
     #[allow(dead_code)]
     instance: Instance,
     #[allow(dead_code)]
     adapter: Adapter,
+    /// All the adapters found when the renderer was created
+    adapters_info: Vec<AdapterInfo>,
     device: Device,
 
     queue: Queue,
     #[allow(dead_code)]
     surface: Surface,
+    /// Size the surface was last configured with
+    surface_size: Vector2<u32>,
+    /// Fifo waits for the display to present, Mailbox replaces the waiting frame
+    present_mode: PresentMode,
     format: TextureFormat,
+    /// Format the shader renders to, can differ from the swapchain format for hdr output
+    canvas_format: TextureFormat,
     render_size: Vector2<u32>,
 
     render_tex: Texture,
-    last_render_tex: Texture,
+    /// Canvas converted to the swapchain format, only used when the canvas format differs
+    display_tex: Option<Texture>,
+    post_pass: Option<PostPass>,
+    /// Dither the canvas when converting it to the swapchain format
+    dither: bool,
+    /// Replaces the NaN and infinite pixels of the canvas when enabled
+    sanitize: Option<SanitizePass>,
+    /// Averages the sub-frames, only allocated for shaders rendering more than one per frame
+    accumulate: Option<AccumulatePass>,
+    /// Id of the canvas texture in the egui renderer
+    canvas_texture_id: TextureId,
+    /// Filter used to sample the canvas when the ui draws it
+    canvas_filter: FilterMode,
+    /// Version, size and pixels address of the last uploaded font texture
+    font_texture: Option<(u64, usize, usize, usize)>,
+    /// The canvas textures got recreated, the split moved or a sampler changed, render again even
+    /// if paused
+    canvas_reallocated: bool,
+    /// Measures the shader pass when enabled
+    gpu_timer: Option<GpuTimer>,
+    /// Gpu time of the last shader pass measured
+    gpu_time: Option<Duration>,
+    /// Reads back the pixel at `probe_pos` when enabled
+    probe: Option<PixelProbe>,
+    probe_pos: Vector2<u32>,
+    /// Value of the last probed pixel
+    probed: Option<[f32; 4]>,
     last_render_tex_bgl: BindGroupLayout,
-    last_render_tex_bg: BindGroup,
+    /// Last frame texture, only allocated when the shader samples it
+    feedback: Option<Feedback>,
+    uses_feedback: bool,
+    /// Number of previous frames kept for the shader
+    history_frames: u32,
+    /// Number of outputs of the shader, the canvas then the extra targets
+    targets: u32,
+    /// Targets keeping their last content instead of being cleared
+    kept_targets: Vec<u32>,
+    /// Outputs of the shader after the canvas
+    extra_targets: Vec<Texture>,
+    /// Texture channels of the current shader
+    textures_bgl: Option<BindGroupLayout>,
+    textures_bg: Option<BindGroup>,
+    /// Bound texture channels, kept to rebuild the bind group when a sampler changes
+    channel_bindings: Vec<ChannelBinding>,
+    /// Bytes taken by the texture channels
+    textures_memory: u64,
+    /// Keyboard channels, updated every frame
+    keyboard_texs: Vec<Texture>,
+    /// Storage buffer the shader reads and writes, kept across frames until the next load
+    storage_buffer: Option<Buffer>,
+    storage_bgl: Option<BindGroupLayout>,
+    storage_bg: Option<BindGroup>,
+    /// Bytes taken by the storage buffer
+    storage_size: u64,
 
+    /// Vertex stage of the shader pipelines, created with this device
+    vertex_module: ShaderModule,
     shader_module: Option<ShaderModule>,
     shader_rpass: Option<ShaderRenderPass>,
+    /// Second shader drawn over the right part of the canvas
+    compare: Option<Compare>,
+    /// Draw the second shader right of this fraction of the canvas width
+    compare_split: Option<f32>,
+    push_constants_size: u32,
+    globals_mode: GlobalsMode,
+    /// Stages of the main shader reading the globals
+    globals_stages: ShaderStages,
+    params_buffer_size: u64,
+    polygon_mode: PolygonMode,
+    blend_mode: BlendMode,
     pub egui_rpass: egui_wgpu_backend::RenderPass,
 }
 
+/// Variant of the shader compared side by side with it, it shares the globals, params, textures
+/// and feedback of the main shader
+struct Compare {
+    module: ShaderModule,
+    rpass: Option<ShaderRenderPass>,
+    params_buffer_size: u64,
+    globals_stages: ShaderStages,
+}
+
+/// Texture channel as bound to the shader
+struct ChannelBinding {
+    /// The N in textureN
+    index: u32,
+    view: TextureView,
+    wrap: AddressMode,
+    filter: FilterMode,
+    sampler: Sampler,
+}
+
+/// Ring of the last rendered frames, sampled by shaders using feedback
+struct Feedback {
+    textures: Vec<Texture>,
+    /// Last frame of the extra targets
+    targets: Vec<Texture>,
+    /// Bind group for each position of the head
+    bind_groups: Vec<BindGroup>,
+    /// Texture holding the last frame
+    head: usize,
+}
+
+impl Feedback {
+    fn bind_group(&self) -> &BindGroup {
+        &self.bind_groups[self.head]
+    }
+
+    /// Move the head to the oldest frame, returns the texture to write the new frame to
+    fn advance(&mut self) -> &Texture {
+        self.head = (self.head + 1) % self.textures.len();
+        &self.textures[self.head]
+    }
+}
+
 impl Renderer {
+    /// `adapter` is an index in the list of adapters, it takes precedence over `pref_hp`
     pub async fn new(
         window: &Window,
         pref_hp: bool,
+        adapter: Option<usize>,
         render_size: Vector2<u32>,
         push_constants_size: u32,
-    ) -> Result<Self> {
+    ) -> Result<Self, RendererError> {
         let backend = util::backend_bits_from_env().unwrap_or(Backends::PRIMARY);
 
         debug!("Using wgpu backend {:?}", backend);
         let instance = Instance::new(backend);
+        let mut adapters: Vec<Adapter> = instance.enumerate_adapters(backend).collect();
+        let adapters_info: Vec<AdapterInfo> = adapters.iter().map(Adapter::get_info).collect();
         debug!("Found adapters :");
-        instance.enumerate_adapters(backend).for_each(|it| {
+        for info in adapters_info.iter() {
             debug!(
                 " - {}: {:?} ({:?})",
-                it.get_info().name,
-                it.get_info().device_type,
-                it.get_info().backend
+                info.name, info.device_type, info.backend
             );
-        });
+        }
 
         // The surface describes where we'll draw our output
         let surface = unsafe { instance.create_surface(window) };
 
-        let power_preference = if pref_hp {
-            PowerPreference::HighPerformance
-        } else {
-            util::power_preference_from_env().unwrap_or(PowerPreference::LowPower)
-        };
+        let adapter = match adapter {
+            Some(index) => {
+                if index >= adapters.len() || !adapters[index].is_surface_supported(&surface) {
+                    return Err(RendererError::InvalidAdapter(index));
+                }
+                adapters.swap_remove(index)
+            }
+            None => {
+                let power_preference = if pref_hp {
+                    PowerPreference::HighPerformance
+                } else {
+                    util::power_preference_from_env().unwrap_or(PowerPreference::LowPower)
+                };
 
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions {
-                // Use an integrated gpu if possible
-                power_preference,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .context("Can't find a suitable adapter")?;
+                instance
+                    .request_adapter(&RequestAdapterOptions {
+                        // Use an integrated gpu if possible
+                        power_preference,
+                        compatible_surface: Some(&surface),
+                    })
+                    .await
+                    .ok_or(RendererError::NoAdapter)?
+            }
+        };
 
         info!(
             "picked : {}: {:?} ({:?})",
@@ -86,9 +271,18 @@ impl Renderer {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: Some("device_request"),
-                    features: Features::PUSH_CONSTANTS,
+                    // Line polygon mode is only used for the debug view and timestamps for the
+                    // watchdog, we can do without them. The features shaders can require are
+                    // checked when they are loaded.
+                    features: Features::PUSH_CONSTANTS
+                        | (adapter.features()
+                            & (Features::NON_FILL_POLYGON_MODE
+                                | Features::TIMESTAMP_QUERY
+                                | requirable_features())),
                     limits: Limits {
-                        max_push_constant_size: push_constants_size,
+                        // The globals can't use more than the adapter supports
+                        max_push_constant_size: push_constants_size
+                            .min(adapter.limits().max_push_constant_size),
                         ..Default::default()
                     },
                 },
@@ -98,76 +292,796 @@ impl Renderer {
 
         // The output format
         let format = TextureFormat::Bgra8UnormSrgb;
-        let window_size = window.inner_size();
-
-        surface.configure(
-            &device,
-            &SurfaceConfiguration {
-                usage: TextureUsages::RENDER_ATTACHMENT,
-                format,
-                width: window_size.width,
-                height: window_size.height,
-                present_mode: PresentMode::Mailbox,
-            },
-        );
+        let surface_size: Vector2<u32> = window.inner_size().into();
+        let present_mode = PresentMode::Mailbox;
+        Self::configure_surface(&surface, &device, format, surface_size, present_mode);
 
-        let render_tex = Self::create_render_tex(&device, render_size, format);
+        // Start with the canvas in the swapchain format, no conversion needed
+        let canvas_format = format;
+        let render_tex = Self::create_render_tex(&device, render_size, canvas_format);
 
-        let (last_render_tex, last_render_tex_bgl, last_render_tex_bg) =
-            Self::create_last_render_tex(&device, render_size, format);
+        let last_render_tex_bgl = Self::create_last_render_tex_bgl(&device, 1, 1);
+        let vertex_module = create_vertex_module(&device);
 
         // The egui renderer in its own render pass
         let mut egui_rpass = egui_wgpu_backend::RenderPass::new(&device, format, 1);
         // egui will need our render texture
-        egui_rpass.egui_texture_from_wgpu_texture(&device, &render_tex, FilterMode::Linear);
+        let canvas_texture_id =
+            egui_rpass.egui_texture_from_wgpu_texture(&device, &render_tex, FilterMode::Linear);
 
         Ok(Self {
             instance,
             adapter,
+            adapters_info,
             device,
             queue,
             surface,
+            surface_size,
+            present_mode,
             format,
+            canvas_format,
             render_size,
             render_tex,
-            last_render_tex,
+            display_tex: None,
+            post_pass: None,
+            sanitize: None,
+            accumulate: None,
+            dither: false,
+            canvas_texture_id,
+            canvas_filter: FilterMode::Linear,
+            font_texture: None,
+            canvas_reallocated: false,
+            gpu_timer: None,
+            gpu_time: None,
+            probe: None,
+            probe_pos: Vector2::from([0, 0]),
+            probed: None,
             last_render_tex_bgl,
-            last_render_tex_bg,
+            feedback: None,
+            uses_feedback: false,
+            history_frames: 1,
+            targets: 1,
+            kept_targets: Vec::new(),
+            extra_targets: Vec::new(),
+            textures_bgl: None,
+            textures_bg: None,
+            storage_buffer: None,
+            storage_bgl: None,
+            storage_bg: None,
+            storage_size: 0,
+            channel_bindings: Vec::new(),
+            textures_memory: 0,
+            keyboard_texs: Vec::new(),
 
             // Start with nothing loaded
+            vertex_module,
             shader_module: None,
             shader_rpass: None,
+            compare: None,
+            compare_split: None,
+            push_constants_size,
+            globals_mode: GlobalsMode::PushConstants,
+            globals_stages: DEFAULT_GLOBALS_STAGES,
+            params_buffer_size: 0,
+            polygon_mode: PolygonMode::Fill,
+            blend_mode: BlendMode::default(),
             egui_rpass,
         })
     }
 
+    /// How the globals get to the next shaders, their GLSL must be generated for the same mode
+    pub fn set_globals_mode(&mut self, mode: GlobalsMode) {
+        self.globals_mode = mode;
+    }
+
     pub fn set_shader(
         &mut self,
         shader_source: ShaderSource,
-        push_constant_size: u32,
+        push_constants_size: u32,
+        globals_stages: ShaderStages,
         params_buffer_size: u64,
+        uses_feedback: bool,
+        history_frames: u32,
+        targets: u32,
+        kept_targets: Vec<u32>,
     ) {
+        self.uses_feedback = uses_feedback;
+        self.kept_targets = kept_targets;
+        let layout_changed = history_frames != self.history_frames || targets != self.targets;
+        if layout_changed {
+            self.history_frames = history_frames;
+            self.last_render_tex_bgl =
+                Self::create_last_render_tex_bgl(&self.device, history_frames, targets);
+        }
+        if targets != self.targets {
+            self.targets = targets;
+            self.extra_targets = self.create_extra_targets(self.render_size);
+        }
+        self.update_feedback(layout_changed);
         let module = self.device.create_shader_module(&ShaderModuleDescriptor {
             label: Some("nuance fragment shader"),
             source: shader_source,
         });
-        self.shader_rpass = Some(ShaderRenderPass::new(
+        self.shader_module = Some(module);
+        self.push_constants_size = push_constants_size;
+        self.globals_stages = globals_stages;
+        self.params_buffer_size = params_buffer_size;
+        self.recreate_rpasses();
+        // Show the new shader even if paused
+        self.canvas_reallocated = true;
+    }
+
+    /// Load the images of the texture channels and bind them for the next shader
+    pub fn set_textures(&mut self, channels: &[TextureChannel]) -> Result<()> {
+        if channels.is_empty() {
+            self.keyboard_texs.clear();
+            self.textures_bgl = None;
+            self.textures_bg = None;
+            self.channel_bindings.clear();
+            self.textures_memory = 0;
+            return Ok(());
+        }
+
+        let mut memory = 0;
+
+        // Only replaced once every channel loaded, the previous shader keeps running on error
+        let mut keyboard_texs = Vec::new();
+        let mut bindings = Vec::with_capacity(channels.len());
+        for (i, channel) in channels.iter().enumerate() {
+            if channels[..i].iter().any(|it| it.index == channel.index) {
+                return Err(anyhow::anyhow!(
+                    "Texture channel {} is declared multiple times",
+                    channel.index
+                ));
+            }
+
+            let format = if channel.srgb {
+                TextureFormat::Rgba8UnormSrgb
+            } else {
+                TextureFormat::Rgba8Unorm
+            };
+            let texture = match &channel.source {
+                ChannelSource::Image(path) => {
+                    self.create_image_texture(slice::from_ref(path), false, format, &mut memory)?
+                }
+                ChannelSource::Cubemap(faces) => {
+                    self.create_image_texture(faces, true, format, &mut memory)?
+                }
+                ChannelSource::Keyboard => {
+                    memory += (KEYBOARD_TEX_WIDTH * KEYBOARD_TEX_HEIGHT) as u64;
+                    self.device.create_texture(&TextureDescriptor {
+                        label: Some("keyboard texture"),
+                        size: Extent3d {
+                            width: KEYBOARD_TEX_WIDTH,
+                            height: KEYBOARD_TEX_HEIGHT,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: TextureDimension::D2,
+                        format: TextureFormat::R8Unorm,
+                        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    })
+                }
+            };
+            let view = texture.create_view(&TextureViewDescriptor {
+                dimension: Some(channel.source.view_dimension()),
+                ..Default::default()
+            });
+            if let ChannelSource::Keyboard = channel.source {
+                keyboard_texs.push(texture);
+            }
+            bindings.push(ChannelBinding {
+                index: channel.index,
+                view,
+                wrap: channel.wrap,
+                filter: channel.filter,
+                sampler: Self::create_channel_sampler(&self.device, channel.wrap, channel.filter),
+            });
+        }
+
+        self.textures_bgl = Some(self.device.create_bind_group_layout(
+            &BindGroupLayoutDescriptor {
+                label: Some("textures bind group layout"),
+                entries: &channel_layout_entries(channels),
+            },
+        ));
+        self.channel_bindings = bindings;
+        self.keyboard_texs = keyboard_texs;
+        self.update_textures_bg();
+        self.textures_memory = memory;
+        Ok(())
+    }
+
+    /// Bind a zeroed storage buffer of this size for the next shader, 0 removes it
+    pub fn set_storage(&mut self, size: u64) {
+        self.storage_size = size;
+        if size == 0 {
+            self.storage_buffer = None;
+            self.storage_bgl = None;
+            self.storage_bg = None;
+            return;
+        }
+        // Buffers are zeroed on creation
+        let buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("storage buffer"),
+            size,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let layout = self
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("storage bind group layout"),
+                entries: &storage_layout_entries(size),
+            });
+        self.storage_bg = Some(self.device.create_bind_group(&BindGroupDescriptor {
+            label: Some("storage bind group"),
+            layout: &layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        }));
+        self.storage_bgl = Some(layout);
+        self.storage_buffer = Some(buffer);
+    }
+
+    /// Change the filter of a texture channel without reloading its image
+    pub fn set_texture_filter(&mut self, index: u32, filter: FilterMode) {
+        let device = &self.device;
+        if let Some(binding) = self
+            .channel_bindings
+            .iter_mut()
+            .find(|it| it.index == index && it.filter != filter)
+        {
+            binding.filter = filter;
+            binding.sampler = Self::create_channel_sampler(device, binding.wrap, filter);
+            self.update_textures_bg();
+            // Show the change even if paused
+            self.canvas_reallocated = true;
+        }
+    }
+
+    fn create_channel_sampler(device: &Device, wrap: AddressMode, filter: FilterMode) -> Sampler {
+        device.create_sampler(&SamplerDescriptor {
+            label: Some("texture channel sampler"),
+            address_mode_u: wrap,
+            address_mode_v: wrap,
+            address_mode_w: wrap,
+            mag_filter: filter,
+            min_filter: filter,
+            ..Default::default()
+        })
+    }
+
+    fn update_textures_bg(&mut self) {
+        let layout = match self.textures_bgl.as_ref() {
+            Some(layout) => layout,
+            None => return,
+        };
+        let entries: Vec<BindGroupEntry> = self
+            .channel_bindings
+            .iter()
+            .flat_map(|binding| {
+                [
+                    BindGroupEntry {
+                        binding: 2 * binding.index,
+                        resource: BindingResource::TextureView(&binding.view),
+                    },
+                    BindGroupEntry {
+                        binding: 2 * binding.index + 1,
+                        resource: BindingResource::Sampler(&binding.sampler),
+                    },
+                ]
+            })
+            .collect();
+        self.textures_bg = Some(self.device.create_bind_group(&BindGroupDescriptor {
+            label: Some("textures bind group"),
+            layout,
+            entries: &entries,
+        }));
+    }
+
+    /// Upload the keyboard state to the keyboard channels
+    pub fn update_keyboard(&self, data: &[u8]) {
+        for texture in &self.keyboard_texs {
+            self.queue.write_texture(
+                ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: Origin3d::ZERO,
+                    aspect: TextureAspect::All,
+                },
+                data,
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(KEYBOARD_TEX_WIDTH),
+                    rows_per_image: NonZeroU32::new(KEYBOARD_TEX_HEIGHT),
+                },
+                Extent3d {
+                    width: KEYBOARD_TEX_WIDTH,
+                    height: KEYBOARD_TEX_HEIGHT,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+
+    /// Create a texture with one layer per image, all images must have the same size
+    /// and be square for a `cube`. Its size in bytes is added to `memory`.
+    fn create_image_texture(
+        &self,
+        paths: &[PathBuf],
+        cube: bool,
+        format: TextureFormat,
+        memory: &mut u64,
+    ) -> Result<Texture> {
+        let max_dimension = self.device.limits().max_texture_dimension_2d;
+        let mut size = None;
+        let mut data = Vec::new();
+        for path in paths {
+            let image = image::open(path)
+                .with_context(|| format!("Can't load texture {}", path.display()))?
+                .to_rgba8();
+            let (width, height) = image.dimensions();
+            if width > max_dimension || height > max_dimension {
+                return Err(anyhow::anyhow!(
+                    "Texture {} is {}x{}, the device supports at most {}x{}",
+                    path.display(),
+                    width,
+                    height,
+                    max_dimension,
+                    max_dimension
+                ));
+            }
+            if cube && width != height {
+                return Err(anyhow::anyhow!(
+                    "Cubemap face {} isn't square ({}x{})",
+                    path.display(),
+                    width,
+                    height
+                ));
+            }
+            if *size.get_or_insert(image.dimensions()) != image.dimensions() {
+                return Err(anyhow::anyhow!(
+                    "Texture {} doesn't have the same size as the others",
+                    path.display()
+                ));
+            }
+            data.extend_from_slice(&image);
+        }
+        let (width, height) = size.unwrap_or((1, 1));
+        *memory += data.len() as u64;
+
+        Ok(self.device.create_texture_with_data(
+            &self.queue,
+            &TextureDescriptor {
+                label: Some("texture channel"),
+                size: Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: paths.len() as u32,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            },
+            &data,
+        ))
+    }
+
+    /// Estimate of the gpu memory taken by the textures and buffers allocated for the shader.
+    /// wgpu doesn't report it, this is computed from their sizes and formats.
+    pub fn memory_estimate(&self) -> u64 {
+        let pixels = self.render_size.x as u64 * self.render_size.y as u64;
+        let canvas = pixels * self.render_format().describe().block_size as u64;
+        let display = if self.display_tex.is_some() {
+            pixels * self.format.describe().block_size as u64
+        } else {
+            0
+        };
+        let target = pixels * TARGETS_FORMAT.describe().block_size as u64;
+        let feedback = self.feedback.as_ref().map_or(0, |it| {
+            it.textures.len() as u64 * canvas + it.targets.len() as u64 * target
+        });
+        let extra_targets = self.extra_targets.len() as u64 * target;
+        let sanitize = if self.sanitize.is_some() { canvas } else { 0 };
+        // Rgba16Float sum of the sub-frames
+        let accumulate = if self.accumulate.is_some() {
+            pixels * 8
+        } else {
+            0
+        };
+        canvas
+            + display
+            + extra_targets
+            + feedback
+            + sanitize
+            + accumulate
+            + self.textures_memory
+            + self.storage_size
+            + self.params_buffer_size
+    }
+
+    /// Fails naming the first feature required by a shader the device doesn't have
+    pub fn check_features(&self, names: &[String]) -> Result<()> {
+        nuance::check_features(names, self.device.features(), &self.adapter_info().name)
+    }
+
+    /// true if the device can draw the shader triangle in wireframe
+    pub fn supports_wireframe(&self) -> bool {
+        self.device
+            .features()
+            .contains(Features::NON_FILL_POLYGON_MODE)
+    }
+
+    /// true if the device can measure the gpu time of the shader pass
+    pub fn supports_gpu_timing(&self) -> bool {
+        self.device.features().contains(Features::TIMESTAMP_QUERY)
+    }
+
+    /// Measure the gpu time of each shader pass, does nothing if it isn't supported.
+    /// Measuring waits for the gpu at the end of each frame.
+    pub fn set_gpu_timing(&mut self, enabled: bool) {
+        if !enabled {
+            self.gpu_timer = None;
+            self.gpu_time = None;
+        } else if self.gpu_timer.is_none() && self.supports_gpu_timing() {
+            self.gpu_timer = Some(GpuTimer::new(&self.device, &self.queue));
+        }
+    }
+
+    /// Gpu time of the last measured shader pass
+    pub fn gpu_time(&self) -> Option<Duration> {
+        self.gpu_time
+    }
+
+    /// Dither the canvas when it gets converted for display. 8 bit canvases would already be
+    /// quantized by the shader pass, they are rendered in a float texture while dithering.
+    /// Returns true when the canvas got reallocated along with the shader passes.
+    pub fn set_dither(&mut self, dither: bool) -> bool {
+        if dither == self.dither {
+            return false;
+        }
+        let format = self.render_format();
+        self.dither = dither;
+        if self.render_format() == format {
+            return false;
+        }
+        self.resize_inner_canvas(self.render_size);
+        self.recreate_rpasses();
+        true
+    }
+
+    /// Format of the canvas texture, the canvas format unless an 8 bit canvas is dithered
+    fn render_format(&self) -> TextureFormat {
+        if self.dither && !Self::is_hdr(self.canvas_format) {
+            TextureFormat::Rgba16Float
+        } else {
+            self.canvas_format
+        }
+    }
+
+    /// Show NaN and infinite pixels in magenta before they get displayed or fed back to the shader
+    pub fn set_sanitize(&mut self, sanitize: bool) {
+        if sanitize != self.sanitize.is_some() {
+            self.sanitize = sanitize.then(|| self.create_sanitize_pass());
+        }
+    }
+
+    /// Only float canvases can hold NaN and infinite values
+    pub fn can_sanitize(&self) -> bool {
+        Self::is_hdr(self.render_format())
+    }
+
+    fn create_accumulate_pass(&self) -> AccumulatePass {
+        AccumulatePass::new(
+            &self.device,
+            &self.render_tex,
+            Extent3d {
+                width: self.render_size.x,
+                height: self.render_size.y,
+                depth_or_array_layers: 1,
+            },
+            self.render_format(),
+        )
+    }
+
+    fn create_sanitize_pass(&self) -> SanitizePass {
+        SanitizePass::new(
             &self.device,
-            &module,
-            &self.last_render_tex_bgl,
-            push_constant_size,
+            &self.render_tex,
+            Extent3d {
+                width: self.render_size.x,
+                height: self.render_size.y,
+                depth_or_array_layers: 1,
+            },
+            self.render_format(),
+        )
+    }
+
+    /// Read back the canvas pixel at this position (from the top left) after each frame,
+    /// None to disable. Reading waits for the gpu at the end of each frame.
+    pub fn set_probe(&mut self, pos: Option<Vector2<u32>>) {
+        match pos {
+            Some(pos) => {
+                if self.probe.is_none() {
+                    self.probe = Some(PixelProbe::new(&self.device));
+                }
+                self.probe_pos = pos;
+            }
+            None => {
+                self.probe = None;
+                self.probed = None;
+            }
+        }
+    }
+
+    /// Linear rgba value of the last probed pixel
+    pub fn probed(&self) -> Option<[f32; 4]> {
+        self.probed
+    }
+
+    /// Draw the fullscreen triangle in wireframe, does nothing if it isn't supported.
+    pub fn set_wireframe(&mut self, wireframe: bool) {
+        let polygon_mode = if wireframe && self.supports_wireframe() {
+            PolygonMode::Line
+        } else {
+            PolygonMode::Fill
+        };
+        if polygon_mode != self.polygon_mode {
+            self.polygon_mode = polygon_mode;
+            self.recreate_rpasses();
+        }
+    }
+
+    pub fn blend_mode(&self) -> BlendMode {
+        self.blend_mode
+    }
+
+    /// Change how the shader output is blended with the cleared canvas, recreates the pipeline
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        if blend_mode != self.blend_mode {
+            self.blend_mode = blend_mode;
+            self.recreate_rpasses();
+        }
+    }
+
+    /// Whether targets of this format can be blended. Non filterable formats like Rgba32Float
+    /// aren't blendable either.
+    pub fn can_blend(&self, format: TextureFormat) -> bool {
+        self.adapter.get_texture_format_features(format).filterable
+    }
+
+    /// The blend mode of pipelines drawing to this format, the output replaces the content of
+    /// formats that can't be blended
+    fn blend_mode_for(&self, format: TextureFormat) -> BlendMode {
+        if self.can_blend(format) {
+            self.blend_mode
+        } else {
+            BlendMode::Replace
+        }
+    }
+
+    /// Nearest filtering keeps the pixels of low resolution shaders sharp when the canvas is scaled
+    pub fn set_canvas_filter(&mut self, filter: FilterMode) {
+        if filter != self.canvas_filter {
+            self.canvas_filter = filter;
+            self.update_canvas_texture();
+        }
+    }
+
+    /// The texture to display the canvas in the ui, stays valid across canvas reallocations
+    pub fn canvas_texture_id(&self) -> TextureId {
+        self.canvas_texture_id
+    }
+
+    /// All the adapters, in the order used to choose one by index
+    pub fn adapters(&self) -> &[AdapterInfo] {
+        &self.adapters_info
+    }
+
+    pub fn adapter_info(&self) -> AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    /// Format of the window surface
+    pub fn surface_format(&self) -> TextureFormat {
+        self.format
+    }
+
+    pub fn canvas_format(&self) -> TextureFormat {
+        self.canvas_format
+    }
+
+    /// Change the format of the canvas the shader renders to.
+    /// If it differs from the swapchain format, the canvas is tonemapped before being displayed.
+    pub fn set_canvas_format(&mut self, format: TextureFormat) -> Result<()> {
+        let required = TextureUsages::RENDER_ATTACHMENT
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC
+            | TextureUsages::COPY_DST;
+        let features = self.adapter.get_texture_format_features(format);
+        if !features.allowed_usages.contains(required) {
+            return Err(anyhow::anyhow!(
+                "Format {:?} can't be used for the canvas on this adapter",
+                format
+            ));
+        }
+
+        self.canvas_format = format;
+        self.resize_inner_canvas(self.render_size);
+        self.recreate_rpasses();
+        Ok(())
+    }
+
+    /// A pipeline is built for the current shader
+    pub fn has_pipeline(&self) -> bool {
+        self.shader_rpass.is_some()
+    }
+
+    /// Draw the shader once to a 1x1 texture, the driver compiles the pipeline now instead of
+    /// stuttering on the first frame. The storage buffer is stood in for by a scratch one so its
+    /// state isn't touched. Returns the time taken, waiting for the gpu.
+    pub fn warm_up(&mut self) -> Duration {
+        let start = Instant::now();
+        if let Some(rpass) = self.shader_rpass.as_mut() {
+            rpass.update_globals(&self.queue, &[vec![0; self.push_constants_size as usize]]);
+        }
+        let shader_rpass = match self.shader_rpass.as_ref() {
+            Some(rpass) => rpass,
+            None => return Duration::ZERO,
+        };
+        let size = Vector2::from([1, 1]);
+        let target = Self::create_render_tex(&self.device, size, self.render_format());
+        let target_view = target.create_view(&TextureViewDescriptor::default());
+        let extra_targets = self.create_extra_targets(size);
+        let extra_target_views: Vec<TextureView> = extra_targets
+            .iter()
+            .map(|it| it.create_view(&TextureViewDescriptor::default()))
+            .collect();
+        let scratch_storage = self.storage_bgl.as_ref().map(|layout| {
+            let buffer = self.device.create_buffer(&BufferDescriptor {
+                label: Some("warm up storage buffer"),
+                size: self.storage_size,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+            let bind_group = self.device.create_bind_group(&BindGroupDescriptor {
+                label: Some("warm up storage bind group"),
+                layout,
+                entries: &[BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+            });
+            (buffer, bind_group)
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("warm up"),
+            });
+        shader_rpass.execute(
+            &mut encoder,
+            &target_view,
+            &extra_target_views,
+            0,
+            self.feedback.as_ref().map(Feedback::bind_group),
+            self.textures_bg.as_ref(),
+            scratch_storage.as_ref().map(|(_, bind_group)| bind_group),
+            None,
+            true,
+        );
+        self.queue.submit(Some(encoder.finish()));
+        self.device.poll(Maintain::Wait);
+        start.elapsed()
+    }
+
+    /// Set the shader compared with the main one, it must use the same params, textures and
+    /// feedback. None removes it.
+    pub fn set_compare_shader(&mut self, shader: Option<(ShaderSource, u64, ShaderStages)>) {
+        self.compare = shader.map(|(source, params_buffer_size, globals_stages)| Compare {
+            module: self.device.create_shader_module(&ShaderModuleDescriptor {
+                label: Some("nuance compared fragment shader"),
+                source,
+            }),
+            rpass: None,
             params_buffer_size,
-            self.format,
-        ));
-        self.shader_module = Some(module);
+            globals_stages,
+        });
+        self.recreate_rpasses();
+    }
+
+    /// Draw the compared shader right of this fraction of the canvas width, None to only draw
+    /// the main shader
+    pub fn set_compare_split(&mut self, split: Option<f32>) {
+        if split != self.compare_split {
+            self.compare_split = split;
+            // The canvas must show the new split even if paused
+            self.canvas_reallocated = true;
+        }
+    }
+
+    /// Create the render passes for the current shader modules with the current settings
+    fn recreate_rpasses(&mut self) {
+        self.shader_rpass = self
+            .shader_module
+            .as_ref()
+            .map(|module| self.create_rpass(module, self.params_buffer_size, self.globals_stages));
+        if let Some(compare) = self.compare.as_ref() {
+            let rpass = self.create_rpass(
+                &compare.module,
+                compare.params_buffer_size,
+                compare.globals_stages,
+            );
+            self.compare.as_mut().unwrap().rpass = Some(rpass);
+        }
+    }
+
+    fn create_rpass(
+        &self,
+        module: &ShaderModule,
+        params_buffer_size: u64,
+        globals_stages: ShaderStages,
+    ) -> ShaderRenderPass {
+        ShaderRenderPass::new(
+            &self.device,
+            &self.vertex_module,
+            module,
+            self.feedback.as_ref().map(|_| &self.last_render_tex_bgl),
+            self.textures_bgl.as_ref(),
+            self.storage_bgl.as_ref(),
+            self.globals_mode,
+            globals_stages,
+            self.push_constants_size,
+            params_buffer_size,
+            self.render_format(),
+            self.targets,
+            &self.kept_targets,
+            self.polygon_mode,
+            self.blend_mode_for(self.render_format()),
+        )
+    }
+
+    /// Parts of the canvas of the main shader and of the compared one, if both are drawn
+    fn compare_split(&self) -> Option<(Scissor, Scissor, &ShaderRenderPass)> {
+        let split = self.compare_split?;
+        let rpass = self.compare.as_ref()?.rpass.as_ref()?;
+        let Vector2 {
+            x: width,
+            y: height,
+        } = self.render_size;
+        let split_x = (split.clamp(0.0, 1.0) * width as f32).round() as u32;
+        Some((
+            Scissor {
+                x: 0,
+                y: 0,
+                width: split_x,
+                height,
+            },
+            Scissor {
+                x: split_x,
+                y: 0,
+                width: width - split_x,
+                height,
+            },
+            rpass,
+        ))
     }
 
+    /// The params buffer is only uploaded when given, pass it when the params changed.
+    /// The shader runs once per push constants and the runs are averaged.
     pub fn render(
         &mut self,
         screen_desc: &ScreenDescriptor,
         gui: (&egui::Texture, &[ClippedMesh]),
-        params_buffer: &[u8],
-        push_constants: &[u8],
+        params_buffer: Option<&[u8]>,
+        push_constants: &[Vec<u8>],
         should_render: bool,
     ) -> Result<()> {
         puffin::profile_function!();
@@ -175,7 +1089,17 @@ impl Renderer {
         let mut _profiler_scope = ProfilerScope::new("init", puffin::short_file_name(file!()), "");
 
         // We use double buffering, so select the output texture
-        let frame = self.surface.get_current_frame()?.output;
+        let frame = match self.surface.get_current_frame() {
+            Ok(frame) => frame.output,
+            // The display went to sleep or changed, this frame is skipped
+            Err(SurfaceError::Lost) | Err(SurfaceError::Outdated) => {
+                debug!("Surface lost, configuring it again");
+                self.resize(self.surface_size);
+                return Ok(());
+            }
+            Err(SurfaceError::Timeout) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
         let view_desc = TextureViewDescriptor::default();
 
         // This pack a set of render passes for the gpu to execute
@@ -184,19 +1108,120 @@ impl Renderer {
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
 
         let render_tex_view = self.render_tex.create_view(&view_desc);
+        let extra_target_views: Vec<TextureView> = self
+            .extra_targets
+            .iter()
+            .map(|it| it.create_view(&TextureViewDescriptor::default()))
+            .collect();
 
         mem::drop(_profiler_scope);
 
+        // A reallocated canvas is empty, render it once even if paused so the ui doesn't show garbage
+        let should_render = should_render || mem::take(&mut self.canvas_reallocated);
+
+        if let (Some(shader_rpass), Some(params_buffer)) =
+            (self.shader_rpass.as_mut(), params_buffer)
+        {
+            shader_rpass.update_buffers(&self.queue, params_buffer);
+        }
+        if let (Some(compare), Some(params_buffer)) = (self.compare.as_mut(), params_buffer) {
+            if let Some(rpass) = compare.rpass.as_mut() {
+                rpass.update_buffers(&self.queue, params_buffer);
+            }
+        }
+        if let Some(shader_rpass) = self.shader_rpass.as_mut() {
+            shader_rpass.update_globals(&self.queue, push_constants);
+        }
+        if let Some(rpass) = self.compare.as_mut().and_then(|it| it.rpass.as_mut()) {
+            rpass.update_globals(&self.queue, push_constants);
+        }
+
+        // Only measure frames where the shader runs
+        let gpu_timer = self.gpu_timer.as_ref().filter(|_| should_render);
+
+        // One set of globals per sub-frame, they are averaged
+        let subframes = push_constants.len();
+        if (subframes > 1) != self.accumulate.is_some() {
+            self.accumulate = (subframes > 1).then(|| self.create_accumulate_pass());
+        }
+
         if should_render {
             if let Some(shader_rpass) = self.shader_rpass.as_ref() {
                 puffin::profile_scope!("shader render pass");
-                shader_rpass.update_buffers(&self.queue, params_buffer);
-                shader_rpass.execute(
-                    &mut encoder,
-                    &render_tex_view,
-                    push_constants,
-                    &self.last_render_tex_bg,
+                if let Some(timer) = gpu_timer {
+                    timer.begin(&mut encoder);
+                }
+                let feedback = self.feedback.as_ref().map(Feedback::bind_group);
+                for i in 0..subframes {
+                    match self.compare_split() {
+                        Some((main_scissor, compare_scissor, compare_rpass)) => {
+                            // Empty scissors aren't allowed, a side can take the whole canvas
+                            if main_scissor.width > 0 {
+                                shader_rpass.execute(
+                                    &mut encoder,
+                                    &render_tex_view,
+                                    &extra_target_views,
+                                    i,
+                                    feedback,
+                                    self.textures_bg.as_ref(),
+                                    self.storage_bg.as_ref(),
+                                    Some(main_scissor),
+                                    true,
+                                );
+                            }
+                            if compare_scissor.width > 0 {
+                                compare_rpass.execute(
+                                    &mut encoder,
+                                    &render_tex_view,
+                                    &extra_target_views,
+                                    i,
+                                    feedback,
+                                    self.textures_bg.as_ref(),
+                                    self.storage_bg.as_ref(),
+                                    Some(compare_scissor),
+                                    main_scissor.width == 0,
+                                );
+                            }
+                        }
+                        None => shader_rpass.execute(
+                            &mut encoder,
+                            &render_tex_view,
+                            &extra_target_views,
+                            i,
+                            feedback,
+                            self.textures_bg.as_ref(),
+                            self.storage_bg.as_ref(),
+                            None,
+                            true,
+                        ),
+                    }
+                    if let Some(accumulate) = self.accumulate.as_ref() {
+                        accumulate.accumulate(&mut encoder, 1.0 / subframes as f64, i == 0);
+                    }
+                }
+                if let Some(accumulate) = self.accumulate.as_ref() {
+                    accumulate.resolve(&mut encoder, &render_tex_view);
+                }
+                if let Some(timer) = gpu_timer {
+                    timer.end(&mut encoder);
+                }
+            }
+            if let Some(sanitize) = self.sanitize.as_ref() {
+                puffin::profile_scope!("sanitize render pass");
+                sanitize.execute(&mut encoder, &self.render_tex);
+            }
+            if let (Some(post_pass), Some(display_tex)) =
+                (self.post_pass.as_ref(), self.display_tex.as_ref())
+            {
+                puffin::profile_scope!("post render pass");
+                post_pass.update_settings(
+                    &self.queue,
+                    &PostSettings {
+                        tonemap: Self::is_hdr(self.canvas_format) as u32,
+                        dither: self.dither as u32,
+                    },
                 );
+                post_pass.execute(&mut encoder, &display_tex.create_view(&view_desc));
             }
         }
 
@@ -204,8 +1229,7 @@ impl Renderer {
         {
             puffin::profile_scope!("egui render pass");
 
-            self.egui_rpass
-                .update_texture(&self.device, &self.queue, gui.0);
+            self.upload_font_texture(gui.0);
             self.egui_rpass
                 .update_user_textures(&self.device, &self.queue);
             self.egui_rpass
@@ -221,8 +1245,8 @@ impl Renderer {
             )?;
         }
 
-        if should_render {
-            // Copy our rendered texture to the last rendered
+        if let (true, Some(feedback)) = (should_render, self.feedback.as_mut()) {
+            // Copy our rendered texture over the oldest frame, it becomes the last one
             encoder.copy_texture_to_texture(
                 ImageCopyTexture {
                     texture: &self.render_tex,
@@ -231,7 +1255,7 @@ impl Renderer {
                     aspect: TextureAspect::All,
                 },
                 ImageCopyTexture {
-                    texture: &self.last_render_tex,
+                    texture: feedback.advance(),
                     mip_level: 0,
                     origin: Origin3d::ZERO,
                     aspect: TextureAspect::All,
@@ -242,16 +1266,59 @@ impl Renderer {
                     depth_or_array_layers: 1,
                 },
             );
+            for (target, last) in self.extra_targets.iter().zip(&feedback.targets) {
+                encoder.copy_texture_to_texture(
+                    ImageCopyTexture {
+                        texture: target,
+                        mip_level: 0,
+                        origin: Origin3d::ZERO,
+                        aspect: TextureAspect::All,
+                    },
+                    ImageCopyTexture {
+                        texture: last,
+                        mip_level: 0,
+                        origin: Origin3d::ZERO,
+                        aspect: TextureAspect::All,
+                    },
+                    Extent3d {
+                        width: self.render_size.x,
+                        height: self.render_size.y,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        let probe_pos = self.probe_pos;
+        let probe = self
+            .probe
+            .as_ref()
+            .filter(|_| probe_pos.x < self.render_size.x && probe_pos.y < self.render_size.y);
+        if let Some(probe) = probe {
+            probe.copy(&mut encoder, &self.render_tex, probe_pos);
         }
 
         // Launch !
         self.queue.submit(Some(encoder.finish()));
+
+        if let (Some(timer), Some(_)) = (gpu_timer, self.shader_rpass.as_ref()) {
+            puffin::profile_scope!("gpu timer readback");
+            self.gpu_time = Some(timer.read(&self.device)?);
+        }
+
+        if let Some(probe) = probe {
+            puffin::profile_scope!("pixel probe readback");
+            self.probed = Some(probe.read(&self.device, self.render_format())?);
+        }
+
         Ok(())
     }
 
+    /// Render a single frame in the given format and read it back
     pub fn render_to_buffer(
         &self,
         render_size: Vector2<u32>,
+        format: TextureFormat,
         params_buffer: &[u8],
         push_constants: &[u8],
         consume: impl FnOnce(BufferView) -> Result<()>,
@@ -271,12 +1338,13 @@ impl Renderer {
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
-            format: self.format,
+            format,
             usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
         };
         let render_tex = self.device.create_texture(&render_tex_desc);
 
-        let output_buffer_size = (4 * render_size.x * render_size.y) as BufferAddress;
+        let bytes_per_pixel = format.describe().block_size as u32;
+        let output_buffer_size = (bytes_per_pixel * render_size.x * render_size.y) as BufferAddress;
         let output_buffer_desc = BufferDescriptor {
             size: output_buffer_size,
             usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
@@ -285,16 +1353,31 @@ impl Renderer {
         };
         let output_buffer = self.device.create_buffer(&output_buffer_desc);
 
-        let shader_rpass = ShaderRenderPass::new(
+        let mut shader_rpass = ShaderRenderPass::new(
             &self.device,
+            &self.vertex_module,
             self.shader_module.as_ref().unwrap(),
-            &self.last_render_tex_bgl,
+            self.feedback.as_ref().map(|_| &self.last_render_tex_bgl),
+            self.textures_bgl.as_ref(),
+            self.storage_bgl.as_ref(),
+            self.globals_mode,
+            self.globals_stages,
             push_constants.len() as u32,
             params_buffer.len() as u64,
-            self.format,
+            format,
+            self.targets,
+            &self.kept_targets,
+            PolygonMode::Fill,
+            self.blend_mode_for(format),
         );
 
         let render_tex_view = render_tex.create_view(&TextureViewDescriptor::default());
+        // Only the first target is exported, the others still need to be attached
+        let extra_targets = self.create_extra_targets(render_size);
+        let extra_target_views: Vec<TextureView> = extra_targets
+            .iter()
+            .map(|it| it.create_view(&TextureViewDescriptor::default()))
+            .collect();
 
         // This pack a set of render passes for the gpu to execute
         let mut encoder = self
@@ -304,11 +1387,17 @@ impl Renderer {
             });
 
         shader_rpass.update_buffers(&self.queue, params_buffer);
+        shader_rpass.update_globals(&self.queue, &[push_constants.to_vec()]);
         shader_rpass.execute(
             &mut encoder,
             &render_tex_view,
-            push_constants,
-            &self.last_render_tex_bg,
+            &extra_target_views,
+            0,
+            self.feedback.as_ref().map(Feedback::bind_group),
+            self.textures_bg.as_ref(),
+            self.storage_bg.as_ref(),
+            None,
+            true,
         );
 
         encoder.copy_texture_to_buffer(
@@ -322,7 +1411,7 @@ impl Renderer {
                 buffer: &output_buffer,
                 layout: ImageDataLayout {
                     offset: 0,
-                    bytes_per_row: NonZeroU32::new(4 * render_size.x),
+                    bytes_per_row: NonZeroU32::new(bytes_per_pixel * render_size.x),
                     rows_per_image: NonZeroU32::new(render_size.y),
                 },
             },
@@ -345,35 +1434,129 @@ impl Renderer {
 
     pub fn resize_inner_canvas(&mut self, size: Vector2<u32>) {
         self.render_size = size;
-        self.render_tex = Self::create_render_tex(&self.device, size, self.format);
-        let temp = Self::create_last_render_tex(&self.device, size, self.format);
-        self.last_render_tex = temp.0;
-        self.last_render_tex_bgl = temp.1;
-        self.last_render_tex_bg = temp.2;
+        self.render_tex = Self::create_render_tex(&self.device, size, self.render_format());
+        self.extra_targets = self.create_extra_targets(size);
+        self.update_feedback(true);
+        if self.sanitize.is_some() {
+            self.sanitize = Some(self.create_sanitize_pass());
+        }
+        if self.accumulate.is_some() {
+            self.accumulate = Some(self.create_accumulate_pass());
+        }
+
+        if self.render_format() == self.format {
+            self.display_tex = None;
+            self.post_pass = None;
+        } else {
+            let display_tex = Self::create_render_tex(&self.device, size, self.format);
+            match self.post_pass.as_mut() {
+                Some(post_pass) => post_pass.set_input(&self.device, &self.render_tex),
+                None => {
+                    self.post_pass =
+                        Some(PostPass::new(&self.device, &self.render_tex, self.format))
+                }
+            }
+            self.display_tex = Some(display_tex);
+        }
 
+        self.update_canvas_texture();
+        self.canvas_reallocated = true;
+    }
+
+    /// Upload the egui font texture when it changed. egui rebuilds its fonts with a new atlas when
+    /// the scale changes and the version of the new atlas starts over, the backend only compares
+    /// versions and would keep the old atlas when they happen to match.
+    fn upload_font_texture(&mut self, texture: &egui::Texture) {
+        let key = (
+            texture.version,
+            texture.width,
+            texture.height,
+            texture.pixels.as_ptr() as usize,
+        );
+        if self
+            .font_texture
+            .map_or(false, |it| it != key && it.0 == key.0)
+        {
+            // Another version forces the backend to upload the next one
+            self.egui_rpass.update_texture(
+                &self.device,
+                &self.queue,
+                &egui::Texture {
+                    version: !texture.version,
+                    width: 1,
+                    height: 1,
+                    pixels: vec![0],
+                },
+            );
+        }
+        self.font_texture = Some(key);
+        self.egui_rpass
+            .update_texture(&self.device, &self.queue, texture);
+    }
+
+    /// Point the ui canvas texture to the displayed texture with the current filter
+    fn update_canvas_texture(&mut self) {
         self.egui_rpass
             .update_egui_texture_from_wgpu_texture(
                 &self.device,
-                &self.render_tex,
-                FilterMode::Linear,
-                TextureId::User(0),
+                self.display_tex.as_ref().unwrap_or(&self.render_tex),
+                self.canvas_filter,
+                self.canvas_texture_id,
             )
-            .expect("Can't update canvas texture following resize");
+            .expect("Can't update canvas texture");
+    }
+
+    /// Wait for the display when presenting, this caps the framerate to its refresh rate
+    pub fn set_vsync(&mut self, vsync: bool) {
+        let present_mode = if vsync {
+            PresentMode::Fifo
+        } else {
+            PresentMode::Mailbox
+        };
+        if present_mode != self.present_mode {
+            self.present_mode = present_mode;
+            self.resize(self.surface_size);
+        }
     }
 
     pub fn resize(&mut self, size: Vector2<u32>) {
-        self.surface.configure(
+        self.surface_size = size;
+        Self::configure_surface(
+            &self.surface,
             &self.device,
+            self.format,
+            size,
+            self.present_mode,
+        );
+    }
+
+    fn configure_surface(
+        surface: &Surface,
+        device: &Device,
+        format: TextureFormat,
+        size: Vector2<u32>,
+        present_mode: PresentMode,
+    ) {
+        surface.configure(
+            device,
             &SurfaceConfiguration {
                 usage: TextureUsages::RENDER_ATTACHMENT,
-                format: self.format,
+                format,
                 width: size.x,
                 height: size.y,
-                present_mode: PresentMode::Mailbox,
+                present_mode,
             },
         );
     }
 
+    /// Float formats can hold values outside of the displayable range
+    fn is_hdr(format: TextureFormat) -> bool {
+        matches!(
+            format,
+            TextureFormat::Rgba16Float | TextureFormat::Rgba32Float | TextureFormat::Rg11b10Float
+        )
+    }
+
     fn create_render_tex(device: &Device, size: Vector2<u32>, format: TextureFormat) -> Texture {
         let render_tex_desc = TextureDescriptor {
             label: Some("shader render tex"),
@@ -388,16 +1571,50 @@ impl Renderer {
             format,
             usage: TextureUsages::RENDER_ATTACHMENT
                 | TextureUsages::TEXTURE_BINDING
-                | TextureUsages::COPY_SRC,
+                | TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST,
         };
         device.create_texture(&render_tex_desc)
     }
 
-    fn create_last_render_tex(
+    /// Outputs of the shader after the canvas, in the canvas format
+    fn create_extra_targets(&self, size: Vector2<u32>) -> Vec<Texture> {
+        (1..self.targets)
+            .map(|_| Self::create_render_tex(&self.device, size, TARGETS_FORMAT))
+            .collect()
+    }
+
+    /// Allocate the previous frame textures only when the shader samples them
+    fn update_feedback(&mut self, reallocate: bool) {
+        if !self.uses_feedback {
+            self.feedback = None;
+        } else if reallocate || self.feedback.is_none() {
+            self.feedback = Some(Self::create_feedback(
+                &self.device,
+                &self.last_render_tex_bgl,
+                self.render_size,
+                self.render_format(),
+                self.history_frames,
+                self.targets,
+            ));
+        }
+    }
+
+    fn create_last_render_tex_bgl(device: &Device, frames: u32, targets: u32) -> BindGroupLayout {
+        device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("last tex bind group layout"),
+            entries: &feedback_layout_entries(frames, targets),
+        })
+    }
+
+    fn create_feedback(
         device: &Device,
+        layout: &BindGroupLayout,
         size: Vector2<u32>,
         format: TextureFormat,
-    ) -> (Texture, BindGroupLayout, BindGroup) {
+        frames: u32,
+        targets: u32,
+    ) -> Feedback {
         let last_render_tex_desc = TextureDescriptor {
             label: Some("shader last render tex"),
             size: Extent3d {
@@ -411,7 +1628,34 @@ impl Renderer {
             format,
             usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
         };
-        let last_render_tex = device.create_texture(&last_render_tex_desc);
+        let textures: Vec<Texture> = (0..frames)
+            .map(|_| device.create_texture(&last_render_tex_desc))
+            .collect();
+        let target_textures: Vec<Texture> = (1..targets)
+            .map(|_| {
+                device.create_texture(&TextureDescriptor {
+                    format: TARGETS_FORMAT,
+                    ..last_render_tex_desc.clone()
+                })
+            })
+            .collect();
+        let views: Vec<TextureView> = textures
+            .iter()
+            .chain(&target_textures)
+            .map(|texture| {
+                texture.create_view(&TextureViewDescriptor {
+                    label: None,
+                    // The format of the texture
+                    format: None,
+                    dimension: Some(TextureViewDimension::D2),
+                    aspect: TextureAspect::All,
+                    base_mip_level: 0,
+                    mip_level_count: None,
+                    base_array_layer: 0,
+                    array_layer_count: None,
+                })
+            })
+            .collect();
 
         let sampler = device.create_sampler(&SamplerDescriptor {
             label: Some("last render tex sampler"),
@@ -421,57 +1665,50 @@ impl Renderer {
             ..Default::default()
         });
 
-        let last_render_tex_bgl = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        sample_type: TextureSampleType::Float { filterable: false },
-                        view_dimension: TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler {
-                        filtering: false,
-                        comparison: false,
-                    },
-                    count: None,
-                },
-            ],
-        });
-
-        let last_render_tex_bg = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("last tex bind group"),
-            layout: &last_render_tex_bgl,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(&last_render_tex.create_view(
-                        &TextureViewDescriptor {
-                            label: None,
-                            format: Some(format),
-                            dimension: Some(TextureViewDimension::D2),
-                            aspect: TextureAspect::All,
-                            base_mip_level: 0,
-                            mip_level_count: None,
-                            base_array_layer: 0,
-                            array_layer_count: None,
-                        },
-                    )),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&sampler),
-                },
-            ],
-        });
+        // One bind group per position of the ring head, the newest frame is at binding 0.
+        // The extra targets only keep their last frame.
+        let bind_groups = (0..frames as usize)
+            .map(|head| {
+                let frame_views = (0..frames as usize).map(|age| {
+                    (
+                        2 * age as u32,
+                        &views[(head + frames as usize - age) % frames as usize],
+                    )
+                });
+                let target_views = (1..targets).map(|target| {
+                    (
+                        PREVIOUS_TARGETS_BINDING + 2 * (target - 1),
+                        &views[(frames + target - 1) as usize],
+                    )
+                });
+                let entries: Vec<BindGroupEntry> = frame_views
+                    .chain(target_views)
+                    .flat_map(|(binding, view)| {
+                        [
+                            BindGroupEntry {
+                                binding,
+                                resource: BindingResource::TextureView(view),
+                            },
+                            BindGroupEntry {
+                                binding: binding + 1,
+                                resource: BindingResource::Sampler(&sampler),
+                            },
+                        ]
+                    })
+                    .collect();
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("last tex bind group"),
+                    layout,
+                    entries: &entries,
+                })
+            })
+            .collect();
 
-        (last_render_tex, last_render_tex_bgl, last_render_tex_bg)
+        Feedback {
+            textures,
+            targets: target_textures,
+            bind_groups,
+            head: 0,
+        }
     }
 }
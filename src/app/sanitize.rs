@@ -0,0 +1,135 @@
+//! Pass replacing the NaN and infinite pixels of the canvas with magenta, so they are visible
+//! and don't poison the previous frames sampled by feedback shaders
+
+use wgpu::*;
+
+pub struct SanitizePass {
+    bind_group: BindGroup,
+    pipeline: RenderPipeline,
+    /// The sanitized canvas, copied back over the canvas
+    output: Texture,
+    size: Extent3d,
+}
+
+impl SanitizePass {
+    /// The pass is tied to the canvas, it is created again when the canvas changes
+    pub fn new(device: &Device, input: &Texture, size: Extent3d, format: TextureFormat) -> Self {
+        let module = device.create_shader_module(&include_wgsl!("sanitize.wgsl"));
+
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("sanitize bind group layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("sanitize pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("sanitize pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: VertexState {
+                module: &module,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                module: &module,
+                entry_point: "fs_main",
+                targets: &[ColorTargetState {
+                    format,
+                    write_mask: ColorWrites::ALL,
+                    blend: None,
+                }],
+            }),
+        });
+
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, input);
+        let output = Self::create_output(device, size, format);
+
+        Self {
+            bind_group,
+            pipeline,
+            output,
+            size,
+        }
+    }
+
+    /// Sanitize the canvas in place
+    pub fn execute(&self, encoder: &mut CommandEncoder, input: &Texture) {
+        puffin::profile_scope!("sanitize pass execute");
+
+        {
+            let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("sanitize render pass"),
+                color_attachments: &[RenderPassColorAttachment {
+                    view: &self.output.create_view(&TextureViewDescriptor::default()),
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            rpass.set_pipeline(&self.pipeline);
+            rpass.set_bind_group(0, &self.bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+
+        encoder.copy_texture_to_texture(
+            ImageCopyTexture {
+                texture: &self.output,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            ImageCopyTexture {
+                texture: input,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            self.size,
+        );
+    }
+
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, input: &Texture) -> BindGroup {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: Some("sanitize bind group"),
+            layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(
+                    &input.create_view(&TextureViewDescriptor::default()),
+                ),
+            }],
+        })
+    }
+
+    fn create_output(device: &Device, size: Extent3d, format: TextureFormat) -> Texture {
+        device.create_texture(&TextureDescriptor {
+            label: Some("sanitize output tex"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        })
+    }
+}
@@ -0,0 +1,106 @@
+//! Timelines of commands run as the shader time passes, for demo reels and regression captures.
+//!
+//! A script has one command per line, prefixed by the shader time in sec it runs at :
+//! ```text
+//! # Comments and empty lines are ignored
+//! 0 load demo.frag
+//! 2 set speed=2.5
+//! 2 set color=1,0.5,0
+//! 5 screenshot demo.png
+//! 5 quit
+//! ```
+//! The time starts over when a shader is loaded, later commands are timed from the load.
+//! Relative paths are resolved from the script directory.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::cli::ParamOverride;
+
+pub enum ScriptCommand {
+    /// Load a shader, the time starts over
+    Load(PathBuf),
+    /// Set a param, vectors and colors take multiple values
+    Set(ParamOverride),
+    /// Export an image with the export settings, the format follows the extension
+    Screenshot(PathBuf),
+    /// Close the app
+    Quit,
+}
+
+pub struct Script {
+    /// Commands left to run in order, with their time
+    commands: VecDeque<(f32, ScriptCommand)>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Can't read script {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut commands = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let command = parse_line(line, dir)
+                .with_context(|| format!("{}:{} : invalid command", path.display(), i + 1))?;
+            commands.push(command);
+        }
+        // Stable, commands at the same time keep their order
+        commands.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Ok(Self {
+            commands: commands.into(),
+        })
+    }
+
+    /// Take the next command if its time has come
+    pub fn next_due(&mut self, time: f32) -> Option<ScriptCommand> {
+        match self.commands.front() {
+            Some((at, _)) if *at <= time => self.commands.pop_front().map(|it| it.1),
+            _ => None,
+        }
+    }
+
+    /// All the commands ran
+    pub fn is_done(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// Parse `time command [argument]`
+fn parse_line(line: &str, dir: &Path) -> Result<(f32, ScriptCommand)> {
+    let (time, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim_start();
+    let (name, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let argument = Some(argument.trim()).filter(|it| !it.is_empty());
+    let time: f32 = time
+        .parse()
+        .with_context(|| format!("Invalid time '{}'", time))?;
+    if !time.is_finite() || time < 0.0 {
+        bail!("Invalid time '{}'", time);
+    }
+    if name.is_empty() {
+        bail!("Missing command");
+    }
+    let path = || {
+        argument
+            .map(|it| dir.join(it.trim_matches('"')))
+            .ok_or_else(|| anyhow!("{} expects a path", name))
+    };
+
+    let command = match name {
+        "load" => ScriptCommand::Load(path()?),
+        "set" => ScriptCommand::Set(ParamOverride::parse(
+            argument.ok_or_else(|| anyhow!("set expects name=value"))?,
+        )?),
+        "screenshot" => ScriptCommand::Screenshot(path()?),
+        "quit" => ScriptCommand::Quit,
+        other => bail!("Unknown command '{}'", other),
+    };
+    Ok((time, command))
+}
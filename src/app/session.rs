@@ -0,0 +1,97 @@
+//! Session files, saving everything needed to resume working on a shader
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use nuance::Slider;
+
+/// Bumped on incompatible changes, new optional fields don't need a bump
+pub const SESSION_VERSION: u32 = 1;
+pub const SESSION_EXTENSION: &str = "nuance";
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    /// Path or url of the main shader file
+    pub shader: PathBuf,
+    /// Param values by name, vectors and colors have multiple components
+    #[serde(default)]
+    pub params: BTreeMap<String, Vec<f32>>,
+    /// Names of the params in the order they are shown in the ui, empty for the declaration order
+    #[serde(default)]
+    pub param_order: Vec<String>,
+    /// Shader time in sec
+    #[serde(default)]
+    pub time: f32,
+    #[serde(default)]
+    pub frame: u32,
+    #[serde(default)]
+    pub mouse_wheel: f32,
+    #[serde(default)]
+    pub wheel_steps: i32,
+    #[serde(default)]
+    pub paused: bool,
+    /// Origin at the bottom left, the shader default is used when missing
+    pub flip_y: Option<bool>,
+    /// Target framerate
+    pub framerate: Option<u32>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Can't read session file {}", path.display()))?;
+        let session: Session = serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid session file {}", path.display()))?;
+        if session.version > SESSION_VERSION {
+            bail!(
+                "Session file version {} is newer than the supported version {}",
+                session.version,
+                SESSION_VERSION
+            );
+        }
+        Ok(session)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)
+            .with_context(|| format!("Can't write session file {}", path.display()))
+    }
+}
+
+/// Name and components of a param value
+pub fn slider_values(slider: &Slider) -> (&str, Vec<f32>) {
+    match slider {
+        Slider::Float { name, value, .. } => (name, vec![*value]),
+        Slider::Uint { name, value, .. } | Slider::Bool { name, value, .. } => {
+            (name, vec![*value as f32])
+        }
+        Slider::Vec2 { name, value, .. } => (name, vec![value.x, value.y]),
+        Slider::Vec3 { name, value, .. } | Slider::Color { name, value, .. } => {
+            (name, vec![value.x, value.y, value.z])
+        }
+    }
+}
+
+/// Restore a param value saved with [slider_values], mismatched values are ignored
+pub fn set_slider_values(slider: &mut Slider, values: &[f32]) {
+    match (slider, values) {
+        (Slider::Float { value, .. }, [v]) => *value = *v,
+        (Slider::Uint { value, .. }, [v]) | (Slider::Bool { value, .. }, [v]) => *value = *v as u32,
+        (Slider::Vec2 { value, .. }, [x, y]) => {
+            value.x = *x;
+            value.y = *y;
+        }
+        (Slider::Vec3 { value, .. }, [x, y, z]) | (Slider::Color { value, .. }, [x, y, z]) => {
+            value.x = *x;
+            value.y = *y;
+            value.z = *z;
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,139 @@
+//! Share strings, a session and the source of its shader packed in a single line of text to paste
+//! in another instance. Includes and textures aren't packed, only the main shader file.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::app::session::{Session, SESSION_VERSION};
+
+/// Share strings start with this so they can't be mistaken for an url or a path
+pub const SHARE_PREFIX: &str = "nuance:";
+
+/// Zlib compression level, the strings are small so the best one is cheap
+const COMPRESSION_LEVEL: u8 = 10;
+
+/// Maximum size of the decompressed json, so a crafted string can't exhaust the memory
+const MAX_JSON_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct Shared {
+    pub session: Session,
+    /// Source of the main shader file, None for shaders loaded from an url which is shared instead
+    pub source: Option<String>,
+}
+
+impl Shared {
+    /// The json of the shared state compressed with zlib and in base64, after the prefix
+    pub fn encode(&self) -> Result<String> {
+        let json = serde_json::to_vec(self)?;
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&json, COMPRESSION_LEVEL);
+        Ok(format!("{}{}", SHARE_PREFIX, base64::encode(compressed)))
+    }
+
+    pub fn decode(text: &str) -> Result<Self> {
+        let data = text
+            .trim()
+            .strip_prefix(SHARE_PREFIX)
+            .context("Not a share string")?;
+        // Whitespace is ignored so the string survives line wrapping
+        let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+        let data = base64::decode(data).context("Invalid share string")?;
+        // Strings from before the compression are plain json
+        let json = if data.starts_with(b"{") {
+            data
+        } else {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data, MAX_JSON_SIZE).map_err(
+                |status| anyhow!("Invalid share string, can't decompress : {:?}", status),
+            )?
+        };
+        let shared: Shared = serde_json::from_slice(&json).context("Invalid share string")?;
+        if shared.session.version > SESSION_VERSION {
+            bail!(
+                "Share string version {} is newer than the supported version {}",
+                shared.session.version,
+                SESSION_VERSION
+            );
+        }
+        Ok(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn shared(source: Option<&str>) -> Shared {
+        Shared {
+            session: serde_json::from_str(
+                r#"{"version": 1, "shader": "shaders/demo.frag", "params": {"radius": [0.5]}}"#,
+            )
+            .unwrap(),
+            source: source.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "void main() {\n    fragColor = vec4(1.0);\n}\n".repeat(20);
+        let encoded = shared(Some(&source)).encode().unwrap();
+        assert!(encoded.starts_with(SHARE_PREFIX));
+        assert!(!encoded.contains(char::is_whitespace));
+        // The repeated source compresses well
+        assert!(encoded.len() < source.len());
+
+        let decoded = Shared::decode(&encoded).unwrap();
+        assert_eq!(decoded.source.as_deref(), Some(source.as_str()));
+        assert_eq!(decoded.session.shader, PathBuf::from("shaders/demo.frag"));
+        assert_eq!(decoded.session.params["radius"], vec![0.5]);
+
+        let decoded = Shared::decode(&shared(None).encode().unwrap()).unwrap();
+        assert!(decoded.source.is_none());
+    }
+
+    #[test]
+    fn wrapped_string() {
+        let encoded = shared(Some("void main() {}")).encode().unwrap();
+        let (head, tail) = encoded.split_at(encoded.len() / 2);
+        let wrapped = format!("  {}\n\t{}\r\n", head, tail);
+        assert!(Shared::decode(&wrapped).is_ok());
+    }
+
+    #[test]
+    fn uncompressed_string() {
+        let json = serde_json::to_vec(&shared(Some("void main() {}"))).unwrap();
+        let encoded = format!("{}{}", SHARE_PREFIX, base64::encode(json));
+        let decoded = Shared::decode(&encoded).unwrap();
+        assert_eq!(decoded.source.as_deref(), Some("void main() {}"));
+    }
+
+    #[test]
+    fn malformed_strings() {
+        let encoded = shared(Some("void main() {}")).encode().unwrap();
+        // Missing prefix
+        assert!(Shared::decode(&encoded[SHARE_PREFIX.len()..]).is_err());
+        assert!(Shared::decode("").is_err());
+        assert!(Shared::decode(SHARE_PREFIX).is_err());
+        // Not base64
+        assert!(Shared::decode("nuance:not*base64!").is_err());
+        // Truncated
+        assert!(Shared::decode(&encoded[..encoded.len() - 8]).is_err());
+        // Base64 of something that isn't compressed json
+        let garbage = format!("{}{}", SHARE_PREFIX, base64::encode(b"garbage data"));
+        assert!(Shared::decode(&garbage).is_err());
+        let not_json = format!(
+            "{}{}",
+            SHARE_PREFIX,
+            base64::encode(miniz_oxide::deflate::compress_to_vec_zlib(b"[1, 2]", 6))
+        );
+        assert!(Shared::decode(&not_json).is_err());
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut newer = shared(None);
+        newer.session.version = SESSION_VERSION + 1;
+        assert!(Shared::decode(&newer.encode().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Gpu time measurement with timestamp queries
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::Result;
+use wgpu::*;
+
+/// Measures the gpu time of the commands recorded between `begin` and `end`
+pub struct GpuTimer {
+    query_set: QuerySet,
+    /// Resolved timestamps, read back on the cpu
+    buffer: Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+}
+
+impl GpuTimer {
+    /// The device must have been created with [Features::TIMESTAMP_QUERY]
+    pub fn new(device: &Device, queue: &Queue) -> Self {
+        let query_set = device.create_query_set(&QuerySetDescriptor {
+            label: Some("gpu timer queries"),
+            ty: QueryType::Timestamp,
+            count: 2,
+        });
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("gpu timer buffer"),
+            size: 2 * std::mem::size_of::<u64>() as BufferAddress,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        Self {
+            query_set,
+            buffer,
+            period: queue.get_timestamp_period(),
+        }
+    }
+
+    pub fn begin(&self, encoder: &mut CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 0);
+    }
+
+    pub fn end(&self, encoder: &mut CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 1);
+        encoder.resolve_query_set(&self.query_set, 0..2, &self.buffer, 0);
+    }
+
+    /// Wait for the gpu to finish the submitted work and read the elapsed time
+    pub fn read(&self, device: &Device) -> Result<Duration> {
+        let slice = self.buffer.slice(..);
+        let mapping = slice.map_async(MapMode::Read);
+        device.poll(Maintain::Wait);
+        futures_executor::block_on(mapping)?;
+
+        let elapsed = {
+            let view = slice.get_mapped_range();
+            let start = u64::from_ne_bytes(view[0..8].try_into().unwrap());
+            let end = u64::from_ne_bytes(view[8..16].try_into().unwrap());
+            end.saturating_sub(start)
+        };
+        self.buffer.unmap();
+
+        Ok(Duration::from_nanos(
+            (elapsed as f64 * self.period as f64) as u64,
+        ))
+    }
+}
@@ -0,0 +1,211 @@
+//! Headless validation of shaders, for pre-commit hooks and CI
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info};
+use wgpu::*;
+
+use nuance::builtins::{Builtins, PUSH_CONSTANTS_SIZE};
+use nuance::loader::ShaderLoader;
+use nuance::renderer::{
+    channel_layout_entries, create_vertex_module, feedback_layout_entries, storage_layout_entries,
+    BlendMode, GlobalsMode, ShaderRenderPass,
+};
+use nuance::{check_features, requirable_features, ShaderMetadata};
+
+/// Compile the given shaders and create their pipelines without opening a window.
+/// Directories are searched (non recursively) for shaders.
+/// Returns the number of shaders that failed.
+pub fn check(paths: &[String], pref_hp: bool, include_dirs: &[PathBuf]) -> Result<usize> {
+    if paths.is_empty() {
+        return Err(anyhow!("Usage : nuance check <shader or directory>..."));
+    }
+
+    let (device, device_name, errors) = futures_executor::block_on(create_device(pref_hp))?;
+    let vertex_module = create_vertex_module(&device);
+
+    // Globals too large for push constants go in a uniform buffer, like in the app
+    let builtins = Builtins::default();
+    let globals_mode = if builtins.fits_push_constants() {
+        GlobalsMode::PushConstants
+    } else {
+        GlobalsMode::UniformBuffer
+    };
+    let mut loader = ShaderLoader::new();
+    loader.set_globals_mode(globals_mode);
+    for dir in include_dirs {
+        loader.add_include_dir(dir);
+    }
+    let mut failed = 0;
+    for path in collect_shaders(paths)? {
+        let (shader, source) = match loader.load_shader(&path) {
+            Ok(it) => it,
+            Err(e) => {
+                error!("{} : {}", path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        // Same as when loading in the app, the shader fails when the device misses a feature
+        let required_features = shader
+            .metadata
+            .as_ref()
+            .map_or(&[][..], |it| it.required_features.as_slice());
+        if let Err(e) = check_features(required_features, device.features(), &device_name) {
+            error!("{} : {}", path.display(), e);
+            failed += 1;
+            continue;
+        }
+
+        let targets = shader
+            .metadata
+            .as_ref()
+            .map_or(1, ShaderMetadata::target_count);
+
+        // Stands in for the previous frame textures
+        let last_tex_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("last tex layout"),
+            entries: &feedback_layout_entries(
+                shader
+                    .metadata
+                    .as_ref()
+                    .map_or(1, ShaderMetadata::history_frames),
+                targets,
+            ),
+        });
+
+        let (params_buffer_size, textures_layout) = match shader.metadata.as_ref() {
+            Some(metadata) => (
+                metadata.params_buffer_size(),
+                if metadata.textures.is_empty() {
+                    None
+                } else {
+                    Some(device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                        label: Some("textures layout"),
+                        entries: &channel_layout_entries(&metadata.textures),
+                    }))
+                },
+            ),
+            None => (0, None),
+        };
+        let storage_size = shader
+            .metadata
+            .as_ref()
+            .map_or(0, ShaderMetadata::storage_size);
+        let storage_layout = if storage_size > 0 {
+            Some(device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("storage layout"),
+                entries: &storage_layout_entries(storage_size),
+            }))
+        } else {
+            None
+        };
+
+        let module = device.create_shader_module(&ShaderModuleDescriptor {
+            label: Some("nuance fragment shader"),
+            source,
+        });
+        ShaderRenderPass::new(
+            &device,
+            &vertex_module,
+            &module,
+            if shader.uses_feedback {
+                Some(&last_tex_layout)
+            } else {
+                None
+            },
+            textures_layout.as_ref(),
+            storage_layout.as_ref(),
+            globals_mode,
+            shader.globals_stages,
+            builtins.size(),
+            params_buffer_size,
+            TextureFormat::Bgra8UnormSrgb,
+            targets,
+            &[],
+            PolygonMode::Fill,
+            BlendMode::Replace,
+        );
+        device.poll(Maintain::Wait);
+
+        let errors: Vec<String> = errors.lock().unwrap().drain(..).collect();
+        if errors.is_empty() {
+            info!("{} : ok", path.display());
+        } else {
+            for e in errors {
+                error!("{} : {}", path.display(), e);
+            }
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+/// A device without a surface, validation errors are collected instead of panicking
+/// Returns the device, the adapter name and the errors.
+async fn create_device(pref_hp: bool) -> Result<(Device, String, Arc<Mutex<Vec<String>>>)> {
+    let backend = util::backend_bits_from_env().unwrap_or(Backends::PRIMARY);
+    let instance = Instance::new(backend);
+
+    let power_preference = if pref_hp {
+        PowerPreference::HighPerformance
+    } else {
+        util::power_preference_from_env().unwrap_or(PowerPreference::LowPower)
+    };
+    let adapter = instance
+        .request_adapter(&RequestAdapterOptions {
+            power_preference,
+            compatible_surface: None,
+        })
+        .await
+        .context("Can't find a suitable adapter")?;
+    debug!("Checking with {}", adapter.get_info().name);
+
+    let (device, _queue) = adapter
+        .request_device(
+            &DeviceDescriptor {
+                label: Some("check device"),
+                // The features shaders can require, like the app does
+                features: Features::PUSH_CONSTANTS | (adapter.features() & requirable_features()),
+                limits: Limits {
+                    max_push_constant_size: Builtins::default().size().min(PUSH_CONSTANTS_SIZE),
+                    ..Default::default()
+                },
+            },
+            None,
+        )
+        .await?;
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sink = errors.clone();
+    device.on_uncaptured_error(move |e| sink.lock().unwrap().push(e.to_string()));
+    Ok((device, adapter.get_info().name, errors))
+}
+
+/// Expand directories to the shaders they contain
+fn collect_shaders(paths: &[String]) -> Result<Vec<PathBuf>> {
+    let mut shaders = Vec::new();
+    for path in paths.iter().map(Path::new) {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|it| it.path()))
+                .filter(|it| {
+                    it.extension()
+                        .and_then(|ext| ext.to_str())
+                        .map_or(false, |ext| {
+                            ShaderLoader::supported_extensions().contains(&ext)
+                        })
+                })
+                .collect();
+            entries.sort();
+            shaders.extend(entries);
+        } else {
+            shaders.push(path.to_path_buf());
+        }
+    }
+    Ok(shaders)
+}
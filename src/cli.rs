@@ -0,0 +1,282 @@
+//! Command line arguments
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+pub const USAGE: &str = "Usage : nuance [-H] [-I dir]... [--adapter index] [--title title] [--icon image] [--always-on-top] [--script file] [--replay inputs] [--kiosk] [--paused] [shader | --watch shader] [--param name=value[,value...]]... [--osc port [--osc-map address=param]...]\n        nuance [-H] [-I dir]... check <shader or directory>...\n        nuance [-I dir]... compile <shader> <output.spv or output.wgsl>\n        nuance import <shadertoy export.json> [output dir]\n        nuance --list-adapters";
+
+pub enum Command {
+    /// Open a window, optionally loading a shader with some params overridden
+    Run {
+        shader: Option<PathBuf>,
+        params: Vec<ParamOverride>,
+        /// Timeline of commands to run
+        script: Option<PathBuf>,
+        /// Input trace to replay
+        replay: Option<PathBuf>,
+        /// Fullscreen without ui, only Esc is handled
+        kiosk: bool,
+        /// Start with the time stopped at the first frame
+        paused: bool,
+        /// Reload the shader when its files change
+        watch: bool,
+    },
+    /// Validate shaders without opening a window
+    Check(Vec<String>),
+    /// Write the compiled shader to a file
+    Compile { shader: PathBuf, output: PathBuf },
+    /// Convert a Shadertoy export to a shader in the output directory
+    Import { export: PathBuf, output: PathBuf },
+    /// Print the adapters that can be chosen with `--adapter`
+    ListAdapters,
+}
+
+pub struct Args {
+    /// Use the high performance gpu
+    pub pref_hp: bool,
+    /// Index of the adapter to use, overrides `pref_hp`
+    pub adapter: Option<usize>,
+    /// Directories searched for includes after the directory of the including file
+    pub include_dirs: Vec<PathBuf>,
+    pub command: Command,
+    pub window: WindowConfig,
+    /// Listen for OSC messages driving params
+    #[cfg(feature = "osc")]
+    pub osc: Option<OscConfig>,
+}
+
+/// Window decorations shared by all windows
+#[derive(Clone, Default)]
+pub struct WindowConfig {
+    /// Fixed window title, the name of the loaded shader is shown otherwise
+    pub title: Option<String>,
+    /// Image used as the window icon
+    pub icon: Option<PathBuf>,
+    /// Keep the windows above the others, can be changed from the ui
+    pub always_on_top: bool,
+}
+
+#[cfg(feature = "osc")]
+pub struct OscConfig {
+    pub port: u16,
+    /// OSC address and the name of the param it drives
+    pub map: Vec<(String, String)>,
+}
+
+/// A param value set from the command line, vectors and colors have multiple components
+pub struct ParamOverride {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+impl Args {
+    /// Parse the arguments, without the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut pref_hp = false;
+        let mut adapter = None;
+        let mut include_dirs = Vec::new();
+        let mut list_adapters = false;
+        let mut positional = Vec::new();
+        let mut params = Vec::new();
+        let mut window = WindowConfig::default();
+        let mut script = None;
+        let mut replay = None;
+        let mut kiosk = false;
+        let mut paused = false;
+        let mut watch = false;
+        #[cfg(feature = "osc")]
+        let mut osc_port = None;
+        #[cfg(feature = "osc")]
+        let mut osc_map = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-H" => pref_hp = true,
+                "--list-adapters" => list_adapters = true,
+                "--always-on-top" => window.always_on_top = true,
+                "--kiosk" => kiosk = true,
+                "--paused" => paused = true,
+                "--adapter" => {
+                    let index = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--adapter expects an index\n{}", USAGE))?;
+                    adapter = Some(
+                        index
+                            .parse::<usize>()
+                            .with_context(|| format!("Invalid adapter index '{}'", index))?,
+                    );
+                }
+                "-I" | "--include-dir" => {
+                    include_dirs
+                        .push(PathBuf::from(args.next().ok_or_else(|| {
+                            anyhow!("{} expects a directory\n{}", arg, USAGE)
+                        })?));
+                }
+                "--param" => {
+                    let param = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--param expects name=value\n{}", USAGE))?;
+                    params.push(ParamOverride::parse(&param)?);
+                }
+                "--watch" => {
+                    positional.push(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--watch expects a shader\n{}", USAGE))?,
+                    );
+                    watch = true;
+                }
+                "--script" => {
+                    script =
+                        Some(PathBuf::from(args.next().ok_or_else(|| {
+                            anyhow!("--script expects a file\n{}", USAGE)
+                        })?));
+                }
+                "--replay" => {
+                    replay = Some(PathBuf::from(args.next().ok_or_else(|| {
+                        anyhow!("--replay expects an input trace\n{}", USAGE)
+                    })?));
+                }
+                "--title" => {
+                    window.title = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--title expects a title\n{}", USAGE))?,
+                    );
+                }
+                "--icon" => {
+                    window.icon =
+                        Some(PathBuf::from(args.next().ok_or_else(|| {
+                            anyhow!("--icon expects an image\n{}", USAGE)
+                        })?));
+                }
+                #[cfg(feature = "osc")]
+                "--osc" => {
+                    let port = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--osc expects a port\n{}", USAGE))?;
+                    osc_port = Some(
+                        port.parse::<u16>()
+                            .with_context(|| format!("Invalid OSC port '{}'", port))?,
+                    );
+                }
+                #[cfg(feature = "osc")]
+                "--osc-map" => {
+                    let mapping = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--osc-map expects address=param\n{}", USAGE))?;
+                    let (address, param) = mapping.split_once('=').ok_or_else(|| {
+                        anyhow!("Invalid OSC mapping '{}', expected address=param", mapping)
+                    })?;
+                    osc_map.push((address.trim().to_string(), param.trim().to_string()));
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let command = if list_adapters {
+            Command::ListAdapters
+        } else if positional.first().map(String::as_str) == Some("check") {
+            if !params.is_empty() {
+                bail!("--param can't be used with check\n{}", USAGE);
+            }
+            if script.is_some() {
+                bail!("--script can't be used with check\n{}", USAGE);
+            }
+            Command::Check(positional.split_off(1))
+        } else if positional.first().map(String::as_str) == Some("compile") {
+            if !params.is_empty() {
+                bail!("--param can't be used with compile\n{}", USAGE);
+            }
+            if script.is_some() {
+                bail!("--script can't be used with compile\n{}", USAGE);
+            }
+            match positional.as_slice() {
+                [_, shader, output] => Command::Compile {
+                    shader: PathBuf::from(shader),
+                    output: PathBuf::from(output),
+                },
+                _ => bail!("compile expects a shader and an output file\n{}", USAGE),
+            }
+        } else if positional.first().map(String::as_str) == Some("import") {
+            if !params.is_empty() {
+                bail!("--param can't be used with import\n{}", USAGE);
+            }
+            if script.is_some() {
+                bail!("--script can't be used with import\n{}", USAGE);
+            }
+            match positional.as_slice() {
+                [_, export] => Command::Import {
+                    export: PathBuf::from(export),
+                    output: PathBuf::from("."),
+                },
+                [_, export, output] => Command::Import {
+                    export: PathBuf::from(export),
+                    output: PathBuf::from(output),
+                },
+                _ => bail!("import expects a Shadertoy export\n{}", USAGE),
+            }
+        } else {
+            if positional.len() > 1 {
+                bail!("Only one shader can be loaded at startup\n{}", USAGE);
+            }
+            let shader = positional.pop().map(PathBuf::from);
+            if shader.is_none() && !params.is_empty() {
+                bail!("--param needs a shader to apply to\n{}", USAGE);
+            }
+            if shader.is_none() && replay.is_some() {
+                bail!("--replay needs a shader to replay the inputs on\n{}", USAGE);
+            }
+            if shader.is_none() && kiosk {
+                bail!("--kiosk needs a shader to run\n{}", USAGE);
+            }
+            Command::Run {
+                shader,
+                params,
+                script,
+                replay,
+                kiosk,
+                paused,
+                watch,
+            }
+        };
+
+        #[cfg(feature = "osc")]
+        if osc_port.is_none() && !osc_map.is_empty() {
+            bail!("--osc-map needs --osc\n{}", USAGE);
+        }
+
+        Ok(Self {
+            pref_hp,
+            adapter,
+            include_dirs,
+            command,
+            window,
+            #[cfg(feature = "osc")]
+            osc: osc_port.map(|port| OscConfig { port, map: osc_map }),
+        })
+    }
+}
+
+impl ParamOverride {
+    /// Parse `name=value[,value...]`
+    pub fn parse(param: &str) -> Result<Self> {
+        let (name, values) = param
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid param '{}', expected name=value", param))?;
+        let values = values
+            .split(',')
+            .map(|value| match value.trim() {
+                "true" => Ok(1.0),
+                "false" => Ok(0.0),
+                value => value
+                    .parse::<f32>()
+                    .with_context(|| format!("Invalid value '{}' for param {}", value, name)),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            name: name.trim().to_string(),
+            values,
+        })
+    }
+}
@@ -0,0 +1,40 @@
+//! Export of the compiled shader, to see what nuance gives to wgpu
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use wgpu::ShaderSource;
+
+use nuance::loader::ShaderLoader;
+use nuance::reflect;
+
+/// Compile a shader and write the result to `output`, as SPIR-V or translated to WGSL by naga
+/// depending on its extension
+pub fn compile(shader: &Path, output: &Path, include_dirs: &[PathBuf]) -> Result<()> {
+    let mut loader = ShaderLoader::new();
+    for dir in include_dirs {
+        loader.add_include_dir(dir);
+    }
+    let (_, source) = loader
+        .load_shader(shader)
+        .with_context(|| format!("Can't compile {}", shader.display()))?;
+
+    let bytes = match (output.extension().and_then(|it| it.to_str()), source) {
+        (Some("spv"), ShaderSource::SpirV(spirv)) => {
+            spirv.iter().flat_map(|word| word.to_le_bytes()).collect()
+        }
+        (Some("wgsl"), ShaderSource::SpirV(spirv)) => reflect::to_wgsl(&spirv)?.into_bytes(),
+        // Wgsl shaders are given to wgpu as is
+        (Some("wgsl"), ShaderSource::Wgsl(wgsl)) => wgsl.into_owned().into_bytes(),
+        (Some("spv"), ShaderSource::Wgsl(_)) => bail!("WGSL shaders can't be exported to SPIR-V"),
+        _ => bail!(
+            "Unsupported output format for {}, use .spv or .wgsl",
+            output.display()
+        ),
+    };
+    fs::write(output, bytes).with_context(|| format!("Can't write {}", output.display()))?;
+    info!("{} compiled to {}", shader.display(), output.display());
+    Ok(())
+}
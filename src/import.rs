@@ -0,0 +1,558 @@
+//! Conversion of Shadertoy exports to nuance shaders.
+//! The image pass renders to the canvas and each buffer to its own render target, all of them in
+//! the same draw. Buffers are read from the last frame, even by the passes coming after them.
+//! The passes share a single translation unit, the names several of them define are suffixed with
+//! the name of their pass.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+
+use nuance::preprocessor::MAX_TARGETS;
+
+const SHADERTOY_URL: &str = "https://www.shadertoy.com";
+
+#[derive(Deserialize)]
+struct ShadertoyShader {
+    info: Info,
+    renderpass: Vec<RenderPass>,
+}
+
+#[derive(Deserialize)]
+struct Info {
+    id: String,
+    name: String,
+    #[serde(default)]
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct RenderPass {
+    #[serde(default)]
+    inputs: Vec<Input>,
+    #[serde(default)]
+    outputs: Vec<Output>,
+    code: String,
+    name: String,
+    /// image, buffer, common, sound or cubemap
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct Input {
+    /// Id of the buffer output for buffer inputs
+    id: Value,
+    /// Path of the media on the website, older exports call it filepath
+    #[serde(alias = "filepath", default)]
+    src: String,
+    /// texture, cubemap, keyboard, buffer or an unsupported one, older exports call it type
+    #[serde(alias = "type")]
+    ctype: String,
+    channel: u32,
+    #[serde(default)]
+    sampler: Sampler,
+}
+
+/// All values are strings in exports
+#[derive(Deserialize, Default)]
+struct Sampler {
+    filter: Option<String>,
+    wrap: Option<String>,
+    vflip: Option<String>,
+    srgb: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    id: Value,
+}
+
+/// Convert a Shadertoy export (as returned by the Shadertoy api) to a shader in `output_dir`.
+/// Textures are downloaded next to it. Returns the path of the shader.
+pub fn import(export: &Path, output_dir: &Path) -> Result<PathBuf> {
+    let contents =
+        fs::read_to_string(export).with_context(|| format!("Can't read {}", export.display()))?;
+    let mut json: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid Shadertoy export {}", export.display()))?;
+    // The api wraps the shader, exports of several shaders are arrays
+    if let Value::Array(shaders) = json {
+        if shaders.len() > 1 {
+            warn!(
+                "The export has {} shaders, only the first one is imported",
+                shaders.len()
+            );
+        }
+        json = shaders
+            .into_iter()
+            .next()
+            .context("The export has no shader")?;
+    }
+    if let Some(shader) = json.get_mut("Shader") {
+        json = shader.take();
+    }
+    let shader: ShadertoyShader = serde_json::from_value(json)
+        .with_context(|| format!("Invalid Shadertoy export {}", export.display()))?;
+
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Can't create {}", output_dir.display()))?;
+    let source = convert(&shader, output_dir)?;
+    let path = output_dir.join(format!("{}.frag", file_name(&shader.info.name)));
+    fs::write(&path, source).with_context(|| format!("Can't write {}", path.display()))?;
+    info!("{} imported to {}", shader.info.name, path.display());
+    Ok(path)
+}
+
+/// Assemble the passes in a single shader, the channels of each pass are macros defined around
+/// its code
+fn convert(shader: &ShadertoyShader, output_dir: &Path) -> Result<String> {
+    let mut buffers = Vec::new();
+    let mut image = None;
+    let mut common = Vec::new();
+    for pass in &shader.renderpass {
+        match pass.kind.as_str() {
+            "image" => image = Some(pass),
+            "buffer" => buffers.push(pass),
+            "common" => common.push(pass),
+            other => warn!(
+                "Skipping the {} pass '{}', it isn't supported",
+                other, pass.name
+            ),
+        }
+    }
+    let image = image.context("The shader has no image pass")?;
+    // The canvas is the first target
+    if buffers.len() as u32 + 1 > MAX_TARGETS {
+        bail!(
+            "The shader has {} buffers, at most {} are supported",
+            buffers.len(),
+            MAX_TARGETS - 1
+        );
+    }
+
+    let mut header = format!(
+        "// {} by {}, imported from {}/view/{}\n\n#include <shadertoy>\n",
+        shader.info.name, shader.info.username, SHADERTOY_URL, shader.info.id
+    );
+    if !buffers.is_empty() {
+        header += &format!("#pragma targets {}\n", buffers.len() + 1);
+    }
+
+    // Buffers are bound by the id of their output
+    let target_of = |id: &Value| {
+        buffers
+            .iter()
+            .position(|pass| pass.outputs.iter().any(|output| output.id == *id))
+            .map(|index| index + 1)
+    };
+    // Names defined by more than one pass, or by a pass and the common code
+    let mut definitions = HashMap::new();
+    for pass in common.iter().chain(&buffers).chain(Some(&image)) {
+        for name in top_level_names(&pass.code) {
+            *definitions.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    let mut next_texture = 0;
+    let mut body = String::new();
+    for pass in common {
+        body += &format!("\n// {}\n{}\n", pass.name, pass.code);
+    }
+    // The image pass comes last to call its mainImage after the others
+    let mut main = String::new();
+    for (target, pass) in buffers.iter().chain(Some(&image)).enumerate() {
+        let target = (target + 1) % (buffers.len() + 1);
+        body += &format!("\n// {}\n", pass.name);
+        let mut channels = Vec::new();
+        for input in &pass.inputs {
+            let sampler = match input.ctype.as_str() {
+                "buffer" => match target_of(&input.id) {
+                    Some(target) => format!(
+                        "sampler2D(previousColor{}, previousColor{}Sampler)",
+                        target, target
+                    ),
+                    None => {
+                        warn!(
+                            "{} : iChannel{} reads an unknown buffer",
+                            pass.name, input.channel
+                        );
+                        continue;
+                    }
+                },
+                "texture" | "cubemap" | "keyboard" => {
+                    let index = next_texture;
+                    next_texture += 1;
+                    header += &texture_pragma(input, index, output_dir);
+                    match input.ctype.as_str() {
+                        "texture" => {
+                            format!("sampler2D(texture{}, texture{}Sampler)", index, index)
+                        }
+                        "cubemap" => {
+                            format!("samplerCube(cubemap{}, cubemap{}Sampler)", index, index)
+                        }
+                        _ => format!("sampler2D(keyboard{}, keyboard{}Sampler)", index, index),
+                    }
+                }
+                other => {
+                    warn!(
+                        "{} : iChannel{} is a {} input, it isn't supported",
+                        pass.name, input.channel, other
+                    );
+                    continue;
+                }
+            };
+            channels.push(format!("iChannel{}", input.channel));
+            body += &format!("#define iChannel{} {}\n", input.channel, sampler);
+        }
+
+        let suffix = file_name(&pass.name);
+        let mut code = rename_ident(&pass.code, "mainImage", &format!("mainImage{}", suffix));
+        for name in top_level_names(&pass.code) {
+            if definitions.get(&name).copied().unwrap_or(0) > 1 {
+                info!("{} : renamed {} to {}{}", pass.name, name, name, suffix);
+                code = rename_ident(&code, &name, &format!("{}{}", name, suffix));
+            }
+        }
+        let entry = format!("mainImage{}", suffix);
+        body += &format!("{}\n", code);
+        for channel in channels {
+            body += &format!("#undef {}\n", channel);
+        }
+        main += &if target == 0 {
+            format!("    {}(fragColor, shadertoyCoord);\n", entry)
+        } else {
+            format!("    {}(fragColor{}, gl_FragCoord.xy);\n", entry, target)
+        };
+    }
+
+    Ok(format!("{}{}\nvoid main() {{\n{}}}\n", header, body, main))
+}
+
+/// The texture pragma of a channel, the images are downloaded to `output_dir`
+fn texture_pragma(input: &Input, index: u32, output_dir: &Path) -> String {
+    if input.ctype == "keyboard" {
+        return format!("#pragma keyboard{}\n", index);
+    }
+    let mut settings = String::new();
+    match input.sampler.filter.as_deref() {
+        Some("nearest") => settings += " filter:nearest",
+        Some("linear") | Some("mipmap") => settings += " filter:linear",
+        _ => {}
+    }
+    match input.sampler.wrap.as_deref() {
+        Some("clamp") => settings += " wrap:clamp",
+        Some("repeat") => settings += " wrap:repeat",
+        _ => {}
+    }
+    match input.sampler.srgb.as_deref() {
+        Some("true") => settings += " srgb:true",
+        Some("false") => settings += " srgb:false",
+        _ => {}
+    }
+    // Shadertoy flips the images so their origin is at the bottom left like the coordinates
+    let flip = input.sampler.vflip.as_deref() == Some("true");
+
+    if input.ctype == "cubemap" {
+        // The other faces have the index of the face appended
+        let (stem, extension) = input.src.rsplit_once('.').unwrap_or((&input.src, ""));
+        let faces = (0..6)
+            .map(|face| {
+                let src = if face == 0 {
+                    input.src.clone()
+                } else {
+                    format!("{}_{}.{}", stem, face, extension)
+                };
+                download(&src, false, output_dir)
+            })
+            .collect::<Vec<_>>()
+            .join("\" \"");
+        format!("#pragma cubemap{} \"{}\"{}\n", index, faces, settings)
+    } else {
+        let file = download(&input.src, flip, output_dir);
+        format!("#pragma texture{} \"{}\"{}\n", index, file, settings)
+    }
+}
+
+/// Download a media from the website to `output_dir`, returns its file name.
+/// Failures are only logged so the shader can still be written, the file can be fetched by hand.
+fn download(src: &str, flip: bool, output_dir: &Path) -> String {
+    let name = src.rsplit('/').next().unwrap_or(src).to_string();
+    let url = format!("{}{}", SHADERTOY_URL, src);
+    let path = output_dir.join(&name);
+    info!("Downloading {}", url);
+    let downloaded = ureq::get(&url)
+        .call()
+        .map_err(anyhow::Error::from)
+        .and_then(|response| {
+            let mut data = Vec::new();
+            response.into_reader().read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .and_then(|data| {
+            if flip {
+                image::load_from_memory(&data)?.flipv().save(&path)?;
+            } else {
+                fs::write(&path, data)?;
+            }
+            Ok(())
+        });
+    if let Err(e) = downloaded {
+        warn!(
+            "Can't download {} to {} : {:#}{}",
+            url,
+            path.display(),
+            e,
+            if flip { ", flip it vertically" } else { "" }
+        );
+    }
+    name
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replace whole identifiers only, members and swizzles after a `.` are left alone as well as the
+/// names of the struct members, they don't share the scope of the globals
+fn rename_ident(code: &str, from: &str, to: &str) -> String {
+    let stripped = strip_comments(code);
+    let structs = struct_bodies(&stripped);
+    let mut renamed = String::with_capacity(code.len());
+    let mut last = 0;
+    for (start, _) in code.match_indices(from) {
+        let end = start + from.len();
+        let before = code[..start]
+            .chars()
+            .next_back()
+            .map_or(false, |c| is_ident_char(c) || c == '.');
+        let after = code[end..].chars().next().map_or(false, is_ident_char);
+        // The type of a member comes first in its declaration, the names after it
+        let member = structs
+            .iter()
+            .any(|(open, close)| *open < start && start < *close)
+            && !stripped[..start].trim_end().ends_with(&['{', ';'][..]);
+        if !(before || after || member) {
+            renamed += &code[last..start];
+            renamed += to;
+            last = end;
+        }
+    }
+    renamed + &code[last..]
+}
+
+/// Byte ranges between the braces of the struct definitions
+fn struct_bodies(code: &str) -> Vec<(usize, usize)> {
+    let mut bodies = Vec::new();
+    for (start, keyword) in code.match_indices("struct") {
+        let end = start + keyword.len();
+        if code[..start].ends_with(is_ident_char) || code[end..].starts_with(is_ident_char) {
+            continue;
+        }
+        // Struct definitions can't be nested
+        if let Some(open) = code[end..].find('{').map(|it| end + it) {
+            let close = code[open..].find('}').map_or(code.len(), |it| open + it);
+            bodies.push((open, close));
+        }
+    }
+    bodies
+}
+
+/// Names of the functions, structs, global variables and macros defined at the top level of a
+/// pass, `mainImage` excepted
+fn top_level_names(code: &str) -> Vec<String> {
+    let code = strip_comments(code);
+    let mut names = Vec::new();
+    let mut statements = String::new();
+    for line in code.lines() {
+        match line.trim_start().strip_prefix('#') {
+            Some(directive) => {
+                if let Some(name) = directive.trim_start().strip_prefix("define") {
+                    names.extend(first_ident(name));
+                }
+            }
+            None => {
+                statements += line;
+                statements.push('\n');
+            }
+        }
+    }
+
+    let mut depth = 0;
+    let mut statement = String::new();
+    for c in statements.chars() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    // A function body or a struct
+                    let head = statement.split('(').next().unwrap_or("");
+                    if let Some(name) = head.trim().strip_prefix("struct") {
+                        names.extend(first_ident(name));
+                    } else if statement.contains('(') {
+                        names.extend(last_ident(head));
+                    }
+                    statement.clear();
+                }
+                depth += 1;
+            }
+            '}' => depth -= 1,
+            ';' if depth == 0 => {
+                if statement.contains('(') && !statement.contains('=') {
+                    // A prototype
+                    names.extend(last_ident(statement.split('(').next().unwrap_or("")));
+                } else {
+                    // Global variables, each declarator before its initializer
+                    let mut parens = 0;
+                    let mut declarator = String::new();
+                    for c in statement.chars().chain(Some(',')) {
+                        match c {
+                            '(' => parens += 1,
+                            ')' => parens -= 1,
+                            ',' if parens == 0 => {
+                                let name = declarator.split(|c| c == '=' || c == '[').next();
+                                names.extend(name.and_then(last_ident));
+                                declarator.clear();
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        declarator.push(c);
+                    }
+                }
+                statement.clear();
+            }
+            _ if depth == 0 => statement.push(c),
+            _ => {}
+        }
+    }
+    names.retain(|it| it != "mainImage");
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn first_ident(text: &str) -> Option<String> {
+    let name: String = text
+        .trim_start()
+        .chars()
+        .take_while(|c| is_ident_char(*c))
+        .collect();
+    Some(name).filter(|it| it.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+fn last_ident(text: &str) -> Option<String> {
+    let text = text.trim_end();
+    let start = text
+        .rfind(|c: char| !is_ident_char(c))
+        .map_or(0, |it| it + 1);
+    first_ident(&text[start..])
+}
+
+/// Blank out the comments, keeping the line breaks and the byte offsets of the code
+fn strip_comments(code: &str) -> String {
+    let mut stripped = String::with_capacity(code.len());
+    let mut rest = code;
+    while let Some(start) = rest.find('/') {
+        stripped += &rest[..start];
+        let after = &rest[start..];
+        let end = if after.starts_with("//") {
+            after.find('\n').unwrap_or(after.len())
+        } else if let Some(comment) = after.strip_prefix("/*") {
+            // The end can't share the star of the start, `/*/` isn't a comment
+            comment.find("*/").map_or(after.len(), |it| it + 4)
+        } else {
+            stripped.push('/');
+            rest = &after[1..];
+            continue;
+        };
+        // A comment separates the tokens around it like a space, one per byte
+        for byte in after[..end].bytes() {
+            stripped.push(if byte == b'\n' { '\n' } else { ' ' });
+        }
+        rest = &after[end..];
+    }
+    stripped + rest
+}
+
+/// Only keep ascii letters and digits
+fn file_name(name: &str) -> String {
+    let name: String = name.chars().filter(char::is_ascii_alphanumeric).collect();
+    if name.is_empty() {
+        "shadertoy".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename() {
+        let code = "float x = 1.0;\nvec2 v = vec2(x);\nv.x = x + v.yx.x;\n";
+        assert_eq!(
+            rename_ident(code, "x", "xA"),
+            "float xA = 1.0;\nvec2 v = vec2(xA);\nv.x = xA + v.yx.x;\n"
+        );
+        // Only whole identifiers
+        assert_eq!(rename_ident("max(xy, x2)", "x", "xA"), "max(xy, x2)");
+    }
+
+    #[test]
+    fn rename_members() {
+        let code = "vec2 p;\nstruct Ray { vec3 p; Hit hit; };\n\
+                    void f(Ray ray) { p.x = ray.p.y + p.y; }\n";
+        assert_eq!(
+            rename_ident(code, "p", "pA"),
+            "vec2 pA;\nstruct Ray { vec3 p; Hit hit; };\n\
+             void f(Ray ray) { pA.x = ray.p.y + pA.y; }\n"
+        );
+        // The types of the members are renamed
+        assert_eq!(
+            rename_ident(code, "Hit", "HitA"),
+            "vec2 p;\nstruct Ray { vec3 p; HitA hit; };\n\
+             void f(Ray ray) { p.x = ray.p.y + p.y; }\n"
+        );
+        // Not fooled by a struct in a comment
+        assert_eq!(
+            rename_ident("// struct\nvoid f() { p = 1.0; }", "p", "pA"),
+            "// struct\nvoid f() { pA = 1.0; }"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let code = "float a; // float b;\n/* float c;\n*/float/**/d = e / f;";
+        let stripped = strip_comments(code);
+        assert_eq!(stripped.len(), code.len());
+        assert_eq!(stripped.lines().count(), code.lines().count());
+        assert_eq!(
+            stripped.split_whitespace().collect::<Vec<_>>(),
+            ["float", "a;", "float", "d", "=", "e", "/", "f;"]
+        );
+        assert_eq!(strip_comments("a /*/ b */ c").split_whitespace().count(), 2);
+        assert_eq!(strip_comments("a /* b"), "a     ");
+        assert_eq!(top_level_names(code), ["a", "d"]);
+    }
+
+    #[test]
+    fn names() {
+        let code = "float a, b = 1.0, c[2];\n\
+                    vec2 d = vec2(0.0, 1.0), e;\n\
+                    struct S { float m; };\n\
+                    float f(float x);\n\
+                    #define G 1\n\
+                    vec3 h(vec2 p) { float local; return vec3(p, 0.0); }\n\
+                    void mainImage(out vec4 fragColor, in vec2 fragCoord) {}\n";
+        assert_eq!(
+            top_level_names(code),
+            ["G", "S", "a", "b", "c", "d", "e", "f", "h"]
+        );
+    }
+}
@@ -1,22 +1,26 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
 use env_logger::{Target, WriteStyle};
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use winit::dpi::LogicalSize;
-use winit::event::Event;
-use winit::event_loop::EventLoop;
-use winit::window::WindowBuilder;
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget};
+use winit::window::{Icon, WindowBuilder};
 
-use crate::app::Nuance;
+use crate::app::{install_panic_hook, Nuance};
+use crate::cli::{Args, Command, WindowConfig};
 
 mod app;
+mod check;
+mod cli;
+mod compile;
+mod import;
 
 fn main() -> Result<()> {
-    let mut pref_hp = false;
-    for arg in std::env::args() {
-        if arg.as_str() == "-H" {
-            pref_hp = true;
-        }
-    }
+    let args = Args::parse(std::env::args().skip(1))?;
+    let pref_hp = args.pref_hp;
 
     puffin::set_scopes_on(true);
 
@@ -35,32 +39,212 @@ fn main() -> Result<()> {
         .filter_module("naga::valid", LevelFilter::Warn)
         .init();
 
+    install_panic_hook();
+
+    let (shader, params, script, replay, kiosk, paused, watch) = match args.command {
+        Command::Run {
+            shader,
+            params,
+            script,
+            replay,
+            kiosk,
+            paused,
+            watch,
+        } => (shader, params, script, replay, kiosk, paused, watch),
+        // Validate shaders without opening a window
+        Command::Check(shaders) => {
+            let failed = check::check(&shaders, pref_hp, &args.include_dirs)?;
+            std::process::exit(if failed == 0 { 0 } else { 1 });
+        }
+        Command::Compile { shader, output } => {
+            return compile::compile(&shader, &output, &args.include_dirs)
+        }
+        Command::Import { export, output } => {
+            import::import(&export, &output)?;
+            return Ok(());
+        }
+        Command::ListAdapters => {
+            list_adapters();
+            return Ok(());
+        }
+    };
+
     info!("Starting up !");
 
     let event_loop = EventLoop::new();
 
-    // Create the window
-    let builder = WindowBuilder::new()
-        .with_title("Nuance")
-        .with_inner_size(LogicalSize::new(1280, 720))
-        .with_resizable(true)
-        .with_visible(true);
-    let window = builder.build(&event_loop)?;
-
-    let mut app = futures_executor::block_on(Nuance::init(window, pref_hp))?;
+    // Each window has its own independent app
+    let mut apps = HashMap::new();
+    let icon = args.window.icon.as_deref().map(load_icon).transpose()?;
+    let window_config = args.window.clone();
+    let adapter = args.adapter;
+    let include_dirs = args.include_dirs.clone();
+    let mut app = open_window(
+        &event_loop,
+        pref_hp,
+        adapter,
+        &window_config,
+        &include_dirs,
+        icon.clone(),
+    )?;
+    if let Some(shader) = shader {
+        app.open_shader(&shader, &params)?;
+        app.set_watching(watch);
+    } else if script.is_none() {
+        if let Err(e) = app.offer_recovery() {
+            error!("Can't restore the crashed session : {:#}", e);
+        }
+    }
+    // Only the first window runs the script
+    if let Some(script) = script {
+        app.load_script(&script)?;
+    }
+    if let Some(replay) = replay {
+        app.replay_inputs(&replay)?;
+    }
+    if kiosk {
+        app.enter_kiosk();
+    }
+    if paused {
+        app.pause();
+    }
+    // Only the first window listens, the port can't be shared
+    #[cfg(feature = "osc")]
+    if let Some(osc) = args.osc.as_ref() {
+        app.listen_osc(osc)?;
+    }
+    apps.insert(app.window_id(), app);
 
-    event_loop.run(move |event, _, control_flow| match event {
-        Event::WindowEvent { .. } => {
-            app.handle_event(event, control_flow);
+    event_loop.run(move |event, target, control_flow| match event {
+        Event::WindowEvent {
+            window_id,
+            event: WindowEvent::CloseRequested,
+        } => {
+            apps.remove(&window_id);
+            if apps.is_empty() {
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+        Event::WindowEvent { window_id, .. } => {
+            if let Some(app) = apps.get_mut(&window_id) {
+                app.handle_event(event);
+            }
         }
         Event::MainEventsCleared => {
-            app.update(control_flow);
+            // Every app will ask to be woken up for its next frame, the earliest wins
+            *control_flow = ControlFlow::Wait;
+            let mut new_windows = 0;
+            let mut adapter_switches = Vec::new();
+            for app in apps.values_mut() {
+                app.update(control_flow);
+                if app.take_new_window_request() {
+                    new_windows += 1;
+                }
+                if let Some(index) = app.take_adapter_request() {
+                    adapter_switches.push((app.window_id(), index));
+                }
+            }
+            // The new window is created before closing the old one, which stays if it fails
+            for (window_id, index) in adapter_switches {
+                let mut app = match open_window(
+                    target,
+                    pref_hp,
+                    Some(index),
+                    &window_config,
+                    &include_dirs,
+                    icon.clone(),
+                ) {
+                    Ok(app) => app,
+                    Err(e) => {
+                        error!("Can't open a window on adapter {} : {}", index, e);
+                        continue;
+                    }
+                };
+                if let Some(session) = apps.get(&window_id).and_then(|it| it.session().ok()) {
+                    // Keep the current window when the shader doesn't work on the new adapter
+                    if let Err(e) = app
+                        .restore_session(session)
+                        .and_then(|_| app.check_pipelines())
+                    {
+                        error!("{:#}", e);
+                        error!("Can't switch to adapter {}, keeping the current one", index);
+                        continue;
+                    }
+                }
+                apps.remove(&window_id);
+                apps.insert(app.window_id(), app);
+            }
+            for _ in 0..new_windows {
+                match open_window(
+                    target,
+                    pref_hp,
+                    adapter,
+                    &window_config,
+                    &include_dirs,
+                    icon.clone(),
+                ) {
+                    Ok(app) => {
+                        apps.insert(app.window_id(), app);
+                    }
+                    Err(e) => error!("Can't open a new window : {}", e),
+                }
+            }
         }
-        Event::RedrawRequested(_) => {
-            app.draw();
+        Event::RedrawRequested(window_id) => {
+            if let Some(app) = apps.get_mut(&window_id) {
+                app.draw();
+            }
         }
         _ => {}
     });
 
     //Ok(())
 }
+
+/// Create a new window with its own app
+fn open_window(
+    target: &EventLoopWindowTarget<()>,
+    pref_hp: bool,
+    adapter: Option<usize>,
+    config: &WindowConfig,
+    include_dirs: &[PathBuf],
+    icon: Option<Icon>,
+) -> Result<Nuance> {
+    let builder = WindowBuilder::new()
+        .with_title(config.title.as_deref().unwrap_or("Nuance"))
+        .with_window_icon(icon)
+        .with_inner_size(LogicalSize::new(1280, 720))
+        .with_resizable(true)
+        .with_visible(true);
+    let window = builder.build(target)?;
+
+    let mut app =
+        futures_executor::block_on(Nuance::init(window, pref_hp, adapter, config.title.clone()))?;
+    app.set_always_on_top(config.always_on_top);
+    for dir in include_dirs {
+        app.add_include_dir(dir);
+    }
+    Ok(app)
+}
+
+/// Print the adapters with the index to give to `--adapter`
+fn list_adapters() {
+    let backend = wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::PRIMARY);
+    let instance = wgpu::Instance::new(backend);
+    for (i, adapter) in instance.enumerate_adapters(backend).enumerate() {
+        let info = adapter.get_info();
+        println!(
+            "{} : {} ({:?}, {:?})",
+            i, info.name, info.backend, info.device_type
+        );
+    }
+}
+
+/// Read an image to be used as the window icon
+fn load_icon(path: &Path) -> Result<Icon> {
+    let image = image::open(path)
+        .with_context(|| format!("Can't read icon {}", path.display()))?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(Icon::from_rgba(image.into_raw(), width, height)?)
+}
@@ -0,0 +1,230 @@
+//! Builtin uniforms computed on the cpu and registered by name. They are appended to the globals
+//! block after the fields of [`Globals`], each one brings its GLSL declaration so adding one
+//! doesn't touch the struct or the standard header.
+
+use anyhow::{bail, Result};
+use crevice::std430::{AsStd430, Std430};
+
+use crate::Globals;
+
+/// Push constants size every device supports, larger globals go in a uniform buffer
+pub const PUSH_CONSTANTS_SIZE: u32 = 128;
+
+/// Uniform buffer binding size every device supports
+pub const MAX_GLOBALS_SIZE: u32 = 16384;
+
+/// Replaced by the declarations of the builtins in the standard header
+pub const BUILTINS_MARKER: &str = "// NUANCE_BUILTINS";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BuiltinValue {
+    Float(f32),
+    Int(i32),
+    Uint(u32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+}
+
+impl BuiltinValue {
+    fn glsl_type(&self) -> &'static str {
+        match self {
+            BuiltinValue::Float(_) => "float",
+            BuiltinValue::Int(_) => "int",
+            BuiltinValue::Uint(_) => "uint",
+            BuiltinValue::Vec2(_) => "vec2",
+            BuiltinValue::Vec3(_) => "vec3",
+            BuiltinValue::Vec4(_) => "vec4",
+        }
+    }
+
+    /// Alignment in the std430 layout, vec3 are aligned like vec4
+    fn align(&self) -> usize {
+        match self {
+            BuiltinValue::Float(_) | BuiltinValue::Int(_) | BuiltinValue::Uint(_) => 4,
+            BuiltinValue::Vec2(_) => 8,
+            BuiltinValue::Vec3(_) | BuiltinValue::Vec4(_) => 16,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            BuiltinValue::Float(value) => value.to_ne_bytes().to_vec(),
+            BuiltinValue::Int(value) => value.to_ne_bytes().to_vec(),
+            BuiltinValue::Uint(value) => value.to_ne_bytes().to_vec(),
+            BuiltinValue::Vec2(value) => value.iter().flat_map(|it| it.to_ne_bytes()).collect(),
+            BuiltinValue::Vec3(value) => value.iter().flat_map(|it| it.to_ne_bytes()).collect(),
+            BuiltinValue::Vec4(value) => value.iter().flat_map(|it| it.to_ne_bytes()).collect(),
+        }
+    }
+
+    fn same_type(&self, other: &BuiltinValue) -> bool {
+        self.glsl_type() == other.glsl_type()
+    }
+}
+
+struct Builtin {
+    /// Name in GLSL
+    name: String,
+    description: String,
+    /// Offset in the globals block
+    offset: usize,
+    value: BuiltinValue,
+}
+
+pub struct Builtins {
+    builtins: Vec<Builtin>,
+}
+
+impl Default for Builtins {
+    /// The builtins declared by the standard header
+    fn default() -> Self {
+        let mut builtins = Self {
+            builtins: Vec::new(),
+        };
+        builtins
+            .register(
+                "uDebug",
+                "1 when the debug view is enabled",
+                BuiltinValue::Uint(0),
+            )
+            .unwrap();
+        builtins
+            .register(
+                "fFrameRate",
+                "Measured framerate, averaged over the last frames",
+                BuiltinValue::Float(0.0),
+            )
+            .unwrap();
+        builtins
+            .register(
+                "fScaleFactor",
+                "Physical pixels per logical pixel of the display",
+                BuiltinValue::Float(1.0),
+            )
+            .unwrap();
+        builtins
+            .register(
+                "uParamCount",
+                "Number of params declared by the shader",
+                BuiltinValue::Uint(0),
+            )
+            .unwrap();
+        builtins
+            .register(
+                "fDayColor",
+                "Color cycling through the hues over the day, from the local time",
+                BuiltinValue::Vec3(day_color(0.0)),
+            )
+            .unwrap();
+        builtins
+            .register(
+                "fMouseDrag",
+                "Cursor position while the left button is held then the click position, \
+                 negated once released, in pixels",
+                BuiltinValue::Vec4([0.0; 4]),
+            )
+            .unwrap();
+        builtins
+    }
+}
+
+impl Builtins {
+    /// Add a builtin after the others, its value keeps the type of `initial`
+    pub fn register(&mut self, name: &str, description: &str, initial: BuiltinValue) -> Result<()> {
+        if self.builtins.iter().any(|it| it.name == name) {
+            bail!("Builtin {} is already registered", name);
+        }
+        let end = self.end();
+        let align = initial.align();
+        let offset = (end + align - 1) / align * align;
+        if offset + initial.bytes().len() > MAX_GLOBALS_SIZE as usize {
+            bail!(
+                "Builtin {} doesn't fit in the {} bytes of globals",
+                name,
+                MAX_GLOBALS_SIZE
+            );
+        }
+        self.builtins.push(Builtin {
+            name: name.to_string(),
+            description: description.to_string(),
+            offset,
+            value: initial,
+        });
+        Ok(())
+    }
+
+    /// Update the value of a builtin, it must have the type it was registered with
+    pub fn set(&mut self, name: &str, value: BuiltinValue) -> Result<()> {
+        match self.builtins.iter_mut().find(|it| it.name == name) {
+            Some(builtin) if builtin.value.same_type(&value) => builtin.value = value,
+            Some(builtin) => bail!(
+                "Builtin {} is a {}, it can't be set with a {}",
+                name,
+                builtin.value.glsl_type(),
+                value.glsl_type()
+            ),
+            None => bail!("Builtin {} isn't registered", name),
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of the globals block
+    pub fn size(&self) -> u32 {
+        // Push constants are set by words
+        ((self.end() + 3) / 4 * 4) as u32
+    }
+
+    /// true if the globals block can be passed with push constants on every device
+    pub fn fits_push_constants(&self) -> bool {
+        self.size() <= PUSH_CONSTANTS_SIZE
+    }
+
+    /// Offset of the end of the last builtin
+    fn end(&self) -> usize {
+        self.builtins
+            .last()
+            .map_or(Globals::std430_size_static(), |it| {
+                it.offset + it.value.bytes().len()
+            })
+    }
+
+    /// The globals block, the fields of `globals` followed by the builtins
+    pub fn write(&self, globals: &Globals) -> Vec<u8> {
+        let mut bytes = globals.as_std430().as_bytes().to_vec();
+        bytes.resize(self.size() as usize, 0);
+        for builtin in &self.builtins {
+            let value = builtin.value.bytes();
+            bytes[builtin.offset..builtin.offset + value.len()].copy_from_slice(&value);
+        }
+        bytes
+    }
+
+    /// Members of the globals block in GLSL, the offsets are explicit so the trailing padding of
+    /// the struct can't shift them
+    pub fn declarations(&self) -> String {
+        self.builtins
+            .iter()
+            .map(|it| {
+                format!(
+                    "// {}\n    layout(offset = {}) {} {};\n",
+                    it.description,
+                    it.offset,
+                    it.value.glsl_type(),
+                    it.name
+                )
+            })
+            .collect()
+    }
+}
+
+/// Color going around the color wheel once a day from the fraction of the day elapsed, midnight
+/// is red
+pub fn day_color(day_fraction: f32) -> [f32; 3] {
+    let phase = std::f32::consts::TAU * day_fraction;
+    [
+        0.5 + 0.5 * phase.cos(),
+        0.5 + 0.5 * (phase - std::f32::consts::TAU / 3.0).cos(),
+        0.5 + 0.5 * (phase + std::f32::consts::TAU / 3.0).cos(),
+    ]
+}
@@ -1,21 +1,205 @@
 use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter, Write};
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::string::FromUtf8Error;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
-use log::warn;
+use log::{debug, info, warn};
 use shaderc::{
     CompileOptions, Compiler, EnvVersion, GlslProfile, IncludeType, OptimizationLevel,
     ResolvedInclude, ShaderKind, SourceLanguage, TargetEnv,
 };
 use wgpu::ShaderSource;
 
-use crate::shader::preprocessor;
+use crate::shader::builtins::{Builtins, BUILTINS_MARKER};
+use crate::shader::renderer::{GlobalsMode, DEFAULT_GLOBALS_STAGES};
 use crate::shader::Shader;
+use crate::shader::{preprocessor, reflect};
+
+/// Shader compilation failed, keeps the compiled source around to locate the errors
+#[derive(Debug)]
+pub struct CompileError {
+    /// Name given to the compiler, prefix of the diagnostics
+    pub name: String,
+    /// Diagnostics from the compiler
+    pub message: String,
+    /// Source code given to the compiler
+    pub source: String,
+}
+
+impl CompileError {
+    /// Number of lines shown around an error
+    const CONTEXT_LINES: usize = 2;
+
+    /// Format the diagnostics with the offending source lines, ready to be pasted in a bug report
+    pub fn report(&self) -> String {
+        let lines: Vec<&str> = self.source.lines().collect();
+        let mut report = String::new();
+        for diagnostic in self.message.lines() {
+            writeln!(report, "{}", diagnostic).unwrap();
+            let (line, column) = match self.locate(diagnostic) {
+                Some(location) if location.0 > 0 && location.0 <= lines.len() => location,
+                _ => continue,
+            };
+
+            let first = line.saturating_sub(Self::CONTEXT_LINES).max(1);
+            let last = (line + Self::CONTEXT_LINES).min(lines.len());
+            let width = last.to_string().len();
+            writeln!(report, "```").unwrap();
+            for i in first..=last {
+                let marker = if i == line { '>' } else { ' ' };
+                writeln!(
+                    report,
+                    "{} {:>width$} | {}",
+                    marker,
+                    i,
+                    lines[i - 1],
+                    width = width
+                )
+                .unwrap();
+                if i == line {
+                    // Point at the column or at the start of the code
+                    let column = column.unwrap_or_else(|| {
+                        lines[i - 1].len() - lines[i - 1].trim_start().len() + 1
+                    });
+                    writeln!(
+                        report,
+                        "  {:width$} | {:>column$}",
+                        "",
+                        "^",
+                        width = width,
+                        column = column
+                    )
+                    .unwrap();
+                }
+            }
+            writeln!(report, "```").unwrap();
+        }
+        report
+    }
+
+    /// Extract the line and the column (if any) from a diagnostic in the form
+    /// `name:line:[column:] error: message`. Diagnostics from included files are ignored.
+    fn locate(&self, diagnostic: &str) -> Option<(usize, Option<usize>)> {
+        let start = diagnostic.find(&self.name)? + self.name.len();
+        let rest = diagnostic[start..].strip_prefix(':')?;
+        let mut parts = rest.splitn(3, ':');
+        let line = parts.next()?.trim().parse().ok()?;
+        let column = parts.next().and_then(|it| it.trim().parse().ok());
+        Some((line, column))
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Why a shader couldn't be loaded
+#[derive(Debug)]
+pub enum ShaderLoadError {
+    /// The shader file can't be read
+    Io(io::Error),
+    /// The shader can't be downloaded and there is no cached version
+    Download {
+        url: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The shader source isn't valid utf-8
+    InvalidUtf8(FromUtf8Error),
+    /// The file extension doesn't match any supported shader format
+    UnsupportedFormat,
+    /// The shader compiler rejected the shader
+    Compile(CompileError),
+    /// The params block declares a param that can't be used
+    Params(anyhow::Error),
+}
+
+impl Display for ShaderLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ShaderLoadError::Io(e) => write!(f, "Can't read shader : {}", e),
+            ShaderLoadError::Download { url, source } => {
+                write!(f, "Can't download {} : {}", url, source)
+            }
+            ShaderLoadError::InvalidUtf8(e) => write!(f, "Invalid shader source : {}", e),
+            ShaderLoadError::UnsupportedFormat => write!(f, "Unsupported shader format !"),
+            ShaderLoadError::Compile(e) => write!(f, "{}", e),
+            ShaderLoadError::Params(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for ShaderLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShaderLoadError::Io(e) => Some(e),
+            ShaderLoadError::Download { source, .. } => Some(source.as_ref()),
+            ShaderLoadError::InvalidUtf8(e) => Some(e),
+            ShaderLoadError::UnsupportedFormat => None,
+            ShaderLoadError::Compile(e) => Some(e),
+            ShaderLoadError::Params(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ShaderLoadError {
+    fn from(e: io::Error) -> Self {
+        ShaderLoadError::Io(e)
+    }
+}
+
+impl From<FromUtf8Error> for ShaderLoadError {
+    fn from(e: FromUtf8Error) -> Self {
+        ShaderLoadError::InvalidUtf8(e)
+    }
+}
+
+impl From<CompileError> for ShaderLoadError {
+    fn from(e: CompileError) -> Self {
+        ShaderLoadError::Compile(e)
+    }
+}
+
+/// Output of the shader compiler
+pub struct Compiled<'a> {
+    pub source: ShaderSource<'a>,
+    /// One line per warning
+    pub warnings: Vec<String>,
+    /// Files included during the compilation, the standard headers aren't files
+    pub includes: Vec<PathBuf>,
+}
+
+/// Time spent in each step of a shader load
+#[derive(Clone, Copy, Default)]
+pub struct LoadTimes {
+    /// Reading the shader and its includes from the disk or the network
+    pub read: Duration,
+    /// Extracting the params and transpiling the source
+    pub preprocess: Duration,
+    /// Compiling the source to spirv
+    pub compile: Duration,
+}
 
 pub struct ShaderLoader {
     compiler: Compiler,
-    include_dirs: Vec<String>,
+    /// Searched in order for includes not found next to the including file
+    include_dirs: Vec<PathBuf>,
+    /// Last downloaded content of shaders loaded from an url
+    url_cache: HashMap<String, Vec<u8>>,
+    /// GLSL declarations of the builtins, inserted in the standard header
+    builtins: String,
+    /// Declaration of the globals block in the standard header
+    globals_mode: GlobalsMode,
+    /// Steps of the last load
+    times: LoadTimes,
 }
 
 impl Default for ShaderLoader {
@@ -23,6 +207,10 @@ impl Default for ShaderLoader {
         ShaderLoader {
             compiler: Compiler::new().expect("Can't create compiler"),
             include_dirs: Vec::with_capacity(4),
+            url_cache: HashMap::new(),
+            builtins: Builtins::default().declarations(),
+            globals_mode: GlobalsMode::PushConstants,
+            times: LoadTimes::default(),
         }
     }
 }
@@ -36,72 +224,204 @@ impl ShaderLoader {
         &["glsl", "frag", "wgsl", "spv"]
     }
 
-    pub fn add_include_dir(&mut self, include: &str) {
-        self.include_dirs.push(include.to_string());
+    /// Declare these builtins in the standard header of the next shaders
+    pub fn set_builtins(&mut self, builtins: &Builtins) {
+        self.builtins = builtins.declarations();
+    }
+
+    /// Declare the globals block of the next shaders for this mode
+    pub fn set_globals_mode(&mut self, mode: GlobalsMode) {
+        self.globals_mode = mode;
+    }
+
+    /// The nuance standard header declaring the builtins and the globals block for the current
+    /// mode
+    fn standard_header(&self) -> String {
+        const STD_NUANCE: &str = include_str!("stdlib.glsl");
+        STD_NUANCE.replace(BUILTINS_MARKER, &self.builtins).replace(
+            GlobalsMode::PushConstants.glsl_layout(),
+            self.globals_mode.glsl_layout(),
+        )
+    }
+
+    /// Time spent in each step of the last load, partial when it failed
+    pub fn last_times(&self) -> LoadTimes {
+        self.times
+    }
+
+    pub fn add_include_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
     }
 
-    /// Load a shader, this will try to guess its type based on the file extension
-    pub fn load_shader<P: AsRef<Path>>(&mut self, path: P) -> Result<(Shader, ShaderSource)> {
+    /// true if this path is in fact an http(s) url
+    pub fn is_url<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().to_str().map_or(false, |it| {
+            it.starts_with("http://") || it.starts_with("https://")
+        })
+    }
+
+    /// Read a shader file from the filesystem or download it if it's an url.
+    /// Downloads are cached so we can still load the last version if the network is down.
+    fn read(&mut self, path: &Path) -> Result<Vec<u8>, ShaderLoadError> {
+        if !Self::is_url(path) {
+            return Ok(fs::read(path)?);
+        }
+
+        let url = path.to_str().unwrap();
+        info!("Downloading {}", url);
+        let downloaded = ureq::get(url)
+            .call()
+            .map_err(Box::<dyn std::error::Error + Send + Sync>::from)
+            .and_then(|response| {
+                let mut data = Vec::new();
+                response.into_reader().read_to_end(&mut data)?;
+                Ok(data)
+            });
+        match downloaded {
+            Ok(data) => {
+                self.url_cache.insert(url.to_string(), data.clone());
+                Ok(data)
+            }
+            Err(e) => match self.url_cache.get(url) {
+                Some(cached) => {
+                    warn!("Can't download {} ({}), using the cached version", url, e);
+                    Ok(cached.clone())
+                }
+                None => Err(ShaderLoadError::Download {
+                    url: url.to_string(),
+                    source: e,
+                }),
+            },
+        }
+    }
+
+    fn read_to_string(&mut self, path: &Path) -> Result<String, ShaderLoadError> {
+        Ok(String::from_utf8(self.read(path)?)?)
+    }
+
+    /// Load a shader from a file or an url, this will try to guess its type based on the file
+    /// extension
+    pub fn load_shader<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<(Shader, ShaderSource), ShaderLoadError> {
         let path = path.as_ref();
-        // TODO collect all files necessary to compilation for watch
+        self.times = LoadTimes::default();
+        let start = Instant::now();
         match path.extension().and_then(|it| it.to_str()) {
             Some("spv") => {
                 // sry for that terrible thing
-                let data: Vec<u32> = fs::read(path)?.into_iter().map(|i| i as u32).collect();
+                let data: Vec<u32> = self.read(path)?.into_iter().map(|i| i as u32).collect();
+                self.times.read = start.elapsed();
                 // We can't extract metadata from spirv modules
                 Ok((
                     Shader {
                         main: path.to_path_buf(),
                         sources: vec![path.to_path_buf()],
                         metadata: None,
+                        warnings: Vec::new(),
+                        uses_feedback: reflect::uses_feedback(&data),
+                        globals_stages: reflect::globals_stages(&data),
                     },
                     ShaderSource::SpirV(Cow::Owned(data)),
                 ))
             }
             Some("glsl") | Some("frag") => {
                 // Preprocess glsl to extract what we need
-                let mut source = fs::read_to_string(path)?;
-                let metadata = if let Ok((metadata, new)) = preprocessor::extract(&source) {
-                    // We found params and transpiled the code
-                    source = new;
-                    Some(metadata)
-                } else {
+                let mut source = self.read_to_string(path)?;
+                self.times.read = start.elapsed();
+                let start = Instant::now();
+                let metadata = match preprocessor::extract(&source) {
+                    Ok((mut metadata, new)) => {
+                        // We found params and transpiled the code
+                        source = new;
+                        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                        for texture in metadata.textures.iter_mut() {
+                            for path in texture.source.paths_mut() {
+                                *path = dir.join(&path);
+                            }
+                        }
+                        Some(metadata)
+                    }
+                    // The compiler would only complain about the params layout, hiding the reason
+                    Err(e) if e.downcast_ref::<preprocessor::InvalidParam>().is_some() => {
+                        return Err(ShaderLoadError::Params(e))
+                    }
                     // No params extracted and source isn't modified
-                    None
+                    Err(_) => None,
                 };
+                self.times.preprocess = start.elapsed();
 
-                self.compile_shader(path.to_str().unwrap(), &source, "main")
-                    .map(|it| {
-                        (
-                            Shader {
-                                main: path.to_path_buf(),
-                                sources: vec![path.to_path_buf()],
-                                metadata,
-                            },
-                            it,
-                        )
-                    })
+                let Compiled {
+                    source: compiled,
+                    mut warnings,
+                    includes,
+                } = self.compile_shader(path.to_str().unwrap(), &source, "main")?;
+
+                // Catch params written at the wrong place
+                if let (ShaderSource::SpirV(spirv), Some(metadata)) = (&compiled, metadata.as_ref())
+                {
+                    warnings.extend(reflect::check_params_layout(spirv, metadata));
+                }
+                for warning in warnings.iter() {
+                    warn!("{}", warning);
+                }
+                let (uses_feedback, globals_stages) = match &compiled {
+                    ShaderSource::SpirV(spirv) => (
+                        reflect::uses_feedback(spirv),
+                        reflect::globals_stages(spirv),
+                    ),
+                    _ => (true, DEFAULT_GLOBALS_STAGES),
+                };
+
+                // Editing an include reloads the shader
+                let mut sources = vec![path.to_path_buf()];
+                sources.extend(includes);
+                Ok((
+                    Shader {
+                        main: path.to_path_buf(),
+                        sources,
+                        metadata,
+                        warnings,
+                        uses_feedback,
+                        globals_stages,
+                    },
+                    compiled,
+                ))
+            }
+            Some("wgsl") => {
+                let source = self.read_to_string(path)?;
+                self.times.read = start.elapsed();
+                Ok((
+                    // TODO extract data from wgsl
+                    Shader {
+                        main: path.to_path_buf(),
+                        sources: vec![path.to_path_buf()],
+                        metadata: None,
+                        warnings: Vec::new(),
+                        uses_feedback: true,
+                        globals_stages: DEFAULT_GLOBALS_STAGES,
+                    },
+                    ShaderSource::Wgsl(Cow::Owned(source)),
+                ))
             }
-            Some("wgsl") => Ok((
-                // TODO extract data from wgsl
-                Shader {
-                    main: path.to_path_buf(),
-                    sources: vec![path.to_path_buf()],
-                    metadata: None,
-                },
-                ShaderSource::Wgsl(Cow::Owned(fs::read_to_string(path)?)),
-            )),
-            _ => Err(anyhow!("Unsupported shader format !")),
+            _ => Err(ShaderLoadError::UnsupportedFormat),
         }
     }
 
-    /// Compile a shader from source to spirv in memory
+    /// Compile a shader from source to spirv in memory. Reading the includes counts as reading
+    /// in the load times.
     pub fn compile_shader(
         &mut self,
         name: &str,
         source: &str,
         entrypoint: &str,
-    ) -> Result<ShaderSource<'_>> {
+    ) -> Result<Compiled<'_>, CompileError> {
+        // Declared before the options holding the include callback borrowing them
+        let included_once = RefCell::new(HashSet::new());
+        let includes = RefCell::new(Vec::new());
+        let include_read = Cell::new(Duration::from_nanos(0));
+        let std_nuance = self.standard_header();
         let mut opts = CompileOptions::new().unwrap();
         opts.set_source_language(SourceLanguage::GLSL);
         opts.set_optimization_level(OptimizationLevel::Zero);
@@ -110,74 +430,94 @@ impl ShaderLoader {
         opts.set_forced_version_profile(460, GlslProfile::None);
 
         let include_dirs = &self.include_dirs;
-        opts.set_include_callback(move |name, include_type, source_file, _| {
-            Self::find_include(include_dirs, name, include_type, source_file)
+        // Files marked with #pragma once are only included once per compilation
+        opts.set_include_callback(|name, include_type, source_file, _| {
+            let start = Instant::now();
+            let include =
+                Self::find_include(include_dirs, &std_nuance, name, include_type, source_file);
+            include_read.set(include_read.get() + start.elapsed());
+            let mut include = include?;
+            let path = fs::canonicalize(&include.resolved_name)
+                .unwrap_or_else(|_| PathBuf::from(&include.resolved_name));
+            if path.is_file() && !includes.borrow().contains(&path) {
+                includes.borrow_mut().push(path.clone());
+            }
+            if let Some(content) = strip_pragma_once(&include.content) {
+                include.content = if included_once.borrow_mut().insert(path) {
+                    content
+                } else {
+                    String::new()
+                };
+            }
+            Ok(include)
         });
 
-        let compiled = self.compiler.compile_into_spirv(
-            source,
-            ShaderKind::Fragment,
-            name,
-            entrypoint,
-            Some(&opts),
-        )?;
-
-        if compiled.get_num_warnings() > 0 {
-            warn!(
-                "Compilation warnings : \n{}",
-                compiled.get_warning_messages()
-            );
-        }
+        let start = Instant::now();
+        let compiled = self
+            .compiler
+            .compile_into_spirv(source, ShaderKind::Fragment, name, entrypoint, Some(&opts))
+            .map_err(|e| CompileError {
+                name: name.to_string(),
+                message: e.to_string(),
+                source: source.to_string(),
+            })?;
+
+        // One warning per line, they are logged with the other warnings by the caller
+        let warnings = compiled
+            .get_warning_messages()
+            .lines()
+            .map(str::trim)
+            .filter(|it| !it.is_empty())
+            .map(String::from)
+            .collect();
 
-        Ok(ShaderSource::SpirV(Cow::Owned(
-            compiled.as_binary().to_owned(),
-        )))
+        // Release the borrow of the includes
+        drop(opts);
+        let include_read = include_read.get();
+        self.times.read += include_read;
+        self.times.compile = start.elapsed().saturating_sub(include_read);
+        Ok(Compiled {
+            source: ShaderSource::SpirV(Cow::Owned(compiled.as_binary().to_owned())),
+            warnings,
+            includes: includes.into_inner(),
+        })
     }
 
-    /// Resolve an include with the given name
+    /// Resolve an include with the given name.
+    /// `"name"` is searched next to the including file then in the include dirs,
+    /// `<name>` is one of the standard includes or searched in the include dirs.
     fn find_include(
-        includes: &[String],
+        includes: &[PathBuf],
+        std_nuance: &str,
         name: &str,
         include_type: IncludeType,
         source_file: &str,
     ) -> Result<ResolvedInclude, String> {
+        let search_dirs = || {
+            includes
+                .iter()
+                .map(|dir| dir.join(name))
+                .find(|path| path.exists())
+                .map(|path| Self::read_include(&path))
+        };
         match include_type {
             IncludeType::Relative => {
                 let local_inc = Path::new(source_file).parent().unwrap().join(name);
                 // Search in the shader directory
                 if local_inc.exists() {
-                    Ok(ResolvedInclude {
-                        resolved_name: local_inc.to_str().unwrap().to_string(),
-                        content: fs::read_to_string(&local_inc).map_err(|e| e.to_string())?,
-                    })
+                    Self::read_include(&local_inc)
                 } else {
                     // Search in registered include dirs
-                    includes
-                        .iter()
-                        .find_map(|dir| {
-                            let path = Path::new(dir).join(name);
-                            if path.exists() {
-                                Some(ResolvedInclude {
-                                    resolved_name: path.to_str().unwrap().to_string(),
-                                    content: fs::read_to_string(&path).ok().unwrap(),
-                                })
-                            } else {
-                                None
-                            }
-                        })
-                        .ok_or_else(|| "Include not found !".to_string())
+                    search_dirs().unwrap_or_else(|| Err("Include not found !".to_string()))
                 }
             }
             IncludeType::Standard => {
                 // The nuance standard header
                 match name {
-                    "Nuance" => {
-                        const STD_NUANCE: &str = include_str!("stdlib.glsl");
-                        Ok(ResolvedInclude {
-                            resolved_name: "STD_NUANCE".to_owned(),
-                            content: STD_NUANCE.to_owned(),
-                        })
-                    }
+                    "Nuance" => Ok(ResolvedInclude {
+                        resolved_name: "STD_NUANCE".to_owned(),
+                        content: std_nuance.to_owned(),
+                    }),
                     "noise" => {
                         const STD_NOISE: &str = include_str!("noise.glsl");
                         Ok(ResolvedInclude {
@@ -185,9 +525,48 @@ impl ShaderLoader {
                             content: STD_NOISE.to_owned(),
                         })
                     }
-                    other => Err(format!("No standard include exist with name {}", other)),
+                    "shadertoy" => {
+                        const STD_SHADERTOY: &str = include_str!("shadertoy.glsl");
+                        Ok(ResolvedInclude {
+                            resolved_name: "STD_SHADERTOY".to_owned(),
+                            content: STD_SHADERTOY.to_owned(),
+                        })
+                    }
+                    other => search_dirs().unwrap_or_else(|| {
+                        Err(format!("No standard include exist with name {}", other))
+                    }),
                 }
             }
         }
     }
+
+    fn read_include(path: &Path) -> Result<ResolvedInclude, String> {
+        debug!("Include resolved to {}", path.display());
+        Ok(ResolvedInclude {
+            resolved_name: path.to_str().unwrap().to_string(),
+            content: fs::read_to_string(path).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// Remove the `#pragma once` lines of an included file, None if it doesn't have any.
+/// The lines are kept empty so diagnostics still point to the right line.
+fn strip_pragma_once(content: &str) -> Option<String> {
+    let is_pragma_once = |line: &str| {
+        let line = line.trim();
+        line.starts_with('#')
+            && line[1..]
+                .split_whitespace()
+                .eq(["pragma", "once"].iter().copied())
+    };
+    if !content.lines().any(is_pragma_once) {
+        return None;
+    }
+    Some(
+        content
+            .lines()
+            .map(|line| if is_pragma_once(line) { "" } else { line })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
 }
@@ -1,14 +1,68 @@
 use std::path::PathBuf;
 
+use anyhow::{bail, Context, Result};
 use crevice::std140;
 use crevice::std430::AsStd430;
 use mint::{Vector2, Vector3};
+use wgpu::{AddressMode, Features, FilterMode, ShaderStages, TextureViewDimension};
 
+pub mod builtins;
 pub mod loader;
 pub mod preprocessor;
+pub mod reflect;
 pub mod renderer;
 
-/// The globals we pass to the fragment shader
+/// Device features a shader can require with `#pragma require name`, they are requested when the
+/// adapter supports them
+pub const REQUIRABLE_FEATURES: &[(&str, Features)] = &[
+    ("push_constants", Features::PUSH_CONSTANTS),
+    ("float64", Features::SHADER_FLOAT64),
+    ("primitive_index", Features::SHADER_PRIMITIVE_INDEX),
+    ("clamp_to_border", Features::ADDRESS_MODE_CLAMP_TO_BORDER),
+    // Filtering of 32 bit float textures is an adapter specific format feature
+    (
+        "float32_filterable",
+        Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+    ),
+];
+
+/// All the features a shader can require, to request the ones the adapter supports
+pub fn requirable_features() -> Features {
+    REQUIRABLE_FEATURES
+        .iter()
+        .fold(Features::empty(), |all, (_, it)| all | *it)
+}
+
+/// Fails naming the first feature required by a shader the device doesn't have
+pub fn check_features(names: &[String], features: Features, device_name: &str) -> Result<()> {
+    for name in names {
+        let feature = REQUIRABLE_FEATURES
+            .iter()
+            .find(|(it, _)| *it == name.as_str())
+            .map(|(_, feature)| *feature)
+            .with_context(|| {
+                format!(
+                    "Unknown feature '{}' required, expected one of {}",
+                    name,
+                    REQUIRABLE_FEATURES
+                        .iter()
+                        .map(|(it, _)| *it)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        if !features.contains(feature) {
+            bail!(
+                "The shader requires the {} feature, {} doesn't support it",
+                name,
+                device_name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The globals we pass to the fragment shader, followed by the registered builtins
 #[derive(AsStd430, Clone)]
 pub struct Globals {
     /// Window resolution
@@ -23,26 +77,66 @@ pub struct Globals {
     pub time: f32,
     /// Number of frame
     pub frame: u32,
+    /// Target framerate of the application
+    pub target_fps: f32,
+    /// Scale for aspect corrected coordinates, the shorter axis is 1
+    pub aspect: Vector2<f32>,
+    /// 1 when the origin is at the bottom left
+    pub flip_y: u32,
+    /// Mouse wheel notches, up is positive
+    pub wheel_steps: i32,
+    /// Wall clock time in sec since the app started, keeps running while paused
+    pub real_time: f32,
+    /// Index of the sub-frame being rendered, from 0 to `subframes - 1`
+    pub subframe: u32,
+    /// Number of sub-frames averaged into a displayed frame
+    pub subframes: u32,
+    /// Offset in sec already added to `time` for this sub-frame, 0 for the last one
+    pub subframe_offset: f32,
+    /// Time in sec elapsed since the last frame, each step is at most the max delta setting
+    pub time_delta: f32,
 }
 
 impl Globals {
+    /// Set the resolution and the values derived from it
+    pub fn set_resolution(&mut self, resolution: Vector2<u32>) {
+        self.resolution = resolution;
+        self.ratio = resolution.x as f32 / resolution.y.max(1) as f32;
+        self.aspect = Vector2::from([self.ratio.max(1.0), (1.0 / self.ratio).max(1.0)]);
+    }
+
     pub fn reset(&mut self) {
         self.frame = 0;
         self.time = 0.0;
+        self.time_delta = 0.0;
         self.mouse_wheel = 0.0;
+        self.wheel_steps = 0;
     }
 }
 
 pub enum Slider {
     Float {
         name: String,
+        /// Shown as a tooltip, taken from the comment following the field
+        description: Option<String>,
         min: f32,
         max: f32,
+        /// Use a logarithmic scale in the ui, for ranges spanning many orders of magnitude
+        logarithmic: bool,
+        /// Mouse axis driving the value while ctrl is held
+        mouse_axis: Option<MouseAxis>,
+        /// Expression of the time `t` driving the value, set with `#pragma expr name "expression"`
+        expr: Option<String>,
+        format: ParamFormat,
+        /// The value follows the expression instead of the ui
+        scripted: bool,
         value: f32,
         default: f32,
     },
     Uint {
         name: String,
+        description: Option<String>,
+        format: ParamFormat,
         value: u32,
         min: u32,
         max: u32,
@@ -58,21 +152,27 @@ pub enum Slider {
     },*/
     Bool {
         name: String,
+        description: Option<String>,
         value: u32,
         default: u32,
     },
     Vec2 {
         name: String,
+        description: Option<String>,
+        format: ParamFormat,
         value: Vector2<f32>,
         default: Vector2<f32>,
     },
     Vec3 {
         name: String,
+        description: Option<String>,
+        format: ParamFormat,
         value: Vector3<f32>,
         default: Vector3<f32>,
     },
     Color {
         name: String,
+        description: Option<String>,
         value: Vector3<f32>,
         default: Vector3<f32>,
     },
@@ -85,6 +185,30 @@ pub enum Slider {
     },*/
 }
 
+/// How a param value is shown in the ui, set in its comment with `suffix:"°"` and `decimals:1`
+#[derive(Clone)]
+pub struct ParamFormat {
+    /// Unit written after the value
+    pub suffix: String,
+    /// Maximum number of decimals shown
+    pub decimals: usize,
+}
+
+impl Default for ParamFormat {
+    fn default() -> Self {
+        Self {
+            suffix: String::new(),
+            decimals: 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MouseAxis {
+    X,
+    Y,
+}
+
 macro_rules! reset_impl {
     ($enum:ident, $($item: ident )*) => (
         impl $enum {
@@ -101,13 +225,183 @@ macro_rules! reset_impl {
 
 reset_impl!(Slider, Float Uint Bool Vec2 Vec3 Color);
 
+impl Slider {
+    pub fn name(&self) -> &str {
+        match self {
+            Slider::Float { name, .. }
+            | Slider::Uint { name, .. }
+            | Slider::Bool { name, .. }
+            | Slider::Vec2 { name, .. }
+            | Slider::Vec3 { name, .. }
+            | Slider::Color { name, .. } => name,
+        }
+    }
+
+    /// Set a random value within the param range from uniform samples in [0, 1).
+    /// Vectors have no range and are left untouched.
+    pub fn randomize(&mut self, mut random: impl FnMut() -> f32) {
+        match self {
+            Slider::Float {
+                value,
+                min,
+                max,
+                logarithmic,
+                ..
+            } => {
+                *value = if *logarithmic && *min > 0.0 && *max > 0.0 {
+                    (min.ln() + random() * (max.ln() - min.ln())).exp()
+                } else {
+                    *min + random() * (*max - *min)
+                };
+            }
+            Slider::Uint {
+                value, min, max, ..
+            } => {
+                let range = max.saturating_sub(*min) as f32;
+                *value = *min + (random() * range).round() as u32;
+            }
+            Slider::Bool { value, .. } => {
+                *value = (random() < 0.5) as u32;
+            }
+            Slider::Color { value, .. } => {
+                *value = Vector3::from([random(), random(), random()]);
+            }
+            Slider::Vec2 { .. } | Slider::Vec3 { .. } => {}
+        }
+    }
+
+    /// Declaration of the param in the params block, its current value is the initial value
+    pub fn glsl_field(&self) -> String {
+        let (layout, ty, description, format) = match self {
+            Slider::Float {
+                description,
+                min,
+                max,
+                logarithmic,
+                mouse_axis,
+                format,
+                value,
+                ..
+            } => {
+                let mut layout = format!(
+                    "min = {}, max = {}, init = {}",
+                    glsl_float(*min),
+                    glsl_float(*max),
+                    glsl_float(*value)
+                );
+                if *logarithmic {
+                    layout += ", log";
+                }
+                match mouse_axis {
+                    Some(MouseAxis::X) => layout += ", mouse_x",
+                    Some(MouseAxis::Y) => layout += ", mouse_y",
+                    None => {}
+                }
+                (layout, "float", description, Some(format))
+            }
+            Slider::Uint {
+                description,
+                format,
+                value,
+                min,
+                max,
+                ..
+            } => (
+                format!("min = {}, max = {}, init = {}", min, max, value),
+                "uint",
+                description,
+                Some(format),
+            ),
+            Slider::Bool {
+                description, value, ..
+            } => (format!("init = {}", *value != 0), "bool", description, None),
+            Slider::Vec2 {
+                description,
+                format,
+                value,
+                ..
+            } => (
+                format!(
+                    "init = vec2({}, {})",
+                    glsl_float(value.x),
+                    glsl_float(value.y)
+                ),
+                "vec2",
+                description,
+                Some(format),
+            ),
+            Slider::Vec3 {
+                description,
+                format,
+                value,
+                ..
+            } => (
+                format!("init = {}", glsl_vec3(value)),
+                "vec3",
+                description,
+                Some(format),
+            ),
+            Slider::Color {
+                description, value, ..
+            } => (
+                format!("color, init = {}", glsl_vec3(value)),
+                "vec3",
+                description,
+                None,
+            ),
+        };
+
+        // The comment holds the description and the display settings
+        let mut comment: Vec<String> = description.iter().cloned().collect();
+        if let Some(format) = format {
+            if !format.suffix.is_empty() {
+                comment.push(format!("suffix:\"{}\"", format.suffix));
+            }
+            if format.decimals != ParamFormat::default().decimals {
+                comment.push(format!("decimals:{}", format.decimals));
+            }
+        }
+        let mut field = format!("layout({}) {} {};", layout, ty, self.name());
+        if !comment.is_empty() {
+            field += &format!(" // {}", comment.join(" "));
+        }
+        field
+    }
+}
+
+/// A float literal, with a decimal point so GLSL doesn't take it for an int.
+/// GLSL has no literal for the infinities and NaN, they are clamped to the largest floats and 0.
+fn glsl_float(value: f32) -> String {
+    let value = if value.is_nan() {
+        0.0
+    } else {
+        value.max(-f32::MAX).min(f32::MAX)
+    };
+    let literal = value.to_string();
+    if literal.contains(|c: char| !c.is_ascii_digit() && c != '-') {
+        literal
+    } else {
+        literal + ".0"
+    }
+}
+
+fn glsl_vec3(value: &Vector3<f32>) -> String {
+    format!(
+        "vec3({}, {}, {})",
+        glsl_float(value.x),
+        glsl_float(value.y),
+        glsl_float(value.z)
+    )
+}
+
 macro_rules! write_impl {
     ($align:ident, $enum:ident, $($item:ident )*) => {
         impl $enum {
-            pub fn write<W: std::io::Write>(&self, writer: &mut crevice::$align::Writer<W>) {
+            /// Returns the offset the value was written at
+            pub fn write<W: std::io::Write>(&self, writer: &mut crevice::$align::Writer<W>) -> usize {
                 match self {
                     $($enum::$item { value, .. } => {
-                        writer.write(value).unwrap();
+                        writer.write(value).unwrap()
                     })*
                 }
             }
@@ -117,18 +411,61 @@ macro_rules! write_impl {
 
 write_impl!(std140, Slider, Float Uint Bool Vec2 Vec3 Color);
 
-/// Traverses the ast and extract useful data while converting the ast to valid glsl source
+/// Data extracted from the shader source
 #[derive(Default)]
 pub struct ShaderMetadata {
     pub sliders: Vec<Slider>,
     pub still_image: bool,
+    /// The shader expects the origin at the bottom left, set with `#define NUANCE_FLIP_Y`
+    pub flip_y: bool,
+    /// Restart from the first frame when a param or the mouse changes, set with
+    /// `#pragma reset_on_change`
+    pub reset_on_change: bool,
+    /// Texture channels declared with `#pragma textureN`
+    pub textures: Vec<TextureChannel>,
+    /// Number of previous frames kept, set with `#pragma history N`, 0 is the same as 1
+    pub history: u32,
+    /// Names of the device features declared with `#pragma require name`
+    pub required_features: Vec<String>,
+    /// Number of sub-frames averaged per frame, set with `#pragma subframes N`, 0 is the same as 1
+    pub subframes: u32,
+    /// Number of vec4 in the storage buffer, set with `#pragma storage N`, 0 when there is none
+    pub storage: u32,
+    /// Number of render targets, set with `#pragma targets N`, 0 is the same as 1
+    pub targets: u32,
+    /// Targets keeping their content of the last frame instead of being cleared before the shader
+    /// runs, set with `#pragma targetK clear:false`
+    pub kept_targets: Vec<u32>,
+    /// The shader reads `fRealTime`, it keeps being drawn while paused
+    pub uses_real_time: bool,
 }
 
 impl ShaderMetadata {
+    /// Number of previous frames the shader can sample
+    pub fn history_frames(&self) -> u32 {
+        self.history.max(1)
+    }
+
+    /// Number of outputs of the shader, the first one is the canvas
+    pub fn target_count(&self) -> u32 {
+        self.targets.max(1)
+    }
+
+    /// Number of times the shader runs per displayed frame
+    pub fn subframe_count(&self) -> u32 {
+        self.subframes.max(1)
+    }
+
+    /// Size in bytes of the storage buffer, 0 when there is none
+    pub fn storage_size(&self) -> u64 {
+        self.storage as u64 * 16
+    }
+
     pub fn params_buffer_size(&self) -> u64 {
         self.params_buffer().len() as u64
     }
 
+    /// The params in the std140 layout, empty when there are no params
     pub fn params_buffer(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         let mut writer = std140::Writer::new(&mut bytes);
@@ -140,6 +477,26 @@ impl ShaderMetadata {
         bytes
     }
 
+    /// Offset of each param in the params buffer
+    pub fn params_offsets(&self) -> Vec<usize> {
+        let mut writer = std140::Writer::new(std::io::sink());
+        self.sliders
+            .iter()
+            .map(|slider| slider.write(&mut writer))
+            .collect()
+    }
+
+    /// The params block with the current values as initial values, to paste in a shader so it
+    /// starts from the tuned look
+    pub fn params_glsl(&self) -> String {
+        let fields: String = self
+            .sliders
+            .iter()
+            .map(|slider| format!("    {}\n", slider.glsl_field()))
+            .collect();
+        format!("layout(params) uniform Params {{\n{}}};\n", fields)
+    }
+
     pub fn reset_params(&mut self) {
         for slider in self.sliders.iter_mut() {
             slider.reset();
@@ -147,6 +504,47 @@ impl ShaderMetadata {
     }
 }
 
+/// A texture bound to the shader, declared with
+/// `#pragma textureN "path" [wrap:?] [filter:?] [srgb:?]`,
+/// `#pragma cubemapN "px" "nx" "py" "ny" "pz" "nz" [settings]` or `#pragma keyboardN`
+pub struct TextureChannel {
+    /// The N in textureN, the texture is bound at 2N in set 2 and its sampler at 2N + 1
+    pub index: u32,
+    pub source: ChannelSource,
+    pub wrap: AddressMode,
+    pub filter: FilterMode,
+    /// The images are srgb encoded colors and get decoded to linear values when sampled.
+    /// Data like normal maps must be stored as is, with `srgb:false`.
+    pub srgb: bool,
+}
+
+/// Where the texture data of a channel comes from.
+/// Relative paths are resolved from the shader directory.
+pub enum ChannelSource {
+    Image(PathBuf),
+    /// Faces in the +x, -x, +y, -y, +z, -z order
+    Cubemap(Vec<PathBuf>),
+    /// 256x3 texture of the keyboard state, like the Shadertoy keyboard
+    Keyboard,
+}
+
+impl ChannelSource {
+    pub fn paths_mut(&mut self) -> &mut [PathBuf] {
+        match self {
+            ChannelSource::Image(path) => std::slice::from_mut(path),
+            ChannelSource::Cubemap(faces) => faces,
+            ChannelSource::Keyboard => &mut [],
+        }
+    }
+
+    pub fn view_dimension(&self) -> TextureViewDimension {
+        match self {
+            ChannelSource::Cubemap(_) => TextureViewDimension::Cube,
+            ChannelSource::Image(_) | ChannelSource::Keyboard => TextureViewDimension::D2,
+        }
+    }
+}
+
 pub struct Shader {
     /// Path to the main shader file
     pub main: PathBuf,
@@ -154,4 +552,10 @@ pub struct Shader {
     pub sources: Vec<PathBuf>,
     /// Shader metadata extracted before compilation
     pub metadata: Option<ShaderMetadata>,
+    /// Problems found after compilation that don't prevent running the shader
+    pub warnings: Vec<String>,
+    /// The shader samples the last frame, the feedback texture can be left out otherwise
+    pub uses_feedback: bool,
+    /// Stages reading the globals and params, from the compiled shader
+    pub globals_stages: ShaderStages,
 }
@@ -1,3 +1,5 @@
+#pragma once
+
 // Generate a pseudo random value from a vec2
 // Source : https://thebookofshaders.com/10/
 float noise(float u) {
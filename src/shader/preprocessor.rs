@@ -2,60 +2,171 @@
 
 use core::panic;
 use std::borrow::Borrow;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::path::PathBuf;
 
 use anyhow::{anyhow, Result};
 use glsl_lang::ast::{
-    FunIdentifier, PreprocessorDefine, TypeQualifier, TypeSpecifier, TypeSpecifierNonArray,
+    ExternalDeclarationData, FunIdentifier, PreprocessorData, PreprocessorDefine,
+    PreprocessorPragma, TypeQualifier, TypeSpecifier, TypeSpecifierNonArray,
 };
 use glsl_lang::{
     ast::{
         Block, Expr, Identifier, IdentifierData, LayoutQualifier, LayoutQualifierSpec, SmolStr,
-        StructFieldSpecifier, TranslationUnit, TypeQualifierSpec,
+        StructFieldSpecifier, TranslationUnit, TypeQualifierSpec, UnaryOp,
     },
-    parse::{Parsable, ParseOptions},
+    parse::{CommentList, Parsable, ParseOptions},
     transpiler::glsl::FormattingState,
     visitor::{HostMut, Visit, VisitorMut},
 };
 use log::error;
 use mint::{Vector2, Vector3};
+use wgpu::{AddressMode, FilterMode};
 
-use crate::{ShaderMetadata, Slider};
+use crate::renderer::PREVIOUS_TARGETS_BINDING;
+use crate::{ChannelSource, MouseAxis, ParamFormat, ShaderMetadata, Slider, TextureChannel};
 
-impl VisitorMut for ShaderMetadata {
+/// Maximum number of previous frames a shader can keep
+const MAX_HISTORY: u32 = 4;
+
+/// Maximum number of vec4 in the storage buffer, 1 MiB
+const MAX_STORAGE: u32 = 65536;
+
+/// Maximum number of render targets, the guaranteed number of color attachments
+pub const MAX_TARGETS: u32 = 4;
+
+/// Maximum number of sub-frames rendered per frame
+const MAX_SUBFRAMES: u32 = 64;
+
+/// A field of the params block can't be made into a param, the context of the setting error
+#[derive(Debug)]
+pub struct InvalidParam(pub String);
+
+impl Display for InvalidParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid param {}", self.0)
+    }
+}
+
+/// Walks the ast to fill the metadata
+struct Extractor<'a> {
+    metadata: ShaderMetadata,
+    /// The original source, to locate comments relative to nodes
+    source: &'a str,
+    comments: CommentList,
+    /// The first invalid param, the visitor can't return it
+    error: Option<anyhow::Error>,
+}
+
+impl Extractor<'_> {
+    /// Text of the comment following a field on the same line
+    fn field_description(&self, field: &StructFieldSpecifier) -> Option<String> {
+        let span = field.identifiers.last()?.ident.span?;
+        let rest = &self.source[span.end..];
+        let line_end = span.end + rest.find('\n').unwrap_or_else(|| rest.len());
+        self.comments
+            .iter()
+            .find(|(comment_span, _)| {
+                comment_span.start >= span.end && comment_span.start < line_end
+            })
+            .map(|(_, comment)| comment.text().trim().to_string())
+            .filter(|text| !text.is_empty())
+    }
+}
+
+impl VisitorMut for Extractor<'_> {
     fn visit_block(&mut self, block: &mut Block) -> Visit {
-        if let Some(TypeQualifierSpec::Layout(layout)) = block.qualifier.qualifiers.first() {
-            if let Some(LayoutQualifierSpec::Identifier(id, _)) = layout.ids.first() {
-                if id.content.0 == "params" {
-                    // We got the block we searched for
-                    for field in block.fields.iter_mut() {
-                        if let Ok(slider) = create_slider_from_field(field) {
-                            self.sliders.push(slider);
-                            convert_field(field);
-                        } else {
-                            panic!("Invalid field");
-                        }
+        if let Some(position) = params_layout_position(&block.qualifier) {
+            // We got the block we searched for
+            for field in block.fields.iter_mut() {
+                let (description, format) = match self.field_description(field) {
+                    Some(comment) => param_format(&comment),
+                    None => (None, ParamFormat::default()),
+                };
+                match create_slider_from_field(field, description, format) {
+                    Ok(slider) => {
+                        self.metadata.sliders.push(slider);
+                        convert_field(field);
+                    }
+                    Err(e) => {
+                        let name = field
+                            .identifiers
+                            .first()
+                            .map(|it| it.ident.content.0.to_string());
+                        self.error
+                            .get_or_insert(e.context(InvalidParam(name.unwrap_or_default())));
+                        return Visit::Parent;
                     }
-                    convert_params_block(block);
                 }
             }
+            convert_params_block(block, position);
         }
         Visit::Parent
     }
 
     fn visit_preprocessor_define(&mut self, define: &mut PreprocessorDefine) -> Visit {
         if let PreprocessorDefine::ObjectLike { ident, .. } = define {
-            if ident.content.0.as_str() == "NUANCE_STILL_IMAGE" {
-                self.still_image = true;
+            match ident.content.0.as_str() {
+                "NUANCE_STILL_IMAGE" => self.metadata.still_image = true,
+                "NUANCE_FLIP_Y" => self.metadata.flip_y = true,
+                _ => {}
+            }
+        }
+        Visit::Parent
+    }
+
+    fn visit_preprocessor_pragma(&mut self, pragma: &mut PreprocessorPragma) -> Visit {
+        let args = pragma_args(&pragma.command);
+        match args.as_slice() {
+            [command] if command == "reset_on_change" => self.metadata.reset_on_change = true,
+            [command, features @ ..] if command == "require" => {
+                self.metadata.required_features.extend_from_slice(features)
+            }
+            [command, rest @ ..] if command == "subframes" => match rest {
+                [count] => match count.parse::<u32>() {
+                    Ok(count) if (1..=MAX_SUBFRAMES).contains(&count) => {
+                        self.metadata.subframes = count
+                    }
+                    _ => error!(
+                        "Invalid pragma, expected subframes N with N from 1 to {}",
+                        MAX_SUBFRAMES
+                    ),
+                },
+                _ => error!("Invalid pragma, expected subframes N"),
+            },
+            [command, rest @ ..]
+                if command.strip_prefix("target").map_or(false, |index| {
+                    !index.is_empty() && index.chars().all(|c| c.is_ascii_digit())
+                }) =>
+            {
+                let target: u32 = command["target".len()..].parse().unwrap_or(u32::MAX);
+                let kept = &mut self.metadata.kept_targets;
+                match rest {
+                    [setting] if setting == "clear:false" => {
+                        if !kept.contains(&target) {
+                            kept.push(target);
+                        }
+                    }
+                    [setting] if setting == "clear:true" => kept.retain(|it| *it != target),
+                    _ => error!("Invalid pragma, expected targetK clear:true|false"),
+                }
             }
+            _ => {}
         }
         Visit::Parent
     }
 
     fn visit_expr(&mut self, expr: &mut Expr) -> Visit {
+        if let Expr::Variable(ident) = expr {
+            if ident.content.0 == "fRealTime" {
+                self.metadata.uses_real_time = true;
+            }
+        }
         if let Expr::Dot(expr2, ident1) = expr {
             if let Expr::Variable(ident0) = expr2.as_ref() {
                 let slider_name = ident0.content.0.as_str();
-                for slider in self.sliders.iter() {
+                for slider in self.metadata.sliders.iter() {
                     match slider {
                         Slider::Float {
                             name,
@@ -126,7 +237,11 @@ impl VisitorMut for ShaderMetadata {
     }
 }
 
-pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider> {
+pub fn create_slider_from_field(
+    field: &StructFieldSpecifier,
+    description: Option<String>,
+    format: ParamFormat,
+) -> Result<Slider> {
     let name = field
         .identifiers
         .first()
@@ -144,26 +259,33 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             let mut min = 0.0;
             let mut max = 1.0;
             let mut init = 0.0;
+            let mut logarithmic = false;
+            let mut mouse_axis = None;
 
-            if let Some(TypeQualifier { qualifiers }) = field.qualifier.as_ref() {
-                if let TypeQualifierSpec::Layout(LayoutQualifier { ids }) =
-                    qualifiers.first().unwrap()
-                {
-                    for qualifier in ids.iter() {
-                        if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
-                            match id.content.0.as_str() {
-                                "min" => {
-                                    min = param.as_ref().unwrap().coerce_const();
-                                }
-                                "max" => {
-                                    max = param.as_ref().unwrap().coerce_const();
-                                }
-                                "init" => {
-                                    init = param.as_ref().unwrap().coerce_const();
-                                }
-                                other => {
-                                    error!("Wrong slider setting : {}", other)
-                                }
+            if let Some(LayoutQualifier { ids }) = field_layout(field) {
+                for qualifier in ids.iter() {
+                    if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
+                        match id.content.0.as_str() {
+                            "log" => {
+                                logarithmic = true;
+                            }
+                            "mouse_x" => {
+                                mouse_axis = Some(MouseAxis::X);
+                            }
+                            "mouse_y" => {
+                                mouse_axis = Some(MouseAxis::Y);
+                            }
+                            "min" => {
+                                min = setting_value("min", param)?.coerce_const()?;
+                            }
+                            "max" => {
+                                max = setting_value("max", param)?.coerce_const()?;
+                            }
+                            "init" => {
+                                init = setting_value("init", param)?.coerce_const()?;
+                            }
+                            other => {
+                                error!("Wrong slider setting : {}", other)
                             }
                         }
                     }
@@ -171,8 +293,14 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             }
             return Ok(Slider::Float {
                 name,
+                description,
                 min,
                 max,
+                logarithmic,
+                mouse_axis,
+                expr: None,
+                scripted: false,
+                format,
                 value: init,
                 default: init,
             });
@@ -183,23 +311,19 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             let mut max = 100;
             let mut init = 0;
 
-            if let Some(TypeQualifier { qualifiers }) = field.qualifier.as_ref() {
-                if let TypeQualifierSpec::Layout(LayoutQualifier { ids }) =
-                    qualifiers.first().unwrap()
-                {
-                    for qualifier in ids.iter() {
-                        if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
-                            match id.content.0.as_str() {
-                                "min" => min = param.as_ref().unwrap().coerce_const(),
-                                "max" => {
-                                    max = param.as_ref().unwrap().coerce_const();
-                                }
-                                "init" => {
-                                    init = param.as_ref().unwrap().coerce_const();
-                                }
-                                other => {
-                                    error!("Wrong slider setting : {}", other)
-                                }
+            if let Some(LayoutQualifier { ids }) = field_layout(field) {
+                for qualifier in ids.iter() {
+                    if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
+                        match id.content.0.as_str() {
+                            "min" => min = setting_value("min", param)?.coerce_const()?,
+                            "max" => {
+                                max = setting_value("max", param)?.coerce_const()?;
+                            }
+                            "init" => {
+                                init = setting_value("init", param)?.coerce_const()?;
+                            }
+                            other => {
+                                error!("Wrong slider setting : {}", other)
                             }
                         }
                     }
@@ -207,6 +331,8 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             }
             return Ok(Slider::Uint {
                 name,
+                description,
+                format,
                 min,
                 max,
                 value: init,
@@ -216,41 +342,39 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
         TypeSpecifierNonArray::Vec2 => {
             let mut init: Vector2<f32> = Vector2::from([0.0, 0.0]);
 
-            if let Some(TypeQualifier { qualifiers }) = field.qualifier.as_ref() {
-                if let TypeQualifierSpec::Layout(LayoutQualifier { ids }) =
-                    qualifiers.first().unwrap()
-                {
-                    for qualifier in ids.iter() {
-                        if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
-                            match id.content.0.as_str() {
-                                "init" => {
-                                    if let Some(Expr::FunCall(
-                                        FunIdentifier::TypeSpecifier(TypeSpecifier { ty, .. }),
-                                        params,
-                                    )) = param.as_deref()
-                                    {
-                                        if *ty == TypeSpecifierNonArray::Vec2 && params.len() == 2 {
-                                            init = Vector2::from([
-                                                params[0].coerce_const(),
-                                                params[1].coerce_const(),
-                                            ]);
-                                            continue;
-                                        }
-                                        error!("Invalid initializer !");
+            if let Some(LayoutQualifier { ids }) = field_layout(field) {
+                for qualifier in ids.iter() {
+                    if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
+                        match id.content.0.as_str() {
+                            "init" => {
+                                if let Expr::FunCall(
+                                    FunIdentifier::TypeSpecifier(TypeSpecifier { ty, .. }),
+                                    params,
+                                ) = setting_value("init", param)?
+                                {
+                                    if *ty == TypeSpecifierNonArray::Vec2 && params.len() == 2 {
+                                        init = Vector2::from([
+                                            params[0].coerce_const()?,
+                                            params[1].coerce_const()?,
+                                        ]);
+                                        continue;
                                     }
+                                    error!("Invalid initializer !");
                                 }
-                                other => {
-                                    error!("Unsupported setting : {}", other)
-                                }
                             }
-                        } else {
-                            error!("Invalid qualifier shared");
+                            other => {
+                                error!("Unsupported setting : {}", other)
+                            }
                         }
+                    } else {
+                        error!("Invalid qualifier shared");
                     }
                 }
             }
             return Ok(Slider::Vec2 {
                 name,
+                description,
+                format,
                 value: init,
                 default: init,
             });
@@ -260,52 +384,51 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             let mut init: Vector3<f32> = Vector3::from([0.0, 0.0, 0.0]);
             let mut color = false;
 
-            if let Some(TypeQualifier { qualifiers }) = field.qualifier.as_ref() {
-                if let TypeQualifierSpec::Layout(LayoutQualifier { ids }) =
-                    qualifiers.first().unwrap()
-                {
-                    for qualifier in ids.iter() {
-                        if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
-                            match id.content.0.as_str() {
-                                "color" => {
-                                    color = true;
-                                }
-                                "init" => {
-                                    if let Some(Expr::FunCall(
-                                        FunIdentifier::TypeSpecifier(TypeSpecifier { ty, .. }),
-                                        params,
-                                    )) = param.as_deref()
-                                    {
-                                        if *ty == TypeSpecifierNonArray::Vec3 && params.len() == 3 {
-                                            init = Vector3::from([
-                                                params[0].coerce_const(),
-                                                params[1].coerce_const(),
-                                                params[2].coerce_const(),
-                                            ]);
-                                            continue;
-                                        }
-                                        error!("Invalid initializer !");
+            if let Some(LayoutQualifier { ids }) = field_layout(field) {
+                for qualifier in ids.iter() {
+                    if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
+                        match id.content.0.as_str() {
+                            "color" => {
+                                color = true;
+                            }
+                            "init" => {
+                                if let Expr::FunCall(
+                                    FunIdentifier::TypeSpecifier(TypeSpecifier { ty, .. }),
+                                    params,
+                                ) = setting_value("init", param)?
+                                {
+                                    if *ty == TypeSpecifierNonArray::Vec3 && params.len() == 3 {
+                                        init = Vector3::from([
+                                            params[0].coerce_const()?,
+                                            params[1].coerce_const()?,
+                                            params[2].coerce_const()?,
+                                        ]);
+                                        continue;
                                     }
-                                }
-                                other => {
-                                    error!("Unsupported setting : {}", other)
+                                    error!("Invalid initializer !");
                                 }
                             }
-                        } else {
-                            error!("Invalid qualifier shared");
+                            other => {
+                                error!("Unsupported setting : {}", other)
+                            }
                         }
+                    } else {
+                        error!("Invalid qualifier shared");
                     }
                 }
             }
             return Ok(if color {
                 Slider::Color {
                     name,
+                    description,
                     value: init,
                     default: init,
                 }
             } else {
                 Slider::Vec3 {
                     name,
+                    description,
+                    format,
                     value: init,
                     default: init,
                 }
@@ -314,24 +437,20 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
         TypeSpecifierNonArray::Bool => {
             let mut init = 0;
 
-            if let Some(TypeQualifier { qualifiers }) = field.qualifier.as_ref() {
-                if let TypeQualifierSpec::Layout(LayoutQualifier { ids }) =
-                    qualifiers.first().unwrap()
-                {
-                    for qualifier in ids.iter() {
-                        if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
-                            match id.content.0.as_str() {
-                                "init" => match param.as_ref().unwrap().as_ref() {
-                                    Expr::BoolConst(value) => {
-                                        init = if *value { 1 } else { 0 };
-                                    }
-                                    _ => {
-                                        error!("Expected boolean value");
-                                    }
-                                },
-                                other => {
-                                    error!("Wrong slider setting : {}", other);
+            if let Some(LayoutQualifier { ids }) = field_layout(field) {
+                for qualifier in ids.iter() {
+                    if let LayoutQualifierSpec::Identifier(id, param) = qualifier {
+                        match id.content.0.as_str() {
+                            "init" => match setting_value("init", param)? {
+                                Expr::BoolConst(value) => {
+                                    init = if *value { 1 } else { 0 };
                                 }
+                                _ => {
+                                    error!("Expected boolean value");
+                                }
+                            },
+                            other => {
+                                error!("Wrong slider setting : {}", other);
                             }
                         }
                     }
@@ -339,18 +458,78 @@ pub fn create_slider_from_field(field: &StructFieldSpecifier) -> Result<Slider>
             }
             return Ok(Slider::Bool {
                 name,
+                description,
                 value: init,
                 default: init,
             });
         }
         _ => {}
     }
-    Err(anyhow!("Invalid field in params block"))
+    Err(anyhow!("Unsupported type"))
+}
+
+/// Value of a `setting = value` param setting, an error when the value is missing like in
+/// `layout(min)`
+fn setting_value<'a>(setting: &str, value: &'a Option<Box<Expr>>) -> Result<&'a Expr> {
+    value
+        .as_deref()
+        .ok_or_else(|| anyhow!("The {} setting needs a value like {} = 1", setting, setting))
+}
+
+/// Take the `suffix:"unit"` and `decimals:N` settings out of a param comment, the rest is the
+/// description
+fn param_format(comment: &str) -> (Option<String>, ParamFormat) {
+    let mut format = ParamFormat::default();
+    let mut description = Vec::new();
+    let mut rest = comment.trim_start();
+    while !rest.is_empty() {
+        if let Some((suffix, after)) = rest
+            .strip_prefix("suffix:\"")
+            .and_then(|it| it.split_once('"'))
+        {
+            format.suffix = suffix.to_string();
+            rest = after.trim_start();
+            continue;
+        }
+        let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        match word.strip_prefix("decimals:").map(str::parse) {
+            Some(Ok(decimals)) => format.decimals = decimals,
+            _ => description.push(word),
+        }
+        rest = after.trim_start();
+    }
+    let description = Some(description.join(" ")).filter(|it| !it.is_empty());
+    (description, format)
+}
+
+/// Find the position of the layout qualifier marking the params block.
+/// `params` can be anywhere in the layout qualifier, and the layout qualifier can be anywhere in
+/// the block qualifiers.
+fn params_layout_position(qualifier: &TypeQualifier) -> Option<usize> {
+    qualifier.qualifiers.iter().position(|spec| match spec {
+        TypeQualifierSpec::Layout(LayoutQualifier { ids }) => ids.iter().any(
+            |id| matches!(id, LayoutQualifierSpec::Identifier(id, _) if id.content.0 == "params"),
+        ),
+        _ => false,
+    })
+}
+
+/// Get the layout qualifier of a field wherever it is placed among the field qualifiers
+fn field_layout(field: &StructFieldSpecifier) -> Option<&LayoutQualifier> {
+    field
+        .qualifier
+        .as_ref()?
+        .qualifiers
+        .iter()
+        .find_map(|spec| match spec {
+            TypeQualifierSpec::Layout(layout) => Some(layout),
+            _ => None,
+        })
 }
 
 /// Replace the layout(params) with a predefined layout(set=?, binding=?)
-pub fn convert_params_block(block: &mut Block) {
-    block.qualifier.qualifiers[0] = TypeQualifierSpec::Layout(LayoutQualifier {
+pub fn convert_params_block(block: &mut Block, position: usize) {
+    block.qualifier.qualifiers[position] = TypeQualifierSpec::Layout(LayoutQualifier {
         ids: vec![
             LayoutQualifierSpec::Identifier(
                 Identifier {
@@ -377,27 +556,62 @@ pub fn convert_params_block(block: &mut Block) {
     });
 }
 
-/// Replace the layout(min=?, max=?) with nothing
+/// Replace the layout(min=?, max=?) with nothing, other qualifiers are kept
 pub fn convert_field(field: &mut StructFieldSpecifier) {
-    field.qualifier = None;
+    if let Some(qualifier) = field.qualifier.as_mut() {
+        qualifier
+            .qualifiers
+            .retain(|spec| !matches!(spec, TypeQualifierSpec::Layout(_)));
+        if qualifier.qualifiers.is_empty() {
+            field.qualifier = None;
+        }
+    }
 }
 
 pub fn extract(source: &str) -> Result<(ShaderMetadata, String)> {
-    let mut metadata = ShaderMetadata::default();
-
     // The AST
-    let (mut ast, _ctx) = TranslationUnit::parse_with_options(
+    let (mut ast, ctx) = TranslationUnit::parse_with_options(
         source,
         &ParseOptions {
             target_vulkan: true,
             source_id: 0,
             allow_rs_ident: false,
         }
-        .build(),
+        .with_comments(),
     )?;
 
+    let mut extractor = Extractor {
+        metadata: ShaderMetadata::default(),
+        source,
+        comments: ctx
+            .into_data()
+            .and_then(|data| data.comments)
+            .unwrap_or_default(),
+        error: None,
+    };
+
     // Extract some ast juice
-    ast.visit_mut(&mut metadata);
+    ast.visit_mut(&mut extractor);
+    if let Some(e) = extractor.error {
+        return Err(e);
+    }
+    let mut metadata = extractor.metadata;
+    declare_textures(&mut ast, &mut metadata.textures)?;
+    declare_history(&mut ast, &mut metadata.history)?;
+    declare_storage(&mut ast, &mut metadata.storage)?;
+    declare_targets(&mut ast, &mut metadata.targets)?;
+    let target_count = metadata.target_count();
+    metadata.kept_targets.retain(|target| {
+        let valid = *target < target_count;
+        if !valid {
+            error!(
+                "Invalid pragma, target{} doesn't exist, the shader has {} targets",
+                target, target_count
+            );
+        }
+        valid
+    });
+    bind_param_exprs(&ast, &mut metadata.sliders);
 
     let mut transpiled = String::new();
     glsl_lang::transpiler::glsl::show_translation_unit(
@@ -408,23 +622,395 @@ pub fn extract(source: &str) -> Result<(ShaderMetadata, String)> {
     Ok((metadata, transpiled))
 }
 
+/// Replace the texture pragmas with the declarations of their texture and sampler
+fn declare_textures(ast: &mut TranslationUnit, textures: &mut Vec<TextureChannel>) -> Result<()> {
+    let mut i = 0;
+    while i < ast.0.len() {
+        let channel = match &*ast.0[i] {
+            ExternalDeclarationData::Preprocessor(preprocessor) => match &**preprocessor {
+                PreprocessorData::Pragma(pragma) => parse_texture_pragma(&pragma.command),
+                _ => None,
+            },
+            _ => None,
+        };
+        let channel = match channel {
+            Some(Ok(channel)) => channel,
+            Some(Err(e)) => {
+                error!("{}", e);
+                i += 1;
+                continue;
+            }
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let (name, ty) = match channel.source {
+            ChannelSource::Image(_) => ("texture", "texture2D"),
+            ChannelSource::Cubemap(_) => ("cubemap", "textureCube"),
+            ChannelSource::Keyboard => ("keyboard", "texture2D"),
+        };
+        let (declarations, _) = TranslationUnit::parse_with_options(
+            &format!(
+                "layout(set = 2, binding = {}) uniform {} {}{};\n\
+                 layout(set = 2, binding = {}) uniform sampler {}{}Sampler;",
+                2 * channel.index,
+                ty,
+                name,
+                channel.index,
+                2 * channel.index + 1,
+                name,
+                channel.index
+            ),
+            &ParseOptions {
+                target_vulkan: true,
+                ..Default::default()
+            }
+            .build(),
+        )?;
+        let count = declarations.0.len();
+        ast.0.splice(i..=i, declarations.0);
+        i += count;
+        textures.push(channel);
+    }
+    Ok(())
+}
+
+/// Replace the `history N` pragma with the declarations of the frames older than the last one,
+/// `previousFrameK` is the frame rendered K frames ago
+fn declare_history(ast: &mut TranslationUnit, history: &mut u32) -> Result<()> {
+    let mut i = 0;
+    while i < ast.0.len() {
+        // None when this isn't a history pragma, Some(None) when it's invalid
+        let frames = match &*ast.0[i] {
+            ExternalDeclarationData::Preprocessor(preprocessor) => match &**preprocessor {
+                PreprocessorData::Pragma(pragma) => match pragma_args(&pragma.command).as_slice() {
+                    [pragma, frames] if pragma == "history" => Some(frames.parse::<u32>().ok()),
+                    [pragma, ..] if pragma == "history" => Some(None),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let frames = match frames {
+            Some(Some(frames)) if (1..=MAX_HISTORY).contains(&frames) && *history == 0 => frames,
+            Some(_) => {
+                error!(
+                    "Invalid pragma, expected a single history N with N from 1 to {}",
+                    MAX_HISTORY
+                );
+                i += 1;
+                continue;
+            }
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        *history = frames;
+        // The last frame is always declared by the standard header
+        if frames == 1 {
+            ast.0.remove(i);
+            continue;
+        }
+
+        let declarations: String = (2..=frames)
+            .map(|age| {
+                format!(
+                    "layout(set = 0, binding = {}) uniform texture2D previousFrame{};\n\
+                     layout(set = 0, binding = {}) uniform sampler previousFrame{}Sampler;\n",
+                    2 * (age - 1),
+                    age,
+                    2 * (age - 1) + 1,
+                    age
+                )
+            })
+            .collect();
+        let (declarations, _) = TranslationUnit::parse_with_options(
+            &declarations,
+            &ParseOptions {
+                target_vulkan: true,
+                ..Default::default()
+            }
+            .build(),
+        )?;
+        let count = declarations.0.len();
+        ast.0.splice(i..=i, declarations.0);
+        i += count;
+    }
+    Ok(())
+}
+
+/// Replace the `storage N` pragma with the declaration of the storage buffer, an array of N vec4
+/// named `storage`
+fn declare_storage(ast: &mut TranslationUnit, storage: &mut u32) -> Result<()> {
+    let mut i = 0;
+    while i < ast.0.len() {
+        // None when this isn't a storage pragma, Some(None) when it's invalid
+        let len = match &*ast.0[i] {
+            ExternalDeclarationData::Preprocessor(preprocessor) => match &**preprocessor {
+                PreprocessorData::Pragma(pragma) => match pragma_args(&pragma.command).as_slice() {
+                    [pragma, len] if pragma == "storage" => Some(len.parse::<u32>().ok()),
+                    [pragma, ..] if pragma == "storage" => Some(None),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let len = match len {
+            Some(Some(len)) if (1..=MAX_STORAGE).contains(&len) && *storage == 0 => len,
+            Some(_) => {
+                error!(
+                    "Invalid pragma, expected a single storage N with N from 1 to {}",
+                    MAX_STORAGE
+                );
+                i += 1;
+                continue;
+            }
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        *storage = len;
+
+        let (declarations, _) = TranslationUnit::parse_with_options(
+            &format!(
+                "layout(std430, set = 3, binding = 0) buffer StorageBlock {{ vec4 storage[{}]; }};",
+                len
+            ),
+            &ParseOptions {
+                target_vulkan: true,
+                ..Default::default()
+            }
+            .build(),
+        )?;
+        let count = declarations.0.len();
+        ast.0.splice(i..=i, declarations.0);
+        i += count;
+    }
+    Ok(())
+}
+
+/// Replace the `targets N` pragma with the declarations of the outputs after `fragColor`,
+/// `fragColorK` is written to target K and `previousColorK` is its last frame
+fn declare_targets(ast: &mut TranslationUnit, targets: &mut u32) -> Result<()> {
+    let mut i = 0;
+    while i < ast.0.len() {
+        // None when this isn't a targets pragma, Some(None) when it's invalid
+        let count = match &*ast.0[i] {
+            ExternalDeclarationData::Preprocessor(preprocessor) => match &**preprocessor {
+                PreprocessorData::Pragma(pragma) => match pragma_args(&pragma.command).as_slice() {
+                    [pragma, count] if pragma == "targets" => Some(count.parse::<u32>().ok()),
+                    [pragma, ..] if pragma == "targets" => Some(None),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        let count = match count {
+            Some(Some(count)) if (1..=MAX_TARGETS).contains(&count) && *targets == 0 => count,
+            Some(_) => {
+                error!(
+                    "Invalid pragma, expected a single targets N with N from 1 to {}",
+                    MAX_TARGETS
+                );
+                i += 1;
+                continue;
+            }
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        *targets = count;
+        // The first target is always declared by the standard header
+        if count == 1 {
+            ast.0.remove(i);
+            continue;
+        }
+
+        let declarations: String = (1..count)
+            .map(|target| {
+                let binding = PREVIOUS_TARGETS_BINDING + 2 * (target - 1);
+                format!(
+                    "layout(location = {}) out vec4 fragColor{};\n\
+                     layout(set = 0, binding = {}) uniform texture2D previousColor{};\n\
+                     layout(set = 0, binding = {}) uniform sampler previousColor{}Sampler;\n",
+                    target,
+                    target,
+                    binding,
+                    target,
+                    binding + 1,
+                    target
+                )
+            })
+            .collect();
+        let (declarations, _) = TranslationUnit::parse_with_options(
+            &declarations,
+            &ParseOptions {
+                target_vulkan: true,
+                ..Default::default()
+            }
+            .build(),
+        )?;
+        let count = declarations.0.len();
+        ast.0.splice(i..=i, declarations.0);
+        i += count;
+    }
+    Ok(())
+}
+
+/// Attach the `expr name "expression"` pragmas to their float param.
+/// The pragmas are left in place, glslang ignores them.
+fn bind_param_exprs(ast: &TranslationUnit, sliders: &mut [Slider]) {
+    for decl in ast.0.iter() {
+        let command = match &**decl {
+            ExternalDeclarationData::Preprocessor(preprocessor) => match &**preprocessor {
+                PreprocessorData::Pragma(pragma) => &pragma.command,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let args = pragma_args(command);
+        let (name, expression) = match args.as_slice() {
+            [pragma, name, expression] if pragma == "expr" => (name, expression),
+            [pragma, ..] if pragma == "expr" => {
+                error!(
+                    "Invalid pragma, expected expr name \"expression\" : {}",
+                    command
+                );
+                continue;
+            }
+            _ => continue,
+        };
+        let slider = sliders.iter_mut().find(|slider| match slider {
+            Slider::Float { name: slider, .. } => slider == name,
+            _ => false,
+        });
+        match slider {
+            Some(Slider::Float { expr, scripted, .. }) => {
+                *expr = Some(expression.clone());
+                *scripted = true;
+            }
+            _ => error!("No float param named {} to bind an expression to", name),
+        }
+    }
+}
+
+/// Parse a `textureN "path" [wrap:repeat|clamp|mirror] [filter:linear|nearest] [srgb:true|false]`
+/// or a `cubemapN "px" "nx" "py" "ny" "pz" "nz" [settings]` or a `keyboardN` pragma.
+/// Returns None if this isn't a texture pragma.
+fn parse_texture_pragma(command: &str) -> Option<Result<TextureChannel>> {
+    let args = pragma_args(command);
+    let first = args.first()?;
+    let (name, path_count, index) = if let Some(index) = first.strip_prefix("texture") {
+        ("texture", 1, index)
+    } else if let Some(index) = first.strip_prefix("cubemap") {
+        ("cubemap", 6, index)
+    } else if let Some(index) = first.strip_prefix("keyboard") {
+        ("keyboard", 0, index)
+    } else {
+        return None;
+    };
+    let index: u32 = index.parse().ok()?;
+
+    if args.len() < 1 + path_count {
+        return Some(Err(anyhow!(
+            "{}{} : expected {} image paths",
+            name,
+            index,
+            path_count
+        )));
+    }
+    let mut paths: Vec<PathBuf> = args[1..=path_count].iter().map(PathBuf::from).collect();
+    let source = match path_count {
+        0 => ChannelSource::Keyboard,
+        1 => ChannelSource::Image(paths.remove(0)),
+        _ => ChannelSource::Cubemap(paths),
+    };
+    // Defaults match Shadertoy
+    let mut channel = TextureChannel {
+        index,
+        source,
+        wrap: AddressMode::Repeat,
+        filter: FilterMode::Linear,
+        srgb: true,
+    };
+    for arg in args.iter().skip(1 + path_count) {
+        match arg.split_once(':') {
+            Some(("wrap", "repeat")) => channel.wrap = AddressMode::Repeat,
+            Some(("wrap", "clamp")) => channel.wrap = AddressMode::ClampToEdge,
+            Some(("wrap", "mirror")) => channel.wrap = AddressMode::MirrorRepeat,
+            Some(("filter", "linear")) => channel.filter = FilterMode::Linear,
+            Some(("filter", "nearest")) => channel.filter = FilterMode::Nearest,
+            Some(("srgb", "true")) => channel.srgb = true,
+            Some(("srgb", "false")) => channel.srgb = false,
+            _ => {
+                return Some(Err(anyhow!(
+                    "{}{} : invalid setting '{}'",
+                    name,
+                    index,
+                    arg
+                )))
+            }
+        }
+    }
+    Some(Ok(channel))
+}
+
+/// Split the pragma arguments on whitespaces, quoted arguments are kept together
+fn pragma_args(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in command.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    args.push(mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
 trait CoerceConst<T> {
-    fn coerce_const(&self) -> T;
+    fn coerce_const(&self) -> Result<T>;
+}
+
+/// Value of a number constant, in any notation the glsl lexer accepts (`.5`, `1e-3`, `0x10`), with
+/// an optional sign in front
+fn const_value(expr: &Expr) -> Result<f64> {
+    Ok(match expr {
+        Expr::IntConst(value) => *value as f64,
+        Expr::UIntConst(value) => *value as f64,
+        Expr::FloatConst(value) => *value as f64,
+        Expr::DoubleConst(value) => *value,
+        Expr::Unary(UnaryOp::Minus, expr) => -const_value(expr)?,
+        Expr::Unary(UnaryOp::Add, expr) => const_value(expr)?,
+        _ => return Err(anyhow!("Expected a number constant")),
+    })
 }
 
 impl<T> CoerceConst<f32> for T
 where
     T: Borrow<Expr>,
 {
-    fn coerce_const(&self) -> f32 {
+    fn coerce_const(&self) -> Result<f32> {
         match self.borrow() {
-            Expr::IntConst(value) => *value as f32,
-            Expr::UIntConst(value) => *value as f32,
-            Expr::FloatConst(value) => *value,
-            Expr::DoubleConst(value) => *value as f32,
-            _ => {
-                panic!("Not a number constant")
-            }
+            Expr::FloatConst(value) => Ok(*value),
+            expr => const_value(expr).map(|value| value as f32),
         }
     }
 }
@@ -433,16 +1019,8 @@ impl<T> CoerceConst<f64> for T
 where
     T: Borrow<Expr>,
 {
-    fn coerce_const(&self) -> f64 {
-        match self.borrow() {
-            Expr::IntConst(value) => *value as f64,
-            Expr::UIntConst(value) => *value as f64,
-            Expr::FloatConst(value) => *value as f64,
-            Expr::DoubleConst(value) => *value,
-            _ => {
-                panic!("Not a number constant")
-            }
-        }
+    fn coerce_const(&self) -> Result<f64> {
+        const_value(self.borrow())
     }
 }
 
@@ -450,16 +1028,8 @@ impl<T> CoerceConst<i32> for T
 where
     T: Borrow<Expr>,
 {
-    fn coerce_const(&self) -> i32 {
-        match self.borrow() {
-            Expr::IntConst(value) => *value,
-            Expr::UIntConst(value) => *value as i32,
-            Expr::FloatConst(value) => *value as i32,
-            Expr::DoubleConst(value) => *value as i32,
-            _ => {
-                panic!("Not a number constant")
-            }
-        }
+    fn coerce_const(&self) -> Result<i32> {
+        const_value(self.borrow()).map(|value| value as i32)
     }
 }
 
@@ -467,15 +1037,136 @@ impl<T> CoerceConst<u32> for T
 where
     T: Borrow<Expr>,
 {
-    fn coerce_const(&self) -> u32 {
-        match self.borrow() {
-            Expr::IntConst(value) => *value as u32,
-            Expr::UIntConst(value) => *value,
-            Expr::FloatConst(value) => *value as u32,
-            Expr::DoubleConst(value) => *value as u32,
-            _ => {
-                panic!("Not a number constant")
+    fn coerce_const(&self) -> Result<u32> {
+        // Negative values saturate to 0
+        const_value(self.borrow()).map(|value| value as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Params of a shader declaring `fields` in its params block
+    fn params(fields: &str) -> Result<Vec<Slider>> {
+        let source = format!(
+            "layout(params) uniform Params {{\n{}\n}};\nvoid main() {{}}\n",
+            fields
+        );
+        extract(&source).map(|(metadata, _)| metadata.sliders)
+    }
+
+    /// Min, max and init of the single float param
+    fn float_param(fields: &str) -> (f32, f32, f32) {
+        match params(fields).unwrap().as_slice() {
+            [Slider::Float {
+                min, max, default, ..
+            }] => (*min, *max, *default),
+            _ => panic!("Expected a single float param"),
+        }
+    }
+
+    #[test]
+    fn settings_spacing() {
+        let expected = (1.0, 2.0, 1.5);
+        assert_eq!(
+            float_param("layout(min=1,max=2,init=1.5) float value;"),
+            expected
+        );
+        assert_eq!(
+            float_param("layout( min = 1 , max = 2 , init = 1.5 ) float value;"),
+            expected
+        );
+        assert_eq!(
+            float_param("layout(min =1, max= 2, init  =  1.5) float value;"),
+            expected
+        );
+        assert_eq!(
+            float_param("\tlayout(min\t=\t1,\tmax = 2,\tinit = 1.5)\tfloat\tvalue;"),
+            expected
+        );
+        assert_eq!(
+            float_param("layout(min = 1,\n    max = 2,\n    init = 1.5)\nfloat value;"),
+            expected
+        );
+    }
+
+    #[test]
+    fn trailing_comment() {
+        assert_eq!(
+            float_param("layout(min = 1, max = 2, init = 1.5) float value; // The value"),
+            (1.0, 2.0, 1.5)
+        );
+        assert_eq!(
+            float_param("layout(min = 1, max = 2, init = 1.5) float value; /* The value */"),
+            (1.0, 2.0, 1.5)
+        );
+        match params("layout(min = 0, max = 2) float value;\t// The value  ")
+            .unwrap()
+            .as_slice()
+        {
+            [Slider::Float { description, .. }] => {
+                assert_eq!(description.as_deref(), Some("The value"))
             }
+            _ => panic!("Expected a single float param"),
+        }
+    }
+
+    #[test]
+    fn missing_setting_value() {
+        let e = params("layout(min) float value;").err().unwrap();
+        assert!(e.downcast_ref::<InvalidParam>().is_some());
+        assert!(format!("{:#}", e).starts_with("Invalid param value"));
+        assert!(params("layout(min = 0, max) float value;").is_err());
+        assert!(params("layout(init) uint value;").is_err());
+        assert!(params("layout(init) bool value;").is_err());
+        assert!(params("layout(init) vec3 value;").is_err());
+    }
+
+    #[test]
+    fn number_notations() {
+        assert_eq!(
+            float_param("layout(min = 1e-4, max = .5, init = 0x10) float value;"),
+            (1e-4, 0.5, 16.0)
+        );
+        assert_eq!(
+            float_param("layout(min = -1, max = +1, init = -.5) float value;"),
+            (-1.0, 1.0, -0.5)
+        );
+        assert_eq!(
+            float_param("layout(min = -1.5e2, max = 2E+1, init = 1u) float value;"),
+            (-150.0, 20.0, 1.0)
+        );
+        match params("layout(min = +1, max = 0x10, init = -1) uint value;")
+            .unwrap()
+            .as_slice()
+        {
+            [Slider::Uint {
+                min, max, default, ..
+            }] => assert_eq!((*min, *max, *default), (1, 16, 0)),
+            _ => panic!("Expected a single uint param"),
         }
     }
+
+    #[test]
+    fn not_a_number() {
+        assert!(params("layout(init = foo) float value;").is_err());
+        assert!(params("layout(min = 1 + 1) float value;").is_err());
+        assert!(params("layout(max = true) uint value;").is_err());
+        assert!(params("layout(init = vec2(0.0, foo)) vec2 value;").is_err());
+    }
+
+    #[test]
+    fn no_params() {
+        let (metadata, _) = extract("void main() {}\n").unwrap();
+        assert!(metadata.sliders.is_empty());
+        assert!(metadata.params_buffer().is_empty());
+        assert_eq!(metadata.params_buffer_size(), 0);
+        assert!(metadata.params_offsets().is_empty());
+    }
+
+    #[test]
+    fn unsupported_type() {
+        assert!(params("layout(min = 0) mat4 value;").is_err());
+    }
 }
@@ -0,0 +1,226 @@
+//! Cross check of the compiled shader interface with the metadata extracted from the source
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use naga::back::wgsl;
+use naga::front::spv;
+use naga::valid::{Capabilities, GlobalUse, ModuleInfo, ValidationFlags, Validator};
+use naga::{Module, ScalarKind, ShaderStage, StorageClass, StructMember, TypeInner};
+use wgpu::ShaderStages;
+
+use crate::shader::renderer::DEFAULT_GLOBALS_STAGES;
+use crate::shader::{ShaderMetadata, Slider};
+
+/// Bind group of the last frame texture
+const FEEDBACK_GROUP: u32 = 0;
+/// Bind group and binding of the params block
+const PARAMS_GROUP: u32 = 1;
+const PARAMS_BINDING: u32 = 0;
+
+fn parse(spirv: &[u32]) -> Option<Module> {
+    match spv::Parser::new(spirv.iter().cloned(), &spv::Options::default()).parse() {
+        Ok(module) => Some(module),
+        Err(e) => {
+            // Naga doesn't support everything, this isn't the user's fault
+            debug!("Can't reflect the compiled shader : {}", e);
+            None
+        }
+    }
+}
+
+/// Translate a compiled shader to WGSL with naga, like wgpu does on backends without spirv
+pub fn to_wgsl(spirv: &[u32]) -> Result<String> {
+    let module = spv::Parser::new(spirv.iter().cloned(), &spv::Options::default())
+        .parse()
+        .map_err(|e| anyhow!("Naga can't parse the compiled shader : {}", e))?;
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|e| anyhow!("Naga rejected the compiled shader : {}", e))?;
+    wgsl::write_string(&module, &info).map_err(|e| anyhow!("Can't write WGSL : {}", e))
+}
+
+/// The module along with the globals used by each entry point
+fn analyze(spirv: &[u32]) -> Option<(Module, ModuleInfo)> {
+    let module = parse(spirv)?;
+    // Only the usage analysis is needed
+    match Validator::new(ValidationFlags::empty(), Capabilities::all()).validate(&module) {
+        Ok(info) => Some((module, info)),
+        Err(e) => {
+            debug!("Can't analyze the compiled shader : {}", e);
+            None
+        }
+    }
+}
+
+/// Whether the fragment shader samples the last frame, assumed true when it can't be analyzed
+pub fn uses_feedback(spirv: &[u32]) -> bool {
+    let (module, info) = match analyze(spirv) {
+        Some(it) => it,
+        None => return true,
+    };
+
+    module
+        .entry_points
+        .iter()
+        .enumerate()
+        .filter(|(_, ep)| ep.stage == ShaderStage::Fragment)
+        .any(|(i, _)| {
+            let ep_info = info.get_entry_point(i);
+            module.global_variables.iter().any(|(handle, var)| {
+                var.binding
+                    .as_ref()
+                    .map_or(false, |it| it.group == FEEDBACK_GROUP)
+                    && ep_info[handle] != GlobalUse::empty()
+            })
+        })
+}
+
+/// Stages reading the globals push constants or the params set, the fragment stage when the
+/// shader can't be analyzed or reads neither
+pub fn globals_stages(spirv: &[u32]) -> ShaderStages {
+    let (module, info) = match analyze(spirv) {
+        Some(it) => it,
+        None => return DEFAULT_GLOBALS_STAGES,
+    };
+
+    let stages = module
+        .entry_points
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            let ep_info = info.get_entry_point(*i);
+            module.global_variables.iter().any(|(handle, var)| {
+                let globals = var.class == StorageClass::PushConstant
+                    || var
+                        .binding
+                        .as_ref()
+                        .map_or(false, |it| it.group == PARAMS_GROUP);
+                globals && ep_info[handle] != GlobalUse::empty()
+            })
+        })
+        .fold(ShaderStages::empty(), |stages, (_, ep)| {
+            stages
+                | match ep.stage {
+                    ShaderStage::Vertex => ShaderStages::VERTEX,
+                    ShaderStage::Fragment => ShaderStages::FRAGMENT,
+                    ShaderStage::Compute => ShaderStages::COMPUTE,
+                }
+        });
+    if stages.is_empty() {
+        DEFAULT_GLOBALS_STAGES
+    } else {
+        stages
+    }
+}
+
+/// Compare the params block of the compiled module with the extracted params.
+/// When they differ, the values written from the ui end up in the wrong place.
+/// Returns a description of each mismatch.
+pub fn check_params_layout(spirv: &[u32], metadata: &ShaderMetadata) -> Vec<String> {
+    if metadata.sliders.is_empty() {
+        return Vec::new();
+    }
+
+    let module = match parse(spirv) {
+        Some(module) => module,
+        None => return Vec::new(),
+    };
+
+    let members = match params_members(&module) {
+        Some(members) => members,
+        None => return vec!["The params block is missing from the compiled shader".to_string()],
+    };
+
+    let mut warnings = Vec::new();
+    if members.len() != metadata.sliders.len() {
+        warnings.push(format!(
+            "The compiled params block has {} members but {} params were extracted",
+            members.len(),
+            metadata.sliders.len()
+        ));
+    }
+
+    let offsets = metadata.params_offsets();
+    for ((slider, offset), member) in metadata.sliders.iter().zip(offsets).zip(members) {
+        let name = slider.name();
+        if member.name.as_deref() != Some(name) {
+            warnings.push(format!(
+                "Param '{}' is named '{}' in the compiled shader",
+                name,
+                member.name.as_deref().unwrap_or("?")
+            ));
+        }
+
+        let compiled_type = describe_type(&module.types[member.ty].inner);
+        if !expected_types(slider).contains(&compiled_type.as_str()) {
+            warnings.push(format!(
+                "Param '{}' is a {} in the compiled shader but a {} is written",
+                name,
+                compiled_type,
+                expected_types(slider)[0]
+            ));
+        }
+
+        if member.offset as usize != offset {
+            warnings.push(format!(
+                "Param '{}' is at offset {} in the compiled shader but is written at offset {}",
+                name, member.offset, offset
+            ));
+        }
+    }
+    warnings
+}
+
+/// Members of the uniform block bound as params
+fn params_members(module: &Module) -> Option<&[StructMember]> {
+    let var = module.global_variables.iter().find_map(|(_, var)| {
+        let binding = var.binding.as_ref()?;
+        if var.class == StorageClass::Uniform
+            && binding.group == PARAMS_GROUP
+            && binding.binding == PARAMS_BINDING
+        {
+            Some(var)
+        } else {
+            None
+        }
+    })?;
+
+    let mut ty = &module.types[var.ty].inner;
+    if let TypeInner::Pointer { base, .. } = ty {
+        ty = &module.types[*base].inner;
+    }
+    match ty {
+        TypeInner::Struct { members, .. } => Some(members),
+        _ => None,
+    }
+}
+
+/// Glsl types matching the data written for a param, the first one is the declared type
+fn expected_types(slider: &Slider) -> &'static [&'static str] {
+    match slider {
+        Slider::Float { .. } => &["float"],
+        Slider::Uint { .. } => &["uint"],
+        // Booleans can't be stored in a uniform block, glslang turns them into uints
+        Slider::Bool { .. } => &["bool", "uint"],
+        Slider::Vec2 { .. } => &["vec2"],
+        Slider::Vec3 { .. } | Slider::Color { .. } => &["vec3"],
+    }
+}
+
+fn describe_type(ty: &TypeInner) -> String {
+    let (kind, size) = match ty {
+        TypeInner::Scalar { kind, .. } => (*kind, None),
+        TypeInner::Vector { kind, size, .. } => (*kind, Some(*size)),
+        _ => return "unsupported type".to_string(),
+    };
+    let (scalar, prefix) = match kind {
+        ScalarKind::Float => ("float", ""),
+        ScalarKind::Uint => ("uint", "u"),
+        ScalarKind::Sint => ("int", "i"),
+        ScalarKind::Bool => ("bool", "b"),
+    };
+    match size {
+        None => scalar.to_string(),
+        Some(size) => format!("{}vec{}", prefix, size as u8),
+    }
+}
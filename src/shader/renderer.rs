@@ -1,95 +1,363 @@
-use lazy_static::lazy_static;
 use wgpu::*;
 
-lazy_static! {
-    static ref VERTEX_SHADER_DESC: ShaderModuleDescriptor<'static> = include_wgsl!("quad.wgsl");
+use crate::TextureChannel;
+
+/// The fullscreen triangle vertex stage of the shader pipelines. Modules belong to the device
+/// they are created with, each device needs its own.
+pub fn create_vertex_module(device: &Device) -> ShaderModule {
+    device.create_shader_module(&include_wgsl!("quad.wgsl"))
+}
+
+/// Format of the render targets after the canvas. They hold data like the state of simulations,
+/// it must not be clamped or quantized like an 8 bit canvas would.
+pub const TARGETS_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+/// Layout of the texture channels bind group (set 2)
+pub fn channel_layout_entries(channels: &[TextureChannel]) -> Vec<BindGroupLayoutEntry> {
+    channels
+        .iter()
+        .flat_map(|channel| {
+            [
+                BindGroupLayoutEntry {
+                    binding: 2 * channel.index,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: channel.source.view_dimension(),
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2 * channel.index + 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler {
+                        filtering: true,
+                        comparison: false,
+                    },
+                    count: None,
+                },
+            ]
+        })
+        .collect()
+}
+
+/// Layout of the storage buffer bind group (set 3), the shader reads and writes it
+pub fn storage_layout_entries(size: u64) -> [BindGroupLayoutEntry; 1] {
+    [BindGroupLayoutEntry {
+        binding: 0,
+        visibility: ShaderStages::FRAGMENT,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: BufferSize::new(size),
+        },
+        count: None,
+    }]
+}
+
+/// Stages reading the globals push constants and the params block when the shader can't be
+/// reflected. The vertex stage is the built-in fullscreen triangle which reads neither.
+pub const DEFAULT_GLOBALS_STAGES: ShaderStages = ShaderStages::FRAGMENT;
+
+/// How the globals get to the shader
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlobalsMode {
+    PushConstants,
+    /// A uniform buffer at binding 1 of the params set, to rule out push constant issues of a
+    /// driver
+    UniformBuffer,
+}
+
+impl GlobalsMode {
+    /// Layout qualifier of the globals block in the standard header
+    pub fn glsl_layout(self) -> &'static str {
+        match self {
+            GlobalsMode::PushConstants => "layout(push_constant)",
+            GlobalsMode::UniformBuffer => "layout(set = 1, binding = 1)",
+        }
+    }
+}
+
+/// Slots of the globals uniform buffer, one per sub-frame
+const GLOBALS_SLOTS: u64 = 64;
+
+/// Params blocks at least this big are uploaded to a ring of slots instead of a single buffer
+const PARAMS_RING_MIN_SIZE: u64 = 1024;
+/// Number of slots in the params ring
+const PARAMS_RING_SLOTS: u64 = 3;
+
+/// First binding of the last frame of the extra targets in the feedback bind group, after the
+/// room for the history frames
+pub const PREVIOUS_TARGETS_BINDING: u32 = 8;
+
+/// Layout of the feedback bind group (set 0), the last frame then the older ones, then the last
+/// frame of each target after the first
+pub fn feedback_layout_entries(frames: u32, targets: u32) -> Vec<BindGroupLayoutEntry> {
+    let bindings = (0..frames)
+        .map(|frame| 2 * frame)
+        .chain((1..targets).map(|target| PREVIOUS_TARGETS_BINDING + 2 * (target - 1)));
+    bindings
+        .flat_map(|binding| {
+            [
+                BindGroupLayoutEntry {
+                    binding,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: binding + 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler {
+                        filtering: false,
+                        comparison: false,
+                    },
+                    count: None,
+                },
+            ]
+        })
+        .collect()
+}
+
+/// How the shader output is combined with the cleared canvas
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    /// The output overwrites the canvas
+    Replace,
+    /// Blend with the output alpha
+    Alpha,
+    /// Blend with the output alpha, the output colors are already multiplied by it
+    Premultiplied,
+    /// Add the output to the canvas
+    Additive,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 4] = [
+        BlendMode::Replace,
+        BlendMode::Alpha,
+        BlendMode::Premultiplied,
+        BlendMode::Additive,
+    ];
+
+    pub fn blend_state(self) -> Option<BlendState> {
+        match self {
+            BlendMode::Replace => None,
+            BlendMode::Alpha => Some(BlendState::ALPHA_BLENDING),
+            BlendMode::Premultiplied => Some(BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+            BlendMode::Additive => Some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+            }),
+        }
+    }
+}
+
+impl Default for BlendMode {
+    fn default() -> Self {
+        BlendMode::Replace
+    }
 }
 
-unsafe fn get_vertex_shader_module(device: &Device) -> &'static ShaderModule {
-    static mut VERTEX_SHADER_MOD: Option<ShaderModule> = None;
-    &*VERTEX_SHADER_MOD.get_or_insert_with(|| device.create_shader_module(&VERTEX_SHADER_DESC))
+/// Part of the output a pass draws to, in pixels from the top left
+#[derive(Clone, Copy, Debug)]
+pub struct Scissor {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
 }
 
 pub struct ShaderRenderPass {
+    /// Number of bind groups in the pipeline layout
+    bind_group_count: u32,
+    /// Fills the unused bind groups before the last one used
+    empty_bind_group: BindGroup,
     params_bind_group: Option<BindGroup>,
     params_buffer: Option<Buffer>,
+    /// Distance between two slots of the params buffer, 0 when there is a single slot
+    params_stride: u64,
+    /// Slot written by the last params upload, bound as a dynamic offset
+    params_slot: u64,
+    globals_mode: GlobalsMode,
+    /// Stages the globals and params are visible to
+    globals_stages: ShaderStages,
+    /// Globals of each sub-frame, for push constants
+    globals: Vec<Vec<u8>>,
+    globals_buffer: Option<Buffer>,
+    /// Distance between two slots of the globals buffer
+    globals_stride: u64,
+    /// Targets loaded with their last content instead of cleared
+    kept_targets: Vec<u32>,
     pipeline: RenderPipeline,
 }
 
 impl ShaderRenderPass {
     pub fn new(
         device: &Device,
+        vertex_module: &ShaderModule,
         shader_source: &ShaderModule,
-        last_tex_layout: &BindGroupLayout,
-        push_constants_size: u32,
+        last_tex_layout: Option<&BindGroupLayout>,
+        textures_layout: Option<&BindGroupLayout>,
+        storage_layout: Option<&BindGroupLayout>,
+        globals_mode: GlobalsMode,
+        globals_stages: ShaderStages,
+        globals_size: u32,
         params_buffer_size: u64,
         format: TextureFormat,
+        targets: u32,
+        kept_targets: &[u32],
+        polygon_mode: PolygonMode,
+        blend_mode: BlendMode,
     ) -> Self {
-        let bind_group_layout;
-        let params_buffer;
-        let params_bind_group;
-        if params_buffer_size > 0 {
-            bind_group_layout = Some(device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: Some("main bind group layout"),
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-            }));
+        let uniform_globals = globals_mode == GlobalsMode::UniformBuffer;
+        // Large params blocks get multiple slots so an upload doesn't wait for the gpu to be done
+        // reading the previous values
+        let params_stride = if params_buffer_size >= PARAMS_RING_MIN_SIZE {
+            align_to(params_buffer_size, BIND_BUFFER_ALIGNMENT)
+        } else {
+            0
+        };
+        let globals_stride = align_to(globals_size as u64, BIND_BUFFER_ALIGNMENT);
 
-            params_buffer = Some(device.create_buffer(&BufferDescriptor {
+        let params_buffer = (params_buffer_size > 0).then(|| {
+            device.create_buffer(&BufferDescriptor {
                 label: Some("params ubo"),
-                size: params_buffer_size,
+                size: if params_stride > 0 {
+                    params_stride * PARAMS_RING_SLOTS
+                } else {
+                    params_buffer_size
+                },
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        });
+        let globals_buffer = uniform_globals.then(|| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some("globals ubo"),
+                size: globals_stride * GLOBALS_SLOTS,
                 usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
                 mapped_at_creation: false,
-            }));
+            })
+        });
 
-            params_bind_group = Some(device.create_bind_group(&BindGroupDescriptor {
-                label: Some("main bind group"),
-                layout: bind_group_layout.as_ref().unwrap(),
-                entries: &[BindGroupEntry {
-                    binding: 0,
+        // The params at binding 0 and the globals at binding 1, both have a dynamic offset
+        let mut layout_entries = Vec::new();
+        let mut entries = Vec::new();
+        for (binding, buffer, size) in [
+            (0, params_buffer.as_ref(), params_buffer_size),
+            (1, globals_buffer.as_ref(), globals_size as u64),
+        ] {
+            if let Some(buffer) = buffer {
+                layout_entries.push(BindGroupLayoutEntry {
+                    binding,
+                    visibility: globals_stages,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: BufferSize::new(size),
+                    },
+                    count: None,
+                });
+                entries.push(BindGroupEntry {
+                    binding,
                     resource: BindingResource::Buffer(BufferBinding {
-                        buffer: params_buffer.as_ref().unwrap(),
+                        buffer,
                         offset: 0,
-                        size: None,
+                        size: BufferSize::new(size),
                     }),
-                }],
-            }));
-        } else {
-            bind_group_layout = None;
-            params_buffer = None;
-            params_bind_group = None;
+                });
+            }
         }
+        let bind_group_layout = (!entries.is_empty()).then(|| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("main bind group layout"),
+                entries: &layout_entries,
+            })
+        });
+        let params_bind_group = bind_group_layout.as_ref().map(|layout| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("main bind group"),
+                layout,
+                entries: &entries,
+            })
+        });
 
-        let mut layouts = vec![last_tex_layout];
-        if let Some(layout) = &bind_group_layout {
-            layouts.push(layout);
-        }
+        // Sets can't be left out before the last one used, they get an empty bind group
+        let empty_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("empty bind group layout"),
+            entries: &[],
+        });
+        let empty_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("empty bind group"),
+            layout: &empty_layout,
+            entries: &[],
+        });
+        let bind_group_count = if storage_layout.is_some() {
+            4
+        } else if textures_layout.is_some() {
+            3
+        } else if bind_group_layout.is_some() {
+            2
+        } else if last_tex_layout.is_some() {
+            1
+        } else {
+            0
+        };
+        let layouts: Vec<&BindGroupLayout> = [
+            last_tex_layout,
+            bind_group_layout.as_ref(),
+            textures_layout,
+            storage_layout,
+        ]
+        .iter()
+        .take(bind_group_count)
+        .map(|layout| layout.unwrap_or(&empty_layout))
+        .collect();
 
+        // The globals in a uniform buffer leave the push constants unused
+        let push_constant_ranges = if uniform_globals {
+            Vec::new()
+        } else {
+            vec![PushConstantRange {
+                stages: globals_stages,
+                range: 0..globals_size,
+            }]
+        };
         let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: Some("nuance shader pipeline layout"),
             bind_group_layouts: &layouts,
-            push_constant_ranges: &[PushConstantRange {
-                stages: ShaderStages::FRAGMENT,
-                range: 0..push_constants_size,
-            }],
+            push_constant_ranges: &push_constant_ranges,
         });
 
-        let vertex_shader = unsafe { get_vertex_shader_module(device) };
+        // The canvas has its own format, the other targets are float. They share the blending.
+        let color_targets: Vec<ColorTargetState> = (0..targets.max(1))
+            .map(|target| ColorTargetState {
+                format: if target == 0 { format } else { TARGETS_FORMAT },
+                write_mask: ColorWrites::ALL,
+                blend: blend_mode.blend_state(),
+            })
+            .collect();
 
         // Describes the operations to execute on a render pass
         let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
             label: Some("nuance shader pipeline"),
             layout: Some(&pipeline_layout),
             vertex: VertexState {
-                module: vertex_shader,
+                module: vertex_module,
                 entry_point: "main",
                 buffers: &[],
             },
@@ -99,7 +367,7 @@ impl ShaderRenderPass {
                 front_face: FrontFace::Ccw,
                 cull_mode: None,
                 clamp_depth: false,
-                polygon_mode: PolygonMode::Fill,
+                polygon_mode,
                 conservative: false,
             },
             depth_stencil: None,
@@ -111,58 +379,138 @@ impl ShaderRenderPass {
             fragment: Some(FragmentState {
                 module: shader_source,
                 entry_point: "main",
-                targets: &[ColorTargetState {
-                    format,
-                    write_mask: ColorWrites::ALL,
-                    blend: None,
-                }],
+                targets: &color_targets,
             }),
         });
 
         Self {
+            bind_group_count: bind_group_count as u32,
+            empty_bind_group,
             params_bind_group,
             params_buffer,
+            params_stride,
+            params_slot: 0,
+            globals_mode,
+            globals_stages,
+            globals: Vec::new(),
+            globals_buffer,
+            globals_stride,
+            kept_targets: kept_targets.to_vec(),
             pipeline,
         }
     }
 
-    pub fn update_buffers(&self, queue: &Queue, params_buffer: &[u8]) {
+    /// Upload the params, does nothing for shaders without params
+    pub fn update_buffers(&mut self, queue: &Queue, params_buffer: &[u8]) {
+        if params_buffer.is_empty() {
+            return;
+        }
         if let Some(buffer) = &self.params_buffer {
+            // Write to the next slot, the one bound last frame might still be in use
+            if self.params_stride > 0 {
+                self.params_slot = (self.params_slot + 1) % PARAMS_RING_SLOTS;
+            }
             // Update the params buffer on the gpu side
-            queue.write_buffer(buffer, 0, params_buffer);
+            queue.write_buffer(buffer, self.params_offset() as u64, params_buffer);
         }
     }
 
+    /// Set the globals of each sub-frame, at most one per slot of the globals buffer
+    pub fn update_globals(&mut self, queue: &Queue, globals: &[Vec<u8>]) {
+        match &self.globals_buffer {
+            Some(buffer) => {
+                for (slot, globals) in globals.iter().take(GLOBALS_SLOTS as usize).enumerate() {
+                    queue.write_buffer(buffer, slot as u64 * self.globals_stride, globals);
+                }
+            }
+            None => self.globals = globals.to_vec(),
+        }
+    }
+
+    fn params_offset(&self) -> DynamicOffset {
+        (self.params_slot * self.params_stride) as DynamicOffset
+    }
+
+    /// The outputs are cleared first when `clear` is set, except the kept targets. The viewport
+    /// always covers the whole output so the shader coordinates don't depend on the scissor.
+    /// `extra_outputs` are the targets after the first, one per extra target of the pipeline.
+    /// `subframe` selects the globals set by the last `update_globals`.
     pub fn execute(
         &self,
         encoder: &mut CommandEncoder,
         output_tex: &TextureView,
-        push_constants: &[u8],
-        last_tex: &BindGroup,
+        extra_outputs: &[TextureView],
+        subframe: usize,
+        last_tex: Option<&BindGroup>,
+        textures: Option<&BindGroup>,
+        storage: Option<&BindGroup>,
+        scissor: Option<Scissor>,
+        clear: bool,
     ) {
         puffin::profile_scope!("shader pass execute");
 
-        let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
-            label: Some("main render pass"),
-            color_attachments: &[RenderPassColorAttachment {
-                view: output_tex,
+        let color_attachments: Vec<RenderPassColorAttachment> = Some(output_tex)
+            .into_iter()
+            .chain(extra_outputs)
+            .enumerate()
+            .map(|(target, view)| RenderPassColorAttachment {
+                view,
                 resolve_target: None,
                 ops: Operations {
-                    load: LoadOp::Clear(Color::BLACK),
+                    load: if clear && !self.kept_targets.contains(&(target as u32)) {
+                        LoadOp::Clear(Color::BLACK)
+                    } else {
+                        LoadOp::Load
+                    },
                     store: true,
                 },
-            }],
+            })
+            .collect();
+        let mut rpass = encoder.begin_render_pass(&RenderPassDescriptor {
+            label: Some("main render pass"),
+            color_attachments: &color_attachments,
             depth_stencil_attachment: None,
         });
-        rpass.set_bind_group(0, last_tex, &[]);
-        if let Some(bind_group) = &self.params_bind_group {
-            rpass.set_bind_group(1, bind_group, &[]);
+        if let Some(scissor) = scissor {
+            rpass.set_scissor_rect(scissor.x, scissor.y, scissor.width, scissor.height);
+        }
+        if self.bind_group_count > 0 {
+            rpass.set_bind_group(0, last_tex.unwrap_or(&self.empty_bind_group), &[]);
+        }
+        if self.bind_group_count > 1 {
+            match &self.params_bind_group {
+                Some(bind_group) => {
+                    let globals_offset = (subframe as u64 * self.globals_stride) as DynamicOffset;
+                    let offsets = [self.params_offset(), globals_offset];
+                    let offsets = match (&self.params_buffer, &self.globals_buffer) {
+                        (Some(_), Some(_)) => &offsets[..],
+                        (Some(_), None) => &offsets[..1],
+                        (None, _) => &offsets[1..],
+                    };
+                    rpass.set_bind_group(1, bind_group, offsets);
+                }
+                None => rpass.set_bind_group(1, &self.empty_bind_group, &[]),
+            }
+        }
+        if self.bind_group_count > 2 {
+            rpass.set_bind_group(2, textures.unwrap_or(&self.empty_bind_group), &[]);
+        }
+        if self.bind_group_count > 3 {
+            rpass.set_bind_group(3, storage.unwrap_or(&self.empty_bind_group), &[]);
         }
         rpass.set_pipeline(&self.pipeline);
         // Push constants mapped to uniform block
-        rpass.set_push_constants(ShaderStages::FRAGMENT, 0, push_constants);
+        if self.globals_mode == GlobalsMode::PushConstants {
+            if let Some(globals) = self.globals.get(subframe) {
+                rpass.set_push_constants(self.globals_stages, 0, globals);
+            }
+        }
         // We have no vertices, they are generated by the vertex shader in place.
         // But we act like we have 3, so the gpu calls the vertex shader 3 times.
         rpass.draw(0..3, 0..1);
     }
 }
+
+fn align_to(size: u64, alignment: u64) -> u64 {
+    (size + alignment - 1) / alignment * alignment
+}
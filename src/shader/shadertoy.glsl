@@ -0,0 +1,31 @@
+#pragma once
+// Shadertoy globals for shaders ported with nuance import
+
+#include <Nuance>
+
+// mainImage names its parameters like the nuance macros
+#undef fragCoord
+#undef fragCoordNorm
+
+// The drag state with the origin at the bottom left, whatever the flip setting.
+// xy follows the cursor while the left button is held, zw is the click position, negative once released.
+vec4 shadertoyMouse() {
+    vec4 mouse = fMouseDrag;
+    if (uFlipY != 0u || mouse == vec4(0.0)) {
+        return mouse;
+    }
+    float height = float(uResolution.y);
+    float clickY = height - abs(mouse.w);
+    return vec4(mouse.x, height - mouse.y, mouse.z, mouse.z < 0.0 ? -clickY : clickY);
+}
+
+// Fragment coordinates of the image pass, the origin is at the bottom left.
+// Buffers are drawn at gl_FragCoord, upside down, so they match the texture coordinates they are sampled at.
+#define shadertoyCoord vec2(gl_FragCoord.x, float(uResolution.y) - gl_FragCoord.y)
+
+#define iResolution vec3(vec2(uResolution), 1.0)
+#define iTime fTime
+#define iTimeDelta fTimeDelta
+#define iFrame int(uFrame)
+#define iFrameRate fFrameRate
+#define iMouse shadertoyMouse()
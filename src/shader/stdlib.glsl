@@ -1,11 +1,12 @@
+#pragma once
 #define NUANCE
 
 #version 460
 
 #include <noise>
 
-// Current fragment coordinates in pixel space
-#define fragCoord gl_FragCoord
+// Current fragment coordinates in pixel space, the origin is at the bottom left when uFlipY is set
+#define fragCoord vec4(gl_FragCoord.x, uFlipY != 0u ? float(uResolution.y) - gl_FragCoord.y : gl_FragCoord.y, gl_FragCoord.zw)
 // Current fragment coordinates in normalized space
 #define fragCoordNorm fragCoord.xy / uResolution
 
@@ -29,12 +30,35 @@ layout(push_constant) uniform Globals {
     float fTime;
 // The number of frame we're at
     uint uFrame;
+// Target framerate
+    float fTargetFps;
+// Scale for aspect corrected coordinates, the shorter axis is 1
+    vec2 fAspect;
+// 1 when the origin is at the bottom left
+    uint uFlipY;
+// Mouse wheel notches
+    int iWheelSteps;
+// Wall clock time in sec since the app started, unlike fTime it keeps running while paused
+    float fRealTime;
+// Index of the sub-frame being rendered
+    uint uSubframe;
+// Number of sub-frames averaged per frame
+    uint uSubframes;
+// Time offset in sec of this sub-frame, already added to fTime
+    float fSubframeOffset;
+// Time in sec elapsed since the last frame
+    float fTimeDelta;
+// NUANCE_BUILTINS
 };
 
 #define FIRST_RUN uFrame == 0
 
 // Sample the last frame at the given normalized coordinates
 vec4 samplePreviousN(vec2 st) {
+    // The texture origin is always at the top left
+    if (uFlipY != 0u) {
+        st.y = 1.0 - st.y;
+    }
     return texture(sampler2D(lastFrame, lastFrameSampler), st);
 }
 